@@ -1,5 +1,6 @@
 -- Table: public.inmate
-CREATE EXTENSION vector;
+CREATE EXTENSION IF NOT EXISTS vector;
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
 CREATE TABLE IF NOT EXISTS inmate (
   id SERIAL PRIMARY KEY,
   first_name TEXT NOT NULL CHECK (first_name <> ''),
@@ -23,6 +24,6 @@ CREATE TABLE IF NOT EXISTS inmate (
   embedding vector(1536),
   UNIQUE (first_name, last_name, dob, booking_date)
 );
-CREATE INDEX idx_inmate_first_name ON inmate(first_name);
-CREATE INDEX idx_inmate_middle_name ON inmate(middle_name);
-CREATE INDEX idx_inmate_last_name ON inmate(last_name);
+CREATE INDEX IF NOT EXISTS idx_inmate_first_name ON inmate(first_name);
+CREATE INDEX IF NOT EXISTS idx_inmate_middle_name ON inmate(middle_name);
+CREATE INDEX IF NOT EXISTS idx_inmate_last_name ON inmate(last_name);
@@ -8,4 +8,4 @@ CREATE TABLE IF NOT EXISTS bond (
   FOREIGN KEY (inmate_id) REFERENCES inmate(id) 
 );
 
-CREATE INDEX bond_inmate_id_idx ON bond(inmate_id);
+CREATE INDEX IF NOT EXISTS bond_inmate_id_idx ON bond(inmate_id);
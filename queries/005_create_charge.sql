@@ -9,4 +9,4 @@ CREATE TABLE IF NOT EXISTS charge (
   FOREIGN KEY (inmate_id) REFERENCES inmate(id)
 );
 
-CREATE INDEX idx_inmate_id ON charge(inmate_id);
+CREATE INDEX IF NOT EXISTS idx_inmate_id ON charge(inmate_id);
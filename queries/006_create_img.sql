@@ -7,4 +7,4 @@ CREATE TABLE IF NOT EXISTS img (
   FOREIGN KEY (inmate_id) REFERENCES inmate(id) 
 );
 
-CREATE INDEX idx_img_inmate_id ON img(inmate_id);
+CREATE INDEX IF NOT EXISTS idx_img_inmate_id ON img(inmate_id);
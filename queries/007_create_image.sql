@@ -0,0 +1,26 @@
+-- Table: public.image
+-- Images are stored once, keyed by the SHA-256 of their content, and linked to every booking that
+-- used them. img_url is the image's object store key, set once the upload succeeds. img is NULL
+-- once the image is only kept in the object store.
+
+CREATE TABLE IF NOT EXISTS image (
+  id SERIAL PRIMARY KEY,
+  sha256 TEXT UNIQUE NOT NULL CHECK (sha256 <> ''),
+  img BYTEA,
+  format TEXT NOT NULL,
+  width INTEGER NOT NULL,
+  height INTEGER NOT NULL,
+  img_url TEXT
+);
+
+-- Table: public.inmate_image
+
+CREATE TABLE IF NOT EXISTS inmate_image (
+  inmate_id INTEGER NOT NULL,
+  image_id INTEGER NOT NULL,
+  FOREIGN KEY (inmate_id) REFERENCES inmate(id),
+  FOREIGN KEY (image_id) REFERENCES image(id),
+  PRIMARY KEY (inmate_id, image_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_inmate_image_image_id ON inmate_image(image_id);
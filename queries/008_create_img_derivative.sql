@@ -0,0 +1,13 @@
+-- Table: public.img_derivative
+
+CREATE TABLE IF NOT EXISTS img_derivative (
+  id SERIAL PRIMARY KEY,
+  image_id INTEGER NOT NULL,
+  name TEXT NOT NULL,
+  img_url TEXT NOT NULL,
+  format TEXT NOT NULL,
+  width INTEGER NOT NULL,
+  height INTEGER NOT NULL,
+  FOREIGN KEY (image_id) REFERENCES image(id),
+  UNIQUE (image_id, name, format)
+);
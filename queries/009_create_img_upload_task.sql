@@ -0,0 +1,20 @@
+-- Table: public.img_upload_task
+-- Outbox of image uploads. Serializing a record enqueues a task in the same transaction, and the
+-- upload queue drains it outside of it, with retries.
+
+CREATE TABLE IF NOT EXISTS img_upload_task (
+  id SERIAL PRIMARY KEY,
+  image_id INTEGER UNIQUE NOT NULL,
+  inmate_id INTEGER NOT NULL,
+  img_url TEXT NOT NULL,
+  source_url TEXT,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  last_error TEXT,
+  next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+  completed_at TIMESTAMP WITH TIME ZONE,
+  FOREIGN KEY (image_id) REFERENCES image(id),
+  FOREIGN KEY (inmate_id) REFERENCES inmate(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_img_upload_task_pending ON img_upload_task(next_attempt_at) WHERE completed_at IS NULL;
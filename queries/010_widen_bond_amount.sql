@@ -0,0 +1,4 @@
+-- Table: public.bond
+-- Bonds are in pennies, so INTEGER wrapped for anything over about $21.4M.
+
+ALTER TABLE bond ALTER COLUMN amount_pennies TYPE BIGINT;
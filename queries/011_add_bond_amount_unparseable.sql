@@ -0,0 +1,4 @@
+-- Table: public.bond
+-- Flags bonds whose amount on the site couldn't be parsed. Their amount_pennies is 0.
+
+ALTER TABLE bond ADD COLUMN IF NOT EXISTS amount_unparseable BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,80 @@
+-- Tables: public.inmate_history, public.charge_history, public.bond_history
+-- Append-only history of each booking. inmate_history stores every observed version of the
+-- profile. charge_history and bond_history store the charges and bonds added and removed between
+-- crawls, so replaying them in id order gives the set at any point in time.
+
+CREATE TABLE IF NOT EXISTS inmate_history (
+  id BIGSERIAL PRIMARY KEY,
+  inmate_id INTEGER NOT NULL,
+  crawl_run_id TEXT NOT NULL,
+  observed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+  middle_name TEXT,
+  affix TEXT,
+  permanent_id TEXT,
+  sex TEXT,
+  arresting_agency TEXT,
+  booking_number TEXT,
+  height TEXT,
+  weight TEXT,
+  race TEXT,
+  eye_color TEXT,
+  scil_sysid TEXT,
+  FOREIGN KEY (inmate_id) REFERENCES inmate(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_inmate_history_inmate_id ON inmate_history(inmate_id, observed_at);
+
+CREATE TABLE IF NOT EXISTS charge_history (
+  id BIGSERIAL PRIMARY KEY,
+  inmate_id INTEGER NOT NULL,
+  crawl_run_id TEXT NOT NULL,
+  observed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+  description TEXT,
+  grade TEXT,
+  offense_date TEXT,
+  removed BOOLEAN NOT NULL DEFAULT FALSE,
+  FOREIGN KEY (inmate_id) REFERENCES inmate(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_charge_history_inmate_id ON charge_history(inmate_id, observed_at);
+
+CREATE TABLE IF NOT EXISTS bond_history (
+  id BIGSERIAL PRIMARY KEY,
+  inmate_id INTEGER NOT NULL,
+  crawl_run_id TEXT NOT NULL,
+  observed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+  type TEXT NOT NULL,
+  amount_pennies BIGINT NOT NULL DEFAULT 0,
+  amount_unparseable BOOLEAN NOT NULL DEFAULT FALSE,
+  removed BOOLEAN NOT NULL DEFAULT FALSE,
+  FOREIGN KEY (inmate_id) REFERENCES inmate(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_bond_history_inmate_id ON bond_history(inmate_id, observed_at);
+
+-- Bookings stored before history existed start with their current state, as of their booking
+-- date
+INSERT INTO inmate_history
+  (inmate_id, crawl_run_id, observed_at, middle_name, affix, permanent_id, sex, arresting_agency,
+   booking_number, height, weight, race, eye_color, scil_sysid)
+SELECT id, 'backfill', booking_date, middle_name, affix, permanent_id, sex, arresting_agency,
+  booking_number, height, weight, race, eye_color, scil_sysid
+FROM inmate
+WHERE NOT EXISTS (SELECT 1 FROM inmate_history WHERE inmate_history.inmate_id = inmate.id);
+
+INSERT INTO charge_history (inmate_id, crawl_run_id, observed_at, description, grade, offense_date)
+SELECT charge.inmate_id, 'backfill', inmate.booking_date, charge.description, charge.grade,
+  charge.offense_date
+FROM charge
+JOIN inmate ON inmate.id = charge.inmate_id
+WHERE NOT EXISTS (SELECT 1 FROM charge_history WHERE charge_history.inmate_id = charge.inmate_id)
+ORDER BY charge.id;
+
+INSERT INTO bond_history
+  (inmate_id, crawl_run_id, observed_at, type, amount_pennies, amount_unparseable)
+SELECT bond.inmate_id, 'backfill', inmate.booking_date, bond.type, bond.amount_pennies,
+  bond.amount_unparseable
+FROM bond
+JOIN inmate ON inmate.id = bond.inmate_id
+WHERE NOT EXISTS (SELECT 1 FROM bond_history WHERE bond_history.inmate_id = bond.inmate_id)
+ORDER BY bond.id;
@@ -0,0 +1,80 @@
+-- Table: public.inmate
+-- Bookings are identified by scil_sysid and booking_number first, so a corrected name updates the
+-- booking instead of inserting a duplicate. Duplicates stored before this point to the newest row
+-- through superseded_by, and their old names become aliases of it.
+
+ALTER TABLE inmate ADD COLUMN IF NOT EXISTS superseded_by INTEGER REFERENCES inmate(id);
+
+UPDATE inmate
+SET superseded_by = survivor.id
+FROM (
+  SELECT booking_number, MAX(id) AS id
+  FROM inmate
+  WHERE booking_number IS NOT NULL AND booking_number <> '' AND superseded_by IS NULL
+  GROUP BY booking_number
+  HAVING COUNT(*) > 1
+) survivor
+WHERE inmate.booking_number = survivor.booking_number
+  AND inmate.id <> survivor.id
+  AND inmate.superseded_by IS NULL;
+
+UPDATE inmate
+SET superseded_by = survivor.id
+FROM (
+  SELECT scil_sysid, MAX(id) AS id
+  FROM inmate
+  WHERE scil_sysid IS NOT NULL AND scil_sysid <> '' AND superseded_by IS NULL
+  GROUP BY scil_sysid
+  HAVING COUNT(*) > 1
+) survivor
+WHERE inmate.scil_sysid = survivor.scil_sysid
+  AND inmate.id <> survivor.id
+  AND inmate.superseded_by IS NULL;
+
+-- 015 backfills alias_key and makes it required, so aliases stored from here on get theirs
+-- right away. Keep the expression in sync with 015.
+CREATE EXTENSION IF NOT EXISTS unaccent;
+ALTER TABLE alias ADD COLUMN IF NOT EXISTS alias_key TEXT;
+
+INSERT INTO alias (alias, alias_key)
+SELECT DISTINCT old_name.alias,
+  btrim(regexp_replace(upper(unaccent(old_name.alias) COLLATE "C"), '[^A-Z0-9]+', ' ', 'g'))
+FROM (
+  SELECT old.first_name || COALESCE(' ' || old.middle_name, '') || ' ' || old.last_name
+    || COALESCE(', ' || old.affix, '') AS alias
+  FROM inmate old
+  JOIN inmate survivor ON survivor.id = old.superseded_by
+  WHERE (old.first_name, old.last_name) IS DISTINCT FROM (survivor.first_name, survivor.last_name)
+) old_name
+ON CONFLICT (alias) DO NOTHING;
+
+INSERT INTO inmate_alias (inmate_id, alias_id)
+SELECT DISTINCT old.superseded_by, alias.id
+FROM inmate old
+JOIN inmate survivor ON survivor.id = old.superseded_by
+JOIN alias ON alias.alias = old.first_name || COALESCE(' ' || old.middle_name, '') || ' '
+  || old.last_name || COALESCE(', ' || old.affix, '')
+WHERE (old.first_name, old.last_name) IS DISTINCT FROM (survivor.first_name, survivor.last_name)
+ON CONFLICT DO NOTHING;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_inmate_scil_sysid_unique ON inmate(scil_sysid)
+  WHERE scil_sysid IS NOT NULL AND scil_sysid <> '' AND superseded_by IS NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_inmate_booking_number_unique ON inmate(booking_number)
+  WHERE booking_number IS NOT NULL AND booking_number <> '' AND superseded_by IS NULL;
+
+-- Table: public.inmate_history
+-- Names, dob and booking date can be corrected now, so the history keeps them too
+
+ALTER TABLE inmate_history
+  ADD COLUMN IF NOT EXISTS first_name TEXT,
+  ADD COLUMN IF NOT EXISTS last_name TEXT,
+  ADD COLUMN IF NOT EXISTS dob DATE,
+  ADD COLUMN IF NOT EXISTS booking_date TIMESTAMP WITH TIME ZONE;
+
+UPDATE inmate_history
+SET first_name = inmate.first_name,
+  last_name = inmate.last_name,
+  dob = inmate.dob,
+  booking_date = inmate.booking_date
+FROM inmate
+WHERE inmate.id = inmate_history.inmate_id AND inmate_history.first_name IS NULL;
@@ -0,0 +1,27 @@
+-- Tables: public.person, public.person_override
+-- A person is everyone booked under the same identity. Each booking links to its person with the
+-- reason it was matched and a confidence between 0 and 1. Bookings are linked by the resolver in
+-- src/person.rs, which runs after each crawl and picks up bookings stored before this table.
+
+CREATE TABLE IF NOT EXISTS person (
+  id SERIAL PRIMARY KEY,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+ALTER TABLE inmate
+  ADD COLUMN IF NOT EXISTS person_id INTEGER REFERENCES person(id),
+  ADD COLUMN IF NOT EXISTS person_match TEXT,
+  ADD COLUMN IF NOT EXISTS person_confidence REAL
+    CHECK (person_confidence >= 0 AND person_confidence <= 1);
+
+CREATE INDEX IF NOT EXISTS idx_inmate_person_id ON inmate(person_id);
+CREATE INDEX IF NOT EXISTS idx_inmate_permanent_id ON inmate(permanent_id);
+CREATE INDEX IF NOT EXISTS idx_inmate_dob ON inmate(dob);
+
+-- Manual corrections win over the resolver. A NULL person_id keeps the booking on its own person.
+CREATE TABLE IF NOT EXISTS person_override (
+  inmate_id INTEGER PRIMARY KEY REFERENCES inmate(id),
+  person_id INTEGER REFERENCES person(id),
+  note TEXT,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
@@ -0,0 +1,17 @@
+-- Table: public.alias
+-- alias_key is the alias with accents stripped, uppercased, and everything but ASCII letters and
+-- digits collapsed to single spaces, so "José" and "JOSE " share a key. COLLATE "C" keeps it
+-- independent of the database's locale. Aliases stay stored as first spelled. Keep the expression in sync with
+-- alias::alias_key and 013.
+
+CREATE EXTENSION IF NOT EXISTS unaccent;
+
+ALTER TABLE alias ADD COLUMN IF NOT EXISTS alias_key TEXT;
+
+UPDATE alias
+SET alias_key = btrim(regexp_replace(upper(unaccent(alias) COLLATE "C"), '[^A-Z0-9]+', ' ', 'g'))
+WHERE alias_key IS NULL;
+
+ALTER TABLE alias ALTER COLUMN alias_key SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_alias_alias_key ON alias(alias_key);
@@ -0,0 +1,6 @@
+-- Tables: public.bond, public.bond_history
+-- Flags bonds the site shows without an amount, e.g. "N/A" or blank. Their amount_pennies is 0,
+-- so they aren't mistaken for "$0.00" bonds. Bonds stored before this can't be told apart.
+
+ALTER TABLE bond ADD COLUMN IF NOT EXISTS amount_not_set BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE bond_history ADD COLUMN IF NOT EXISTS amount_not_set BOOLEAN NOT NULL DEFAULT FALSE;
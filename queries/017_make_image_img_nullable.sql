@@ -0,0 +1,5 @@
+-- Table: public.image
+-- Databases created before migrations existed made img NOT NULL. It's NULL once the image is only
+-- kept in the object store.
+
+ALTER TABLE image ALTER COLUMN img DROP NOT NULL;
@@ -0,0 +1,67 @@
+-- Schema for the SQLite record store. Unlike Postgres it isn't versioned: every statement is
+-- idempotent and runs when the store is migrated. Table and column names follow the Postgres
+-- schema, and the legacy SQLite layout read by migrate_db, so those readers work on it too.
+-- Dates are stored the way the site shows them.
+
+CREATE TABLE IF NOT EXISTS inmate (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  first_name TEXT NOT NULL CHECK (first_name <> ''),
+  middle_name TEXT,
+  last_name TEXT NOT NULL CHECK (last_name <> ''),
+  affix TEXT,
+  permanent_id TEXT,
+  sex TEXT,
+  dob TEXT NOT NULL,
+  arresting_agency TEXT,
+  booking_date TEXT NOT NULL,
+  booking_number TEXT,
+  height TEXT,
+  weight TEXT,
+  race TEXT,
+  eye_color TEXT,
+  scil_sysid TEXT,
+  -- Little-endian f32s
+  embedding BLOB,
+  UNIQUE (first_name, last_name, dob, booking_date)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_inmate_scil_sysid_unique ON inmate(scil_sysid)
+  WHERE scil_sysid IS NOT NULL AND scil_sysid <> '';
+CREATE UNIQUE INDEX IF NOT EXISTS idx_inmate_booking_number_unique ON inmate(booking_number)
+  WHERE booking_number IS NOT NULL AND booking_number <> '';
+
+CREATE TABLE IF NOT EXISTS alias (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  alias TEXT UNIQUE NOT NULL CHECK (alias <> ''),
+  alias_key TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_alias_alias_key ON alias(alias_key);
+
+CREATE TABLE IF NOT EXISTS inmate_alias (
+  inmate_id INTEGER NOT NULL REFERENCES inmate(id),
+  alias_id INTEGER NOT NULL REFERENCES alias(id),
+  PRIMARY KEY (inmate_id, alias_id)
+);
+
+CREATE TABLE IF NOT EXISTS img (
+  inmate_id INTEGER PRIMARY KEY REFERENCES inmate(id),
+  img BLOB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS bond (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  inmate_id INTEGER NOT NULL REFERENCES inmate(id),
+  type TEXT,
+  amount_pennies INTEGER NOT NULL,
+  amount_unparseable INTEGER NOT NULL DEFAULT 0,
+  amount_not_set INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS idx_bond_inmate_id ON bond(inmate_id);
+
+CREATE TABLE IF NOT EXISTS charge (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  inmate_id INTEGER NOT NULL REFERENCES inmate(id),
+  description TEXT,
+  grade TEXT,
+  offense_date TEXT
+);
+CREATE INDEX IF NOT EXISTS idx_charge_inmate_id ON charge(inmate_id);
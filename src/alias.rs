@@ -0,0 +1,327 @@
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use log::{debug, warn};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::inmate::InmateProfile;
+use crate::Error;
+
+/// Stops an alias graph from growing past this many bookings, e.g. around a common alias
+const MAX_GRAPH_BOOKINGS: usize = 1000;
+
+/// Returns the key aliases are compared by: accents stripped, uppercased, and everything but ASCII
+/// letters and digits collapsed to single spaces, so "José" and "JOSE " share a key. Keep it in
+/// sync with the backfills in `queries/013_add_booking_identity.sql` and
+/// `queries/015_add_alias_key.sql`, which strip accents with Postgres's `unaccent`.
+pub fn alias_key(alias: &str) -> String {
+    let mut unaccented = String::with_capacity(alias.len());
+    for c in alias.nfkd().filter(|c| !is_combining_mark(*c)) {
+        match unaccented_letter(c) {
+            Some(letters) => unaccented.push_str(letters),
+            None => unaccented.push(c),
+        }
+    }
+    unaccented
+        .to_ascii_uppercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .join(" ")
+}
+
+/// The Latin letters `unaccent` folds that have no decomposition to strip a mark from.
+fn unaccented_letter(c: char) -> Option<&'static str> {
+    match c {
+        'Æ' | 'æ' => Some("AE"),
+        'Ð' | 'ð' | 'Đ' | 'đ' => Some("D"),
+        'Ø' | 'ø' => Some("O"),
+        'Þ' | 'þ' => Some("TH"),
+        'ß' => Some("SS"),
+        'Ħ' | 'ħ' => Some("H"),
+        'ı' => Some("I"),
+        'ĸ' => Some("Q"),
+        'Ł' | 'ł' => Some("L"),
+        'Ŋ' | 'ŋ' => Some("N"),
+        'Œ' | 'œ' => Some("OE"),
+        'Ŧ' | 'ŧ' => Some("T"),
+        _ => None,
+    }
+}
+
+/// A booking with an alias, as spelled on that booking.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AliasHolder {
+    pub inmate_id: i32,
+    /// None until the booking is linked to a person
+    pub person_id: Option<i32>,
+    pub full_name: String,
+    pub alias: String,
+}
+
+/// A booking in an [`AliasGraph`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AliasGraphBooking {
+    pub inmate_id: i32,
+    pub person_id: Option<i32>,
+    pub full_name: String,
+    pub booking_date: DateTime<Utc>,
+    /// How many shared aliases away from the starting booking it is
+    pub depth: u32,
+}
+
+/// Two bookings sharing an alias key. Each pair and key is listed once, lower inmate id first.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct AliasEdge {
+    pub inmate_id: i32,
+    pub other_inmate_id: i32,
+    pub alias_key: String,
+}
+
+/// The bookings connected to a booking through shared aliases.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AliasGraph {
+    pub inmate_id: i32,
+    /// Ordered by depth, then inmate id. The starting booking comes first.
+    pub bookings: Vec<AliasGraphBooking>,
+    pub edges: Vec<AliasEdge>,
+    /// True if the graph hit the size limit before reaching the requested depth
+    pub truncated: bool,
+}
+
+/// Lists every booking with an alias that has the same key as `alias`, including aliases spelled
+/// differently, ordered by inmate id. Superseded duplicates are skipped.
+///
+/// # Errors
+/// ArgumentError: If the alias has no letters or digits
+pub async fn bookings_sharing_alias(pool: &PgPool, alias: &str) -> Result<Vec<AliasHolder>, Error> {
+    let key = alias_key(alias);
+    if key.is_empty() {
+        return Err(Error::ArgumentError);
+    }
+
+    let rows = sqlx::query(
+        r#"
+        SELECT inmate.id, inmate.person_id, inmate.first_name, inmate.middle_name,
+            inmate.last_name, inmate.affix, alias.alias
+        FROM alias
+        JOIN inmate_alias ON inmate_alias.alias_id = alias.id
+        JOIN inmate ON inmate.id = inmate_alias.inmate_id
+        WHERE alias.alias_key = $1 AND inmate.superseded_by IS NULL
+        ORDER BY inmate.id, alias.alias
+        "#,
+    )
+    .bind(&key)
+    .fetch_all(pool)
+    .await?;
+
+    let mut holders = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        holders.push(AliasHolder {
+            inmate_id: row.try_get("id")?,
+            person_id: row.try_get("person_id")?,
+            full_name: full_name(row)?,
+            alias: row.try_get("alias")?,
+        });
+    }
+    Ok(holders)
+}
+
+/// Walks out from the booking through shared alias keys, up to `max_depth` steps, and returns
+/// the bookings and aliases connecting them. Returns None if there's no such booking.
+pub async fn alias_graph(
+    pool: &PgPool,
+    inmate_id: i32,
+    max_depth: u32,
+) -> Result<Option<AliasGraph>, Error> {
+    let mut builder = GraphBuilder::new(inmate_id);
+    let mut frontier = vec![inmate_id];
+    for depth in 1..=max_depth {
+        if frontier.is_empty() {
+            break;
+        }
+        if builder.depths.len() >= MAX_GRAPH_BOOKINGS {
+            warn!(
+                "Alias graph around inmate {} stopped at {} bookings",
+                inmate_id,
+                builder.depths.len()
+            );
+            builder.truncated = true;
+            break;
+        }
+
+        let rows = sqlx::query(
+            r#"
+            SELECT DISTINCT here.inmate_id AS inmate_id, there.inmate_id AS other_inmate_id,
+                here_alias.alias_key
+            FROM inmate_alias here
+            JOIN alias here_alias ON here_alias.id = here.alias_id
+            JOIN alias there_alias ON there_alias.alias_key = here_alias.alias_key
+            JOIN inmate_alias there ON there.alias_id = there_alias.id
+                AND there.inmate_id <> here.inmate_id
+            JOIN inmate ON inmate.id = there.inmate_id
+            WHERE here.inmate_id = ANY($1) AND inmate.superseded_by IS NULL
+            "#,
+        )
+        .bind(&frontier)
+        .fetch_all(pool)
+        .await?;
+
+        let mut edges = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            edges.push((
+                row.try_get("inmate_id")?,
+                row.try_get("other_inmate_id")?,
+                row.try_get("alias_key")?,
+            ));
+        }
+        frontier = builder.add_level(edges, depth);
+    }
+
+    let ids: Vec<i32> = builder.depths.keys().copied().collect();
+    let rows = sqlx::query(
+        r#"
+        SELECT id, person_id, first_name, middle_name, last_name, affix, booking_date
+        FROM inmate
+        WHERE id = ANY($1)
+        "#,
+    )
+    .bind(&ids)
+    .fetch_all(pool)
+    .await?;
+    if !rows
+        .iter()
+        .any(|row| row.try_get::<i32, _>("id").ok() == Some(inmate_id))
+    {
+        return Ok(None);
+    }
+
+    let mut bookings = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        let id: i32 = row.try_get("id")?;
+        bookings.push(AliasGraphBooking {
+            inmate_id: id,
+            person_id: row.try_get("person_id")?,
+            full_name: full_name(row)?,
+            booking_date: row.try_get("booking_date")?,
+            depth: builder.depths[&id],
+        });
+    }
+    bookings.sort_by_key(|booking| (booking.depth, booking.inmate_id));
+
+    debug!(
+        "Alias graph around inmate {}: {} bookings, {} edges",
+        inmate_id,
+        bookings.len(),
+        builder.edges.len()
+    );
+    Ok(Some(AliasGraph {
+        inmate_id,
+        bookings,
+        edges: builder.edges.into_iter().collect(),
+        truncated: builder.truncated,
+    }))
+}
+
+fn full_name(row: &sqlx::postgres::PgRow) -> Result<String, Error> {
+    Ok(InmateProfile {
+        first_name: row.try_get("first_name")?,
+        middle_name: row.try_get("middle_name")?,
+        last_name: row.try_get("last_name")?,
+        affix: row.try_get("affix")?,
+        ..Default::default()
+    }
+    .get_full_name())
+}
+
+/// Collects an alias graph one level at a time.
+struct GraphBuilder {
+    /// Each booking found so far, with its depth
+    depths: BTreeMap<i32, u32>,
+    edges: BTreeSet<AliasEdge>,
+    truncated: bool,
+}
+
+impl GraphBuilder {
+    fn new(inmate_id: i32) -> Self {
+        GraphBuilder {
+            depths: BTreeMap::from([(inmate_id, 0)]),
+            edges: BTreeSet::new(),
+            truncated: false,
+        }
+    }
+
+    /// Adds the (inmate id, other inmate id, alias key) edges found from the last level's
+    /// bookings. Returns the bookings first found at `depth`, which make up the next level.
+    fn add_level(&mut self, edges: Vec<(i32, i32, String)>, depth: u32) -> Vec<i32> {
+        let mut next = Vec::new();
+        for (inmate_id, other_inmate_id, alias_key) in edges {
+            if let Entry::Vacant(entry) = self.depths.entry(other_inmate_id) {
+                entry.insert(depth);
+                next.push(other_inmate_id);
+            }
+            self.edges.insert(AliasEdge {
+                inmate_id: inmate_id.min(other_inmate_id),
+                other_inmate_id: inmate_id.max(other_inmate_id),
+                alias_key,
+            });
+        }
+        next.sort_unstable();
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alias_key() {
+        assert_eq!(alias_key("Johnny"), "JOHNNY");
+        assert_eq!(alias_key("  JOHNNY  "), "JOHNNY");
+        assert_eq!(alias_key("O'Brien,  Pat-Ray Jr."), "O BRIEN PAT RAY JR");
+        assert_eq!(alias_key("johnny doe"), alias_key("JOHNNY   DOE"));
+        assert_eq!(alias_key(" - , "), "");
+        // Accents are stripped, like unaccent does in the SQL backfills
+        assert_eq!(alias_key("José"), alias_key("JOSE"));
+        assert_eq!(alias_key("José Ñúñez"), "JOSE NUNEZ");
+        assert_eq!(
+            alias_key("Jose\u{301} Østergaard-Straße"),
+            "JOSE OSTERGAARD STRASSE"
+        );
+        assert_eq!(alias_key("李 Lee"), "LEE");
+    }
+
+    #[test]
+    fn test_graph_builder() {
+        let mut builder = GraphBuilder::new(1);
+        let next = builder.add_level(vec![(1, 3, "JD".to_string()), (1, 2, "JD".to_string())], 1);
+        assert_eq!(next, vec![2, 3]);
+
+        // 2 and 3 find each other and 1 again, which only adds edges
+        let next = builder.add_level(
+            vec![
+                (2, 1, "JD".to_string()),
+                (2, 3, "JD".to_string()),
+                (3, 2, "JD".to_string()),
+                (3, 4, "JOHNNY".to_string()),
+            ],
+            2,
+        );
+        assert_eq!(next, vec![4]);
+        assert_eq!(
+            builder.depths,
+            BTreeMap::from([(1, 0), (2, 1), (3, 1), (4, 2)])
+        );
+        assert_eq!(builder.edges.len(), 4);
+        assert!(builder.edges.contains(&AliasEdge {
+            inmate_id: 2,
+            other_inmate_id: 3,
+            alias_key: "JD".to_string(),
+        }));
+    }
+}
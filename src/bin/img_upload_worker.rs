@@ -0,0 +1,62 @@
+use log::{error, info, warn};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+use std::time::Duration;
+
+use scjail_crawler_service::{
+    object_store::{object_store_from_env, ObjectStore, S3ObjectStore},
+    upload_queue::{drain_img_upload_queue, DrainOptions},
+    Error,
+};
+
+const DEFAULT_POLL_MS: u64 = 5000;
+
+/// Drains the img upload queue filled by the crawler. Polls until stopped, or drains once with
+/// --once.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    pretty_env_logger::init();
+    info!("Running img upload worker...");
+    info!("Reading (optional) arguments: --once");
+    info!("Reading ENV Vars--\n -required: DATABASE_URL, \n -optional: UPLOAD_MAX_ATTEMPTS, UPLOAD_WORKER_POLL_MS, OBJECT_STORE, OBJECT_STORE_PATH, AWS_BUCKET_NAME, AWS_REGION, S3_ENDPOINT_URL, S3_FORCE_PATH_STYLE, S3_CREDENTIALS_SOURCE, CRAWL_RUN_ID, MUGSHOT_DERIVATIVES, MUGSHOT_DERIVATIVES_WEBP, IMG_STORAGE_POLICY");
+
+    let once = match env::args().nth(1).as_deref() {
+        None => false,
+        Some("--once") => true,
+        Some(arg) => {
+            error!("Unknown argument: {}", arg);
+            return Err(Error::ArgumentError);
+        }
+    };
+    let options = DrainOptions::from_env()?;
+    let poll_interval = Duration::from_millis(match env::var("UPLOAD_WORKER_POLL_MS") {
+        Ok(poll_ms) => poll_ms.parse::<u64>().map_err(|_| Error::ArgumentError)?,
+        Err(_) => DEFAULT_POLL_MS,
+    });
+
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&env::var("DATABASE_URL").expect("DATABASE_URL must be set!"))
+        .await?;
+    let object_store: Box<dyn ObjectStore> = match object_store_from_env().await? {
+        Some(store) => store,
+        None => Box::new(S3ObjectStore::from_env().await?),
+    };
+
+    loop {
+        let drained = match drain_img_upload_queue(&pool, object_store.as_ref(), &options).await {
+            Ok(report) => report.uploaded + report.failed,
+            Err(e) => {
+                warn!("Failed to drain img upload queue: {:?}", e);
+                0
+            }
+        };
+        if once {
+            return Ok(());
+        }
+        // Keep going while there's a backlog, otherwise wait for new tasks
+        if drained < options.batch_size {
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+}
@@ -6,16 +6,21 @@ use std::env;
 
 use scjail_crawler_service::{
     inmate::{Bond, BondInformation, Charge, ChargeInformation, DbInmateProfile, Record},
-    s3_utils,
-    serialize::{create_dbs, serialize_records},
+    object_store::{object_store_from_env, ObjectStore, S3ObjectStore},
+    migrations::migrate,
+    serialize::{serialize_records, ImgStoragePolicy, SerializeOptions},
+    upload_queue::{drain_img_upload_queue, DrainOptions},
     Error,
 };
 
+/// Records committed per transaction unless SERIALIZE_BATCH_SIZE says otherwise
+const MIGRATE_BATCH_SIZE: usize = 200;
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     pretty_env_logger::init();
     info!("Migrating SQLite database to Postgres...");
-    info!("Reading ENV Vars--\n -required: SQLITE_DATABASE, POSTGRES_DATABASE, \n -optional: QUERY_LIMIT");
+    info!("Reading ENV Vars--\n -required: SQLITE_DATABASE, POSTGRES_DATABASE, \n -optional: QUERY_LIMIT, SERIALIZE_BATCH_SIZE, OBJECT_STORE, OBJECT_STORE_PATH, UPLOAD_MAX_ATTEMPTS, IMG_STORAGE_POLICY");
 
     let mut sqlite_conn = SqliteConnection::connect(
         &env::var("SQLITE_DATABASE").expect("env variable SQLITE_DATABASE must be set"),
@@ -28,7 +33,7 @@ async fn main() -> Result<(), Error> {
             &env::var("POSTGRES_DATABASE").expect("env variable POSTGRES_DATABASE must be set"),
         )
         .await?;
-    let create_req = create_dbs(&pg_pool);
+    let create_req = migrate(&pg_pool);
 
     let limit: Option<i64> = match env::var("QUERY_LIMIT") {
         Ok(limit) => Some(
@@ -44,13 +49,14 @@ async fn main() -> Result<(), Error> {
         .rev()
         .collect();
 
-    let aws_s3_client = if let Ok(_) = env::var("AWS_ACCESS_KEY_ID") {
-        trace!("AWS_ACCESS_KEY_ID found, initializing default S3 client...");
-        let (_region, client) = s3_utils::get_default_s3_client().await;
-        Some(client)
+    let object_store: Option<Box<dyn ObjectStore>> = if let Some(store) = object_store_from_env().await? {
+        Some(store)
+    } else if env::var("AWS_ACCESS_KEY_ID").is_ok() {
+        trace!("AWS_ACCESS_KEY_ID found, initializing default S3 object store...");
+        Some(Box::new(S3ObjectStore::from_env().await?))
     } else {
-        warn!("No AWS_ACCESS_KEY_ID env var found skipping S3 client initialization... (Only environment variables are supported for this implementation)");
-        if let Ok(_) = env::var("AWS_SECRET_ACCESS_KEY") {
+        warn!("No OBJECT_STORE or AWS_ACCESS_KEY_ID env var found skipping object store initialization... (Only environment variables are supported for this implementation)");
+        if env::var("AWS_SECRET_ACCESS_KEY").is_ok() {
             warn!("AWS_SECRET_ACCESS_KEY found, but no AWS_ACCESS_KEY_ID found, skipping S3 client initialization...");
         } else {
             warn!("No AWS_SECRET_ACCESS_KEY found, skipping S3 client initialization...");
@@ -58,7 +64,7 @@ async fn main() -> Result<(), Error> {
         None
     };
 
-    let oai_client = if let Ok(_) = env::var("OPENAI_API_KEY") {
+    let oai_client = if env::var("OPENAI_API_KEY").is_ok() {
         trace!("OpenAI API key found, initializing client...");
         Some(OaiClient::new())
     } else {
@@ -67,17 +73,41 @@ async fn main() -> Result<(), Error> {
     };
 
     trace!(
-        "Established clients: aws? {:?}, openai? {:?}",
-        aws_s3_client.is_some(),
+        "Established clients: object store? {:?}, openai? {:?}",
+        object_store.is_some(),
         oai_client.is_some()
     );
 
+    // Thousands of records at once, so commit them in batches
+    let serialize_options = SerializeOptions {
+        batch_size: MIGRATE_BATCH_SIZE,
+        img_storage_policy: ImgStoragePolicy::from_env(object_store.is_some())?,
+    }
+    .with_env()?;
+
     create_req.await?;
-    match serialize_records::<_, OpenAIConfig>(records, &pg_pool, &oai_client, &aws_s3_client).await
+    match serialize_records::<_, OpenAIConfig>(
+        records,
+        &pg_pool,
+        &oai_client,
+        &object_store,
+        &serialize_options,
+    )
+    .await
     {
         Err(e) => error!("Failed to serialize records: {:?}", e),
         _ => info!("Successfully serialized records!"),
     }
+    if let Some(object_store) = object_store.as_deref() {
+        let options = DrainOptions {
+            img_storage_policy: serialize_options.img_storage_policy,
+            ..DrainOptions::from_env()?
+        };
+        match drain_img_upload_queue(&pg_pool, object_store, &options).await {
+            Err(e) => error!("Failed to drain img upload queue: {:?}", e),
+            Ok(report) => info!("Drained img upload queue: {:?}", report),
+        }
+    }
 
     Ok(())
 }
@@ -85,7 +115,7 @@ async fn main() -> Result<(), Error> {
 async fn get_records_from_sqlite_in_descending_ids(
     conn: &mut SqliteConnection,
     limit: &Option<i64>,
-) -> Result<impl Iterator<Item = Record> + DoubleEndedIterator + ExactSizeIterator, Error> {
+) -> Result<impl DoubleEndedIterator<Item = Record> + ExactSizeIterator, Error> {
     let profiles = get_inmate_profiles_sqlite(conn, limit).await?;
     let mut records: Vec<Record> = Vec::new();
 
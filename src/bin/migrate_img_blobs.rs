@@ -0,0 +1,53 @@
+use log::{error, info};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+
+use scjail_crawler_service::{
+    blob_migration::{migrate_img_blobs, BlobMigrationOptions},
+    object_store::{object_store_from_env, ObjectStore, S3ObjectStore},
+    serialize::ImgStoragePolicy,
+    Error,
+};
+
+/// Moves img blobs out of the database according to IMG_STORAGE_POLICY. Only counts the blobs
+/// that would move by default; pass --apply to move them.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    pretty_env_logger::init();
+    info!("Migrating img blobs...");
+    info!("Reading (optional) arguments: --apply");
+    info!("Reading ENV Vars--\n -required: DATABASE_URL, \n -optional: IMG_STORAGE_POLICY, OBJECT_STORE, OBJECT_STORE_PATH, AWS_BUCKET_NAME, AWS_REGION, S3_ENDPOINT_URL, S3_FORCE_PATH_STYLE, S3_CREDENTIALS_SOURCE, MUGSHOT_DERIVATIVES, MUGSHOT_DERIVATIVES_WEBP");
+
+    let mut options = BlobMigrationOptions {
+        img_storage_policy: ImgStoragePolicy::from_env(true)?,
+        ..Default::default()
+    };
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--apply" => options.dry_run = false,
+            _ => {
+                error!("Unknown argument: {}", arg);
+                return Err(Error::ArgumentError);
+            }
+        }
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&env::var("DATABASE_URL").expect("DATABASE_URL must be set!"))
+        .await?;
+    let object_store: Box<dyn ObjectStore> = match object_store_from_env().await? {
+        Some(store) => store,
+        None => Box::new(S3ObjectStore::from_env().await?),
+    };
+
+    let report = migrate_img_blobs(&pool, object_store.as_ref(), &options).await?;
+    let verb = if options.dry_run { "to move" } else { "moved" };
+    println!("legacy img blobs {}: {}", verb, report.legacy_moved);
+    println!("image blobs {}: {}", verb, report.images_moved);
+    for failure in report.failed.iter() {
+        println!("failed: {}", failure);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use log::{error, info};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+
+use scjail_crawler_service::{
+    object_store::{object_store_from_env, ObjectStore, S3ObjectStore},
+    presign::{presign_expiry_from_env, presign_inmate_img_urls},
+    Error,
+};
+
+/// Prints time-limited URLs for the stored images of each inmate id given as an argument, one
+/// tab separated line per image: inmate id, name, format, expiry and URL.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    pretty_env_logger::init();
+    info!("Presigning inmate img urls...");
+    info!("Reading positional arguments: inmate_id...");
+    info!("Reading ENV Vars--\n -required: DATABASE_URL, \n -optional: PRESIGN_EXPIRES_SECS, OBJECT_STORE, OBJECT_STORE_PATH, AWS_BUCKET_NAME, AWS_REGION, S3_ENDPOINT_URL, S3_FORCE_PATH_STYLE, S3_CREDENTIALS_SOURCE");
+
+    let inmate_ids = env::args()
+        .skip(1)
+        .map(|arg| arg.parse::<i32>().map_err(|_| Error::ArgumentError))
+        .collect::<Result<Vec<i32>, Error>>()?;
+    if inmate_ids.is_empty() {
+        error!("Usage: presign_img <inmate_id>...");
+        return Err(Error::ArgumentError);
+    }
+    let expires_in = presign_expiry_from_env()?;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&env::var("DATABASE_URL").expect("DATABASE_URL must be set!"))
+        .await?;
+    let object_store: Box<dyn ObjectStore> = match object_store_from_env().await? {
+        Some(store) => store,
+        None => Box::new(S3ObjectStore::from_env().await?),
+    };
+
+    for inmate_id in inmate_ids {
+        for img in presign_inmate_img_urls(inmate_id, &pool, object_store.as_ref(), expires_in).await? {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                inmate_id,
+                img.name,
+                img.format,
+                img.expires_at.to_rfc3339(),
+                img.url
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use log::{error, info};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+
+use scjail_crawler_service::{
+    object_store::{object_store_from_env, ObjectStore, S3ObjectStore},
+    reconcile::{reconcile_images, ReconcileOptions},
+    serialize::ImgStoragePolicy,
+    Error,
+};
+
+/// Reconciles the object store with the image keys in the database. Only reports by default;
+/// pass --apply to restore missing objects, and --delete-orphans to also delete orphaned ones.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    pretty_env_logger::init();
+    info!("Reconciling stored images...");
+    info!("Reading (optional) arguments: --apply, --delete-orphans, --prefix=<prefix>");
+    info!("Reading ENV Vars--\n -required: DATABASE_URL, \n -optional: OBJECT_STORE, OBJECT_STORE_PATH, AWS_BUCKET_NAME, AWS_REGION, S3_ENDPOINT_URL, S3_FORCE_PATH_STYLE, S3_CREDENTIALS_SOURCE, IMG_STORAGE_POLICY");
+
+    let mut options = ReconcileOptions {
+        img_storage_policy: ImgStoragePolicy::from_env(true)?,
+        ..Default::default()
+    };
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--apply" => options.dry_run = false,
+            "--delete-orphans" => options.delete_orphans = true,
+            _ => match arg.strip_prefix("--prefix=") {
+                Some(prefix) => options.prefix = prefix.to_string(),
+                None => {
+                    error!("Unknown argument: {}", arg);
+                    return Err(Error::ArgumentError);
+                }
+            },
+        }
+    }
+    if options.delete_orphans && options.dry_run {
+        error!("--delete-orphans requires --apply");
+        return Err(Error::ArgumentError);
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&env::var("DATABASE_URL").expect("DATABASE_URL must be set!"))
+        .await?;
+    let object_store: Box<dyn ObjectStore> = match object_store_from_env().await? {
+        Some(store) => store,
+        None => Box::new(S3ObjectStore::from_env().await?),
+    };
+
+    let report = reconcile_images(&pool, object_store.as_ref(), &options).await?;
+    println!("listed objects: {}", report.listed_objects);
+    println!("referenced objects: {}", report.referenced_objects);
+    for (label, keys) in [
+        ("missing", &report.missing),
+        ("restored", &report.restored),
+        ("unrestorable", &report.unrestorable),
+        ("orphan", &report.orphans),
+        ("deleted orphan", &report.deleted_orphans),
+    ] {
+        for key in keys {
+            println!("{}: {}", label, key);
+        }
+    }
+    println!("relinked inmates: {}", report.relinked_inmates);
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use log::{error, info};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+use std::io::{BufRead, Write};
+
+use scjail_crawler_service::{
+    object_store::{ObjectStore, S3ObjectStore},
+    presign::get_inmate_img_keys,
+    s3_utils::{self, S3Config},
+    Error,
+};
+
+const USAGE: &str = "Usage: s3_admin <command>
+  bootstrap                            create the bucket if it doesn't exist
+  list [prefix]                        list objects: key, size, last modified
+  download-img <inmate_id> [dir]       download an inmate's images (needs DATABASE_URL)
+  copy <source_bucket> <target_bucket> [prefix]
+                                       copy objects between buckets, e.g. prod to dev
+  purge <prefix> [--yes]               delete every object under the prefix";
+
+/// Admin commands for the bucket described by the S3 env config.
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    pretty_env_logger::init();
+    info!("Reading ENV Vars--\n -optional: AWS_BUCKET_NAME, AWS_REGION, S3_ENDPOINT_URL, S3_FORCE_PATH_STYLE, S3_CREDENTIALS_SOURCE, DATABASE_URL");
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let config = S3Config::from_env()?;
+    let client = config.build_client().await;
+
+    match args.as_slice() {
+        ["bootstrap"] => {
+            let created = s3_utils::bootstrap_bucket(&client, &config.bucket, &config.region).await?;
+            let state = if created { "created" } else { "exists" };
+            println!("{}\t{}", config.bucket, state);
+        }
+        ["list"] | ["list", _] => {
+            let prefix = args.get(1).copied().unwrap_or_default();
+            for object in s3_utils::list_objects(&client, &config.bucket, prefix).await? {
+                println!(
+                    "{}\t{}\t{}",
+                    object.key,
+                    object.size,
+                    object.last_modified.unwrap_or_default()
+                );
+            }
+        }
+        ["download-img", inmate_id] | ["download-img", inmate_id, _] => {
+            let inmate_id = inmate_id.parse::<i32>().map_err(|_| Error::ArgumentError)?;
+            let dir = std::path::Path::new(args.get(2).copied().unwrap_or("."));
+            let pool = PgPoolOptions::new()
+                .max_connections(1)
+                .connect(&env::var("DATABASE_URL").expect("DATABASE_URL must be set!"))
+                .await?;
+            let store = S3ObjectStore::new(client, &config.bucket);
+
+            for (name, format, key) in get_inmate_img_keys(inmate_id, &pool).await? {
+                let Some(bytes) = store.get(&key).await? else {
+                    error!("Object {} is missing from {}", key, config.bucket);
+                    continue;
+                };
+                let path = dir.join(format!("{}-{}.{}", inmate_id, name, format));
+                std::fs::write(&path, bytes).map_err(|e| {
+                    Error::InternalError(format!("Failed to write {}: {}", path.display(), e))
+                })?;
+                println!("{}\t{}", key, path.display());
+            }
+        }
+        ["copy", source_bucket, target_bucket] | ["copy", source_bucket, target_bucket, _] => {
+            let prefix = args.get(3).copied().unwrap_or_default();
+            for key in s3_utils::copy_objects(&client, source_bucket, target_bucket, prefix).await? {
+                println!("{}", key);
+            }
+        }
+        ["purge", prefix] | ["purge", prefix, "--yes"] => {
+            let count = s3_utils::list_objects(&client, &config.bucket, prefix).await?.len();
+            if args.last() != Some(&"--yes") && !confirm_purge(&config.bucket, prefix, count)? {
+                error!("Purge cancelled");
+                return Err(Error::ArgumentError);
+            }
+            for key in s3_utils::delete_objects(&client, &config.bucket, prefix).await? {
+                println!("{}", key);
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err(Error::ArgumentError);
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks for the bucket name to be typed back before deleting anything.
+fn confirm_purge(bucket: &str, prefix: &str, count: usize) -> Result<bool, Error> {
+    eprint!(
+        "Delete {} objects under '{}' from {}? Type the bucket name to confirm: ",
+        count, prefix, bucket
+    );
+    std::io::stderr().flush().ok();
+
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|e| Error::InternalError(format!("Failed to read confirmation: {}", e)))?;
+
+    Ok(answer.trim() == bucket)
+}
@@ -0,0 +1,276 @@
+use log::{debug, info, warn};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+
+use crate::mugshot::{self, validate_mugshot};
+use crate::object_store::ObjectStore;
+use crate::serialize::{serialize_image, upload_image, ImgProvenance, ImgStoragePolicy};
+use crate::Error;
+
+#[derive(Debug, Clone)]
+pub struct BlobMigrationOptions {
+    /// Count the blobs that would move without moving them
+    pub dry_run: bool,
+    /// Rows read per query
+    pub batch_size: i64,
+    /// Where the blobs move to
+    pub img_storage_policy: ImgStoragePolicy,
+}
+
+impl Default for BlobMigrationOptions {
+    fn default() -> Self {
+        BlobMigrationOptions {
+            dry_run: true,
+            batch_size: 100,
+            img_storage_policy: ImgStoragePolicy::ObjectStoreOnly,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BlobMigrationReport {
+    /// Blobs moved out of the legacy `img` table
+    pub legacy_moved: usize,
+    /// Blobs dropped from the `image` table, as the object store has them
+    pub images_moved: usize,
+    /// Blobs that couldn't be moved, with the reason
+    pub failed: Vec<String>,
+}
+
+/// Returns the ids after `after_id` of rows in `table` whose blob is still stored.
+async fn get_blob_ids(
+    table: &str,
+    after_id: i32,
+    batch_size: i64,
+    pool: &PgPool,
+) -> Result<Vec<i32>, Error> {
+    let rows = sqlx::query(&format!(
+        "SELECT id FROM {table} WHERE img IS NOT NULL AND id > $1 ORDER BY id LIMIT $2"
+    ))
+    .bind(after_id)
+    .bind(batch_size)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter().map(|row| Ok(row.try_get("id")?)).collect()
+}
+
+async fn count_blobs(table: &str, pool: &PgPool) -> Result<usize, Error> {
+    let count: i64 = sqlx::query(&format!(
+        "SELECT COUNT(*) AS count FROM {table} WHERE img IS NOT NULL"
+    ))
+    .fetch_one(pool)
+    .await?
+    .try_get("count")?;
+
+    Ok(count as usize)
+}
+
+/// Moves a legacy `img` blob into content addressed storage: the `image` table, and the object
+/// store if the policy uploads. The legacy blob is nulled out once it's stored elsewhere.
+async fn move_legacy_img(
+    img_id: i32,
+    policy: ImgStoragePolicy,
+    pool: &PgPool,
+    object_store: &dyn ObjectStore,
+) -> Result<(), Error> {
+    let mut transaction = pool.begin().await?;
+    let row = sqlx::query(
+        r#"
+        SELECT img.img, img.inmate_id, inmate.booking_date::text AS booking_date
+        FROM img
+        JOIN inmate ON inmate.id = img.inmate_id
+        WHERE img.id = $1 AND img.img IS NOT NULL
+        FOR UPDATE OF img
+        "#,
+    )
+    .bind(img_id)
+    .fetch_one(&mut *transaction)
+    .await?;
+    let img_blob: Vec<u8> = row.try_get("img")?;
+    let inmate_id: i32 = row.try_get("inmate_id")?;
+    let booking_date: String = row.try_get("booking_date")?;
+
+    let img_metadata = validate_mugshot(&img_blob)?;
+    let (image_id, existing_img_url) =
+        serialize_image(&inmate_id, &img_blob, &img_metadata, &mut transaction).await?;
+    let img_url = match existing_img_url {
+        Some(existing_img_url) => Some(existing_img_url),
+        None if policy.uploads() => {
+            let img_url = mugshot::content_key(&img_metadata.sha256);
+            let provenance = ImgProvenance {
+                inmate_id,
+                booking_date: &booking_date,
+                source_url: None,
+            };
+            upload_image(
+                &image_id,
+                &img_blob,
+                &img_url,
+                &provenance,
+                policy,
+                object_store,
+                &mut transaction,
+            )
+            .await?;
+            Some(img_url)
+        }
+        None => None,
+    };
+
+    if let Some(img_url) = img_url {
+        sqlx::query(
+            r#"
+            UPDATE inmate
+            SET img_url = $1
+            WHERE id = $2 AND COALESCE(img_url, '') = ''
+            "#,
+        )
+        .bind(img_url)
+        .bind(inmate_id)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    sqlx::query("UPDATE img SET img = NULL WHERE id = $1")
+        .bind(img_id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+
+    debug!("Moved legacy img {} to image {}", img_id, image_id);
+    Ok(())
+}
+
+/// Drops an `image` blob from the database, uploading it first if the object store lacks it.
+async fn move_image_blob(
+    image_id: i32,
+    pool: &PgPool,
+    object_store: &dyn ObjectStore,
+) -> Result<(), Error> {
+    let mut transaction = pool.begin().await?;
+    let row = sqlx::query(
+        r#"
+        SELECT image.img, image.img_url, image.sha256,
+            inmate.id AS inmate_id, inmate.booking_date::text AS booking_date
+        FROM image
+        JOIN inmate_image ON inmate_image.image_id = image.id
+        JOIN inmate ON inmate.id = inmate_image.inmate_id
+        WHERE image.id = $1 AND image.img IS NOT NULL
+        ORDER BY inmate.id
+        LIMIT 1
+        FOR UPDATE OF image
+        "#,
+    )
+    .bind(image_id)
+    .fetch_one(&mut *transaction)
+    .await?;
+    let img_blob: Vec<u8> = row.try_get("img")?;
+    let img_url = row
+        .try_get::<Option<String>, _>("img_url")?
+        .filter(|img_url| !img_url.is_empty());
+
+    match img_url {
+        Some(img_url) if object_store.exists(&img_url).await? => {
+            sqlx::query("UPDATE image SET img = NULL WHERE id = $1")
+                .bind(image_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        img_url => {
+            let sha256: String = row.try_get("sha256")?;
+            let img_url = img_url.unwrap_or_else(|| mugshot::content_key(&sha256));
+            let booking_date: String = row.try_get("booking_date")?;
+            let provenance = ImgProvenance {
+                inmate_id: row.try_get("inmate_id")?,
+                booking_date: &booking_date,
+                source_url: None,
+            };
+            // drops the blob, as the policy only keeps images in the object store
+            upload_image(
+                &image_id,
+                &img_blob,
+                &img_url,
+                &provenance,
+                ImgStoragePolicy::ObjectStoreOnly,
+                object_store,
+                &mut transaction,
+            )
+            .await?;
+        }
+    }
+    transaction.commit().await?;
+
+    debug!("Moved image {} blob to the object store", image_id);
+    Ok(())
+}
+
+/// Moves stored img blobs out of the database. Blobs in the legacy `img` table move into the
+/// `image` table (and the object store, if the [`ImgStoragePolicy`] uploads). If the policy only
+/// keeps images in the object store, blobs in the `image` table are uploaded if missing, then
+/// dropped. Moved blobs are set to NULL.
+///
+/// # Errors
+/// Returns an error if the blobs can't be listed. Failures to move a single blob are recorded in
+/// the report instead.
+pub async fn migrate_img_blobs(
+    pool: &PgPool,
+    object_store: &dyn ObjectStore,
+    options: &BlobMigrationOptions,
+) -> Result<BlobMigrationReport, Error> {
+    let policy = options.img_storage_policy;
+    let mut report = BlobMigrationReport::default();
+    info!("Migrating img blobs with policy {:?}...", policy);
+
+    if options.dry_run {
+        report.legacy_moved = count_blobs("img", pool).await?;
+        if !policy.keeps_db_blob() {
+            report.images_moved = count_blobs("image", pool).await?;
+        }
+        return Ok(report);
+    }
+
+    let mut after_id = 0;
+    loop {
+        let img_ids = get_blob_ids("img", after_id, options.batch_size, pool).await?;
+        let Some(last_id) = img_ids.last() else {
+            break;
+        };
+        after_id = *last_id;
+
+        for img_id in img_ids {
+            match move_legacy_img(img_id, policy, pool, object_store).await {
+                Ok(()) => report.legacy_moved += 1,
+                Err(e) => {
+                    warn!("Failed to move legacy img {}: {:#?}", img_id, e);
+                    report.failed.push(format!("img {}: {}", img_id, e));
+                }
+            }
+        }
+        info!("Moved {} legacy img blobs", report.legacy_moved);
+    }
+
+    if !policy.keeps_db_blob() {
+        let mut after_id = 0;
+        loop {
+            let image_ids = get_blob_ids("image", after_id, options.batch_size, pool).await?;
+            let Some(last_id) = image_ids.last() else {
+                break;
+            };
+            after_id = *last_id;
+
+            for image_id in image_ids {
+                match move_image_blob(image_id, pool, object_store).await {
+                    Ok(()) => report.images_moved += 1,
+                    Err(e) => {
+                        warn!("Failed to move image {} blob: {:#?}", image_id, e);
+                        report.failed.push(format!("image {}: {}", image_id, e));
+                    }
+                }
+            }
+            info!("Moved {} image blobs", report.images_moved);
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,369 @@
+use serde::Serialize;
+
+use crate::history::{BondVersion, ChargeVersion};
+use crate::inmate::{InmateProfile, Record};
+use crate::Error;
+
+/// A profile field that changed, named by its `inmate` column.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// A charge or bond that's still there, but with different details.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Modified<T> {
+    pub old: T,
+    pub new: T,
+}
+
+/// How a booking's mugshot changed, by the SHA-256 of its content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum ImageChange {
+    Added {
+        sha256: String,
+    },
+    Removed {
+        sha256: String,
+    },
+    Replaced {
+        old_sha256: String,
+        new_sha256: String,
+    },
+}
+
+/// The differences between two versions of a record, from [`Record::diff`].
+///
+/// Charges are matched by description and bonds by type, so a charge with a new grade or a bond
+/// with a new amount is modified rather than removed and added.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RecordDiff {
+    pub profile: Vec<FieldChange>,
+    pub aliases_added: Vec<String>,
+    pub aliases_removed: Vec<String>,
+    pub charges_added: Vec<ChargeVersion>,
+    pub charges_removed: Vec<ChargeVersion>,
+    pub charges_modified: Vec<Modified<ChargeVersion>>,
+    pub bonds_added: Vec<BondVersion>,
+    pub bonds_removed: Vec<BondVersion>,
+    pub bonds_modified: Vec<Modified<BondVersion>>,
+    pub image: Option<ImageChange>,
+}
+
+impl RecordDiff {
+    /// Returns the changes going from `old` to `new`.
+    pub fn between(old: &Record, new: &Record) -> RecordDiff {
+        let profile = profile_columns(&old.profile)
+            .into_iter()
+            .zip(profile_columns(&new.profile))
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((field, old), (_, new))| FieldChange { field, old, new })
+            .collect();
+
+        let old_aliases = old.profile.aliases.clone().unwrap_or_default();
+        let new_aliases = new.profile.aliases.clone().unwrap_or_default();
+
+        let old_charges: Vec<ChargeVersion> = old
+            .charges
+            .charges
+            .iter()
+            .map(ChargeVersion::from)
+            .collect();
+        let new_charges: Vec<ChargeVersion> = new
+            .charges
+            .charges
+            .iter()
+            .map(ChargeVersion::from)
+            .collect();
+        let (charges_removed, charges_added, charges_modified) =
+            diff_items(&old_charges, &new_charges, |c| c.description.clone());
+
+        let old_bonds: Vec<BondVersion> = old.bond.bonds.iter().map(BondVersion::from).collect();
+        let new_bonds: Vec<BondVersion> = new.bond.bonds.iter().map(BondVersion::from).collect();
+        let (bonds_removed, bonds_added, bonds_modified) =
+            diff_items(&old_bonds, &new_bonds, |b| b.bond_type.clone());
+
+        RecordDiff {
+            profile,
+            aliases_added: new_aliases
+                .iter()
+                .filter(|alias| !old_aliases.contains(alias))
+                .cloned()
+                .collect(),
+            aliases_removed: old_aliases
+                .iter()
+                .filter(|alias| !new_aliases.contains(alias))
+                .cloned()
+                .collect(),
+            charges_added,
+            charges_removed,
+            charges_modified,
+            bonds_added,
+            bonds_removed,
+            bonds_modified,
+            image: image_change(img_sha256(&old.profile), img_sha256(&new.profile)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == RecordDiff::default()
+    }
+}
+
+/// Every profile column that's compared, with its value. Dates are formatted the way the site
+/// shows them.
+pub(crate) fn profile_columns(profile: &InmateProfile) -> [(&'static str, Option<String>); 15] {
+    [
+        ("first_name", Some(profile.first_name.clone())),
+        ("middle_name", profile.middle_name.clone()),
+        ("last_name", Some(profile.last_name.clone())),
+        ("affix", profile.affix.clone()),
+        ("permanent_id", profile.perm_id.clone()),
+        ("sex", profile.sex.clone()),
+        ("dob", Some(profile.dob.clone())),
+        ("arresting_agency", profile.arrest_agency.clone()),
+        ("booking_date", Some(profile.booking_date_iso8601.clone())),
+        ("booking_number", profile.booking_number.clone()),
+        ("height", profile.height.clone()),
+        ("weight", profile.weight.clone()),
+        ("race", profile.race.clone()),
+        ("eye_color", profile.eye_color.clone()),
+        ("scil_sysid", profile.scil_sys_id.clone()),
+    ]
+}
+
+/// Builds a profile from the columns [`profile_columns`] compares, read by name with `column`.
+/// Missing required columns are left empty.
+pub(crate) fn profile_from_columns<F>(mut column: F) -> Result<InmateProfile, Error>
+where
+    F: FnMut(&'static str) -> Result<Option<String>, Error>,
+{
+    Ok(InmateProfile {
+        first_name: column("first_name")?.unwrap_or_default(),
+        middle_name: column("middle_name")?,
+        last_name: column("last_name")?.unwrap_or_default(),
+        affix: column("affix")?,
+        perm_id: column("permanent_id")?,
+        sex: column("sex")?,
+        dob: column("dob")?.unwrap_or_default(),
+        arrest_agency: column("arresting_agency")?,
+        booking_date_iso8601: column("booking_date")?.unwrap_or_default(),
+        booking_number: column("booking_number")?,
+        height: column("height")?,
+        weight: column("weight")?,
+        race: column("race")?,
+        eye_color: column("eye_color")?,
+        scil_sys_id: column("scil_sysid")?,
+        ..Default::default()
+    })
+}
+
+fn img_sha256(profile: &InmateProfile) -> Option<&str> {
+    profile
+        .img_metadata
+        .as_ref()
+        .map(|metadata| metadata.sha256.as_str())
+}
+
+fn image_change(old: Option<&str>, new: Option<&str>) -> Option<ImageChange> {
+    match (old, new) {
+        (None, Some(new)) => Some(ImageChange::Added {
+            sha256: new.to_string(),
+        }),
+        (Some(old), None) => Some(ImageChange::Removed {
+            sha256: old.to_string(),
+        }),
+        (Some(old), Some(new)) if old != new => Some(ImageChange::Replaced {
+            old_sha256: old.to_string(),
+            new_sha256: new.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// Splits the items into (removed, added, modified). Identical items match first, then leftover
+/// items with the same `pair_key` are paired up as modified.
+fn diff_items<T, K, F>(old: &[T], new: &[T], pair_key: F) -> (Vec<T>, Vec<T>, Vec<Modified<T>>)
+where
+    T: PartialEq + Clone,
+    K: PartialEq,
+    F: Fn(&T) -> K,
+{
+    let (removed, added) = diff_rows(old, new);
+    let mut added: Vec<Option<&T>> = added.into_iter().map(|idx| Some(&new[idx])).collect();
+
+    let (mut removed_items, mut modified) = (Vec::new(), Vec::new());
+    for old_item in removed.into_iter().map(|idx| &old[idx]) {
+        let key = pair_key(old_item);
+        let paired = added
+            .iter_mut()
+            .find(|new_item| new_item.is_some_and(|new_item| pair_key(new_item) == key));
+        match paired.and_then(Option::take) {
+            Some(new_item) => modified.push(Modified {
+                old: old_item.clone(),
+                new: new_item.clone(),
+            }),
+            None => removed_items.push(old_item.clone()),
+        }
+    }
+
+    let added_items = added.into_iter().flatten().cloned().collect();
+    (removed_items, added_items, modified)
+}
+
+/// Matches new rows against existing ones, one for one so duplicates are counted. Returns the
+/// indices of the (existing rows that are gone, new rows that aren't stored yet).
+pub(crate) fn diff_rows<K: PartialEq>(existing: &[K], new: &[K]) -> (Vec<usize>, Vec<usize>) {
+    let mut matched = vec![false; existing.len()];
+    let mut added = Vec::new();
+    for (new_idx, key) in new.iter().enumerate() {
+        match (0..existing.len()).find(|&idx| !matched[idx] && existing[idx] == *key) {
+            Some(idx) => matched[idx] = true,
+            None => added.push(new_idx),
+        }
+    }
+
+    let removed = (0..existing.len()).filter(|&idx| !matched[idx]).collect();
+    (removed, added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inmate::{Bond, BondInformation, Charge, ChargeGrade, ChargeInformation};
+    use crate::utils::Money;
+
+    fn record(weight: &str, charges: Vec<Charge>, bonds: Vec<Bond>) -> Record {
+        Record {
+            url: String::new(),
+            profile: InmateProfile {
+                first_name: "JOHN".to_string(),
+                last_name: "DOE".to_string(),
+                weight: Some(weight.to_string()),
+                aliases: Some(vec!["JOHNNY DOE".to_string()]),
+                ..Default::default()
+            },
+            bond: BondInformation { bonds },
+            charges: ChargeInformation { charges },
+        }
+    }
+
+    fn charge(description: &str, grade: ChargeGrade) -> Charge {
+        Charge {
+            description: description.to_string(),
+            grade,
+            offense_date: "10/01/2024".to_string(),
+        }
+    }
+
+    fn bond(bond_type: &str, cents: u64) -> Bond {
+        Bond {
+            bond_type: bond_type.to_string(),
+            bond_amount: Money::from_cents(cents),
+            amount_unparseable: false,
+            amount_not_set: false,
+        }
+    }
+
+    #[test]
+    fn test_diff_rows() {
+        let existing = ["a", "b", "b", "c"];
+        let new = ["b", "c", "d", "d"];
+        assert_eq!(diff_rows(&existing, &new), (vec![0, 2], vec![2, 3]));
+        assert_eq!(diff_rows(&existing, &existing), (vec![], vec![]));
+        assert_eq!(diff_rows::<&str>(&[], &new), (vec![], vec![0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn test_record_diff_unchanged() {
+        let old = record(
+            "180 lbs",
+            vec![charge("THEFT", ChargeGrade::Felony)],
+            vec![bond("Cash Only", 100)],
+        );
+        let new = record(
+            "180 lbs",
+            vec![charge("THEFT", ChargeGrade::Felony)],
+            vec![bond("Cash Only", 100)],
+        );
+        assert!(old.diff(&new).is_empty());
+    }
+
+    #[test]
+    fn test_record_diff() {
+        let old = record(
+            "180 lbs",
+            vec![
+                charge("THEFT", ChargeGrade::Misdemeanor),
+                charge("TRESPASS", ChargeGrade::Misdemeanor),
+            ],
+            vec![bond("Cash Only", 100_000), bond("Cash or Surety", 500)],
+        );
+        let mut new = record(
+            "190 lbs",
+            vec![
+                charge("THEFT", ChargeGrade::Felony),
+                charge("ASSAULT", ChargeGrade::Felony),
+            ],
+            vec![bond("Cash Only", 50_000)],
+        );
+        new.profile.aliases = Some(vec!["J DOE".to_string()]);
+
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.profile,
+            vec![FieldChange {
+                field: "weight",
+                old: Some("180 lbs".to_string()),
+                new: Some("190 lbs".to_string()),
+            }]
+        );
+        assert_eq!(diff.aliases_added, vec!["J DOE"]);
+        assert_eq!(diff.aliases_removed, vec!["JOHNNY DOE"]);
+        assert_eq!(diff.charges_modified.len(), 1);
+        assert_eq!(
+            diff.charges_modified[0].new.grade.as_deref(),
+            Some("Felony")
+        );
+        assert_eq!(
+            diff.charges_added[0].description.as_deref(),
+            Some("ASSAULT")
+        );
+        assert_eq!(
+            diff.charges_removed[0].description.as_deref(),
+            Some("TRESPASS")
+        );
+        assert_eq!(
+            diff.bonds_modified[0].old.bond_amount,
+            Money::from_cents(100_000)
+        );
+        assert_eq!(
+            diff.bonds_modified[0].new.bond_amount,
+            Money::from_cents(50_000)
+        );
+        assert_eq!(diff.bonds_removed[0].bond_type, "Cash or Surety");
+        assert!(diff.bonds_added.is_empty());
+        assert_eq!(diff.image, None);
+    }
+
+    #[test]
+    fn test_image_change() {
+        assert_eq!(image_change(None, None), None);
+        assert_eq!(image_change(Some("a"), Some("a")), None);
+        assert_eq!(
+            image_change(None, Some("a")),
+            Some(ImageChange::Added {
+                sha256: "a".to_string()
+            })
+        );
+        assert_eq!(
+            image_change(Some("a"), Some("b")),
+            Some(ImageChange::Replaced {
+                old_sha256: "a".to_string(),
+                new_sha256: "b".to_string()
+            })
+        );
+    }
+}
@@ -15,6 +15,8 @@ pub enum Error {
     PostgresError(String),
     /// Error related to AWS S3, with additional explanation
     S3Error(String),
+    /// Error related to decoding or validating images, with additional explanation
+    ImageError(String),
 }
 
 impl std::error::Error for Error {}
@@ -31,6 +33,7 @@ impl std::fmt::Display for Error {
                 write!(f, "Internal Postgres error: {}", explanation)
             }
             Error::S3Error(explanation) => write!(f, "S3 error: {}", explanation),
+            Error::ImageError(explanation) => write!(f, "Image error: {}", explanation),
         }
     }
 }
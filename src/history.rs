@@ -0,0 +1,435 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+
+use crate::inmate::{Bond, Charge};
+use crate::utils::{get_crawl_run_id, Money};
+use crate::Error;
+
+/// A booking's profile as observed by one crawl.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProfileVersion {
+    pub crawl_run_id: String,
+    pub observed_at: DateTime<Utc>,
+    pub first_name: Option<String>,
+    pub middle_name: Option<String>,
+    pub last_name: Option<String>,
+    pub affix: Option<String>,
+    pub permanent_id: Option<String>,
+    pub sex: Option<String>,
+    /// Formatted the way the site shows it
+    pub dob: Option<String>,
+    pub arresting_agency: Option<String>,
+    /// Formatted the way the site shows it
+    pub booking_date: Option<String>,
+    pub booking_number: Option<String>,
+    pub height: Option<String>,
+    pub weight: Option<String>,
+    pub race: Option<String>,
+    pub eye_color: Option<String>,
+    pub scil_sysid: Option<String>,
+}
+
+/// A charge as stored, so versions compare equal to the rows they came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChargeVersion {
+    pub description: Option<String>,
+    pub grade: Option<String>,
+    pub offense_date: Option<String>,
+}
+
+impl From<&Charge> for ChargeVersion {
+    fn from(charge: &Charge) -> Self {
+        ChargeVersion {
+            description: Some(charge.description.clone()),
+            grade: Some(charge.grade.to_string()),
+            offense_date: Some(charge.offense_date.clone()),
+        }
+    }
+}
+
+impl std::fmt::Display for ChargeVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}, {})",
+            self.description.as_deref().unwrap_or_default(),
+            self.grade.as_deref().unwrap_or_default(),
+            self.offense_date.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BondVersion {
+    pub bond_type: String,
+    pub bond_amount: Money,
+    pub amount_unparseable: bool,
+    pub amount_not_set: bool,
+}
+
+impl From<&Bond> for BondVersion {
+    fn from(bond: &Bond) -> Self {
+        BondVersion {
+            bond_type: bond.bond_type.clone(),
+            bond_amount: bond.bond_amount,
+            amount_unparseable: bond.amount_unparseable,
+            amount_not_set: bond.amount_not_set,
+        }
+    }
+}
+
+impl std::fmt::Display for BondVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.amount_unparseable {
+            write!(f, "{} (unparseable amount)", self.bond_type)
+        } else if self.amount_not_set {
+            write!(f, "{} (no amount set)", self.bond_type)
+        } else {
+            write!(f, "{} {}", self.bond_type, self.bond_amount)
+        }
+    }
+}
+
+/// A booking as it was known at `as_of`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BookingSnapshot {
+    pub inmate_id: i32,
+    pub as_of: DateTime<Utc>,
+    /// The latest profile version observed at or before `as_of`
+    pub profile: ProfileVersion,
+    pub charges: Vec<ChargeVersion>,
+    pub bonds: Vec<BondVersion>,
+}
+
+/// Appends the inmate's current profile to its history.
+pub(crate) async fn record_profile_version(
+    inmate_id: i32,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO inmate_history
+            (inmate_id, crawl_run_id, first_name, middle_name, last_name, affix, permanent_id,
+             sex, dob, arresting_agency, booking_date, booking_number, height, weight, race,
+             eye_color, scil_sysid)
+        SELECT id, $2, first_name, middle_name, last_name, affix, permanent_id,
+            sex, dob, arresting_agency, booking_date, booking_number, height, weight, race,
+            eye_color, scil_sysid
+        FROM inmate
+        WHERE id = $1
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(get_crawl_run_id())
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Appends charges being added to, or removed from, the booking, in one round trip.
+pub(crate) async fn record_charge_changes(
+    inmate_id: i32,
+    charges: &[ChargeVersion],
+    removed: bool,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), Error> {
+    if charges.is_empty() {
+        return Ok(());
+    }
+
+    let descriptions: Vec<Option<&str>> = charges
+        .iter()
+        .map(|charge| charge.description.as_deref())
+        .collect();
+    let grades: Vec<Option<&str>> = charges
+        .iter()
+        .map(|charge| charge.grade.as_deref())
+        .collect();
+    let offense_dates: Vec<Option<&str>> = charges
+        .iter()
+        .map(|charge| charge.offense_date.as_deref())
+        .collect();
+    sqlx::query(
+        r#"
+        INSERT INTO charge_history
+            (inmate_id, crawl_run_id, description, grade, offense_date, removed)
+        SELECT $1, $2, description, grade, offense_date, $6
+        FROM UNNEST($3::text[], $4::text[], $5::text[])
+            WITH ORDINALITY AS charges(description, grade, offense_date, idx)
+        ORDER BY idx
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(get_crawl_run_id())
+    .bind(&descriptions)
+    .bind(&grades)
+    .bind(&offense_dates)
+    .bind(removed)
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Appends bonds being added to, or removed from, the booking, in one round trip.
+pub(crate) async fn record_bond_changes(
+    inmate_id: i32,
+    bonds: &[BondVersion],
+    removed: bool,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), Error> {
+    if bonds.is_empty() {
+        return Ok(());
+    }
+
+    let types: Vec<&str> = bonds.iter().map(|bond| bond.bond_type.as_str()).collect();
+    let amounts = bonds
+        .iter()
+        .map(|bond| i64::try_from(bond.bond_amount))
+        .collect::<Result<Vec<i64>, _>>()?;
+    let unparseable: Vec<bool> = bonds.iter().map(|bond| bond.amount_unparseable).collect();
+    let not_set: Vec<bool> = bonds.iter().map(|bond| bond.amount_not_set).collect();
+    sqlx::query(
+        r#"
+        INSERT INTO bond_history
+            (inmate_id, crawl_run_id, type, amount_pennies, amount_unparseable, amount_not_set,
+                removed)
+        SELECT $1, $2, type, amount_pennies, amount_unparseable, amount_not_set, $7
+        FROM UNNEST($3::text[], $4::bigint[], $5::boolean[], $6::boolean[])
+            WITH ORDINALITY AS bonds(type, amount_pennies, amount_unparseable, amount_not_set, idx)
+        ORDER BY idx
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(get_crawl_run_id())
+    .bind(&types)
+    .bind(&amounts)
+    .bind(&unparseable)
+    .bind(&not_set)
+    .bind(removed)
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Reconstructs the booking as it was known at `as_of` from its history. Returns None if the
+/// booking hadn't been observed yet.
+///
+/// Bookings stored before history was kept start with their state at migration time, dated to
+/// their booking date.
+pub async fn booking_as_of(
+    inmate_id: i32,
+    as_of: DateTime<Utc>,
+    pool: &PgPool,
+) -> Result<Option<BookingSnapshot>, Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT crawl_run_id, observed_at, first_name, middle_name, last_name, affix, permanent_id,
+            sex, to_char(dob, 'MM/DD/YYYY') AS dob, arresting_agency,
+            to_char(booking_date AT TIME ZONE 'America/Chicago', 'MM/DD/YYYY HH24:MI') AS booking_date,
+            booking_number, height, weight, race, eye_color, scil_sysid
+        FROM inmate_history
+        WHERE inmate_id = $1 AND observed_at <= $2
+        ORDER BY observed_at DESC, id DESC
+        LIMIT 1
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(as_of)
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let profile = ProfileVersion {
+        crawl_run_id: row.try_get("crawl_run_id")?,
+        observed_at: row.try_get("observed_at")?,
+        first_name: row.try_get("first_name")?,
+        middle_name: row.try_get("middle_name")?,
+        last_name: row.try_get("last_name")?,
+        affix: row.try_get("affix")?,
+        permanent_id: row.try_get("permanent_id")?,
+        sex: row.try_get("sex")?,
+        dob: row.try_get("dob")?,
+        arresting_agency: row.try_get("arresting_agency")?,
+        booking_date: row.try_get("booking_date")?,
+        booking_number: row.try_get("booking_number")?,
+        height: row.try_get("height")?,
+        weight: row.try_get("weight")?,
+        race: row.try_get("race")?,
+        eye_color: row.try_get("eye_color")?,
+        scil_sysid: row.try_get("scil_sysid")?,
+    };
+
+    let rows = sqlx::query(
+        r#"
+        SELECT description, grade, offense_date, removed
+        FROM charge_history
+        WHERE inmate_id = $1 AND observed_at <= $2
+        ORDER BY id
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(as_of)
+    .fetch_all(pool)
+    .await?;
+    let mut charge_changes = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        let charge = ChargeVersion {
+            description: row.try_get("description")?,
+            grade: row.try_get("grade")?,
+            offense_date: row.try_get("offense_date")?,
+        };
+        charge_changes.push((charge, row.try_get::<bool, _>("removed")?));
+    }
+
+    let rows = sqlx::query(
+        r#"
+        SELECT type, amount_pennies, amount_unparseable, amount_not_set, removed
+        FROM bond_history
+        WHERE inmate_id = $1 AND observed_at <= $2
+        ORDER BY id
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(as_of)
+    .fetch_all(pool)
+    .await?;
+    let mut bond_changes = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        let bond = BondVersion {
+            bond_type: row.try_get("type")?,
+            bond_amount: Money::try_from(row.try_get::<i64, _>("amount_pennies")?)?,
+            amount_unparseable: row.try_get("amount_unparseable")?,
+            amount_not_set: row.try_get("amount_not_set")?,
+        };
+        bond_changes.push((bond, row.try_get::<bool, _>("removed")?));
+    }
+
+    Ok(Some(BookingSnapshot {
+        inmate_id,
+        as_of,
+        profile,
+        charges: replay(charge_changes),
+        bonds: replay(bond_changes),
+    }))
+}
+
+/// Applies (item, removed) changes in order. A removal takes out one matching item, so
+/// duplicates are kept track of.
+fn replay<T: PartialEq>(changes: Vec<(T, bool)>) -> Vec<T> {
+    let mut items = Vec::new();
+    for (item, removed) in changes {
+        if !removed {
+            items.push(item);
+        } else if let Some(idx) = items.iter().position(|existing| *existing == item) {
+            items.remove(idx);
+        }
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inmate::ChargeGrade;
+    use crate::serialize::ImgStoragePolicy;
+    use crate::test_db::{test_pool, test_record, unique_suffix};
+    use crate::upsert::upsert_record;
+
+    /// Returns when the booking's latest profile version was observed.
+    async fn last_observed_at(inmate_id: i32, pool: &PgPool) -> DateTime<Utc> {
+        sqlx::query_scalar("SELECT max(observed_at) FROM inmate_history WHERE inmate_id = $1")
+            .bind(inmate_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_replay() {
+        let changes = vec![
+            ("a", false),
+            ("b", false),
+            ("b", false),
+            ("b", true),
+            ("c", true),
+            ("a", true),
+            ("d", false),
+        ];
+        assert_eq!(replay(changes), vec!["b", "d"]);
+        assert!(replay::<&str>(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, run with `TEST_DATABASE_URL=... cargo test -- --ignored`"]
+    async fn test_booking_as_of() {
+        let pool = test_pool().await;
+        let policy = ImgStoragePolicy::DbOnly;
+        let record = test_record(&unique_suffix());
+        let inmate_id = upsert_record(record.clone(), &pool, &None, policy)
+            .await
+            .unwrap()
+            .inmate_id;
+        let created_at = last_observed_at(inmate_id, &pool).await;
+
+        let mut recrawled = record.clone();
+        recrawled.profile.weight = Some("180".to_string());
+        recrawled.charges.charges[0].grade = ChargeGrade::Felony;
+        let mut second_bond = record.bond.bonds[0].clone();
+        second_bond.bond_amount = Money::from_cents(25_000);
+        recrawled.bond.bonds.push(second_bond);
+        upsert_record(recrawled.clone(), &pool, &None, policy)
+            .await
+            .unwrap();
+        let merged_at = last_observed_at(inmate_id, &pool).await;
+        assert!(merged_at > created_at);
+
+        let before = created_at - chrono::Duration::microseconds(1);
+        assert!(booking_as_of(inmate_id, before, &pool)
+            .await
+            .unwrap()
+            .is_none());
+
+        let first = booking_as_of(inmate_id, created_at, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.profile.observed_at, created_at);
+        assert_eq!(first.profile.weight.as_deref(), Some("170"));
+        assert_eq!(first.profile.dob.as_deref(), Some("01/02/1990"));
+        assert_eq!(
+            first.profile.booking_date.as_deref(),
+            Some("10/01/2024 08:15")
+        );
+        assert_eq!(
+            first.charges,
+            vec![ChargeVersion::from(&record.charges.charges[0])]
+        );
+        assert_eq!(first.bonds, vec![BondVersion::from(&record.bond.bonds[0])]);
+
+        // Between crawls the booking is still as first observed
+        let between = created_at + (merged_at - created_at) / 2;
+        let still_first = booking_as_of(inmate_id, between, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(still_first.profile, first.profile);
+
+        let latest = booking_as_of(inmate_id, Utc::now(), &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.profile.weight.as_deref(), Some("180"));
+        assert_eq!(
+            latest.charges,
+            vec![ChargeVersion::from(&recrawled.charges.charges[0])]
+        );
+        let bonds: Vec<BondVersion> = recrawled.bond.bonds.iter().map(BondVersion::from).collect();
+        assert_eq!(latest.bonds, bonds);
+    }
+}
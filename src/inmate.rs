@@ -4,13 +4,16 @@ use sqlx::Row;
 use std::env;
 
 use crate::{
-    utils::{cents_to_dollars, dollars_to_cents},
+    diff::RecordDiff,
+    mugshot::{self, MugshotMetadata},
+    utils::{cents_to_dollars, parse_bond_amount, BondAmount, Money},
     Error,
 };
 use async_openai::{config::Config, types::CreateEmbeddingRequestArgs};
 use scraper::{Html, Selector};
+use serde::Serialize;
 
-#[derive(Default)]
+#[derive(Clone, Default, Serialize)]
 pub struct InmateProfile {
     pub first_name: String,
     pub middle_name: Option<String>,
@@ -27,8 +30,12 @@ pub struct InmateProfile {
     pub race: Option<String>,
     pub eye_color: Option<String>,
     pub aliases: Option<Vec<String>>,
+    #[serde(skip)]
     pub img_blob: Option<Vec<u8>>,
+    #[serde(skip)]
+    pub img_metadata: Option<MugshotMetadata>,
     pub scil_sys_id: Option<String>,
+    #[serde(skip)]
     pub embedding: Option<Vec<f32>>,
 }
 
@@ -49,43 +56,29 @@ impl InmateProfile {
         ))
         .await;
 
-        let img_selector = Selector::parse(".inmates img").map_err(|_| Error::ParseError)?;
-        let img = if let Some(img_url) = html
-            .select(&img_selector)
-            .next()
-            .and_then(|img| img.attr("src"))
-        {
-            let full_img_url = format!("https:{}", img_url);
-            trace!("Found img URL: {:#?}", full_img_url);
-            Some(client.get(full_img_url).send())
-        } else {
-            None
-        };
+        let img = InmateProfile::get_img_url(html)?.map(|img_url| client.get(img_url).send());
 
-        let mut profile = InmateProfile::default();
-        profile.scil_sys_id = Some(sys_id.to_string());
-        profile.set_core_profile_data(html)?;
+        let mut profile = InmateProfile::from_html(html, sys_id)?;
 
         // Not every inmate will have an image,
         if let Some(img) = img {
             match img.await {
                 Ok(img_resp) => {
                     // sometimes resp status is not success, but still have request bytes & valid img url
-                    let resp_status = img_resp.status().clone();
+                    let resp_status = img_resp.status();
                     if let Ok(img_blob) = img_resp.bytes().await {
-                        profile.img_blob = if resp_status.is_success() {
+                        if resp_status.is_success() {
                             trace!(
                                 "Successfully fetched img for inmate: {:#?}",
                                 profile.get_full_name()
                             );
-                            Some(img_blob.to_vec())
+                            profile.set_img_blob(img_blob.to_vec());
                         } else {
                             warn!(
                                 "Fetched img from URL for inmate: {:#?}. But had non-success status: {:#?}",
                                 profile.get_full_name(),
                                 resp_status
                             );
-                            None
                         }
                     }
                 }
@@ -95,6 +88,21 @@ impl InmateProfile {
 
         // TODO! Get and set embedding in build? Already do it in serialize (that way migrate-db
         // has a nice way to get embeddings for all records)
+        Ok(profile)
+    }
+
+    /// Parses the profile data out of an inmate detail page without touching the network. The
+    /// image is not fetched, so `img_blob` is always `None`.
+    ///
+    /// # Errors
+    /// ParseError: If any core attribute (first name, last name, dob, booking date) is missing
+    pub fn from_html(html: &Html, sys_id: &str) -> Result<InmateProfile, Error> {
+        let mut profile = InmateProfile {
+            scil_sys_id: Some(sys_id.to_string()),
+            ..Default::default()
+        };
+        profile.set_core_profile_data(html)?;
+
         if profile.first_name.is_empty()
             || profile.last_name.is_empty()
             || profile.dob.is_empty()
@@ -107,6 +115,54 @@ impl InmateProfile {
         Ok(profile)
     }
 
+    /// Sets the image blob and its metadata if the blob decodes to a real mugshot. Invalid images
+    /// and the county's "no photo" placeholder are dropped, so the profile is treated as having
+    /// no image and gets picked up again by the updatelist.
+    pub fn set_img_blob(&mut self, img_blob: Vec<u8>) {
+        self.img_blob = None;
+        self.img_metadata = None;
+
+        match mugshot::validate_mugshot(&img_blob) {
+            Ok(metadata) if mugshot::is_env_placeholder(&metadata) => {
+                info!(
+                    "Ignoring placeholder img for inmate: {:#?}",
+                    self.get_full_name()
+                );
+            }
+            Ok(metadata) => {
+                self.img_blob = Some(img_blob);
+                self.img_metadata = Some(metadata);
+            }
+            Err(e) => {
+                warn!(
+                    "Ignoring invalid img for inmate: {:#?}. Error: {}",
+                    self.get_full_name(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Returns the absolute mugshot URL found on an inmate detail page, if there is one. The site
+    /// uses protocol-relative srcs, which get https; absolute srcs are kept as is.
+    pub fn get_img_url(html: &Html) -> Result<Option<String>, Error> {
+        let img_selector = Selector::parse(".inmates img").map_err(|_| Error::ParseError)?;
+        let img_url = html
+            .select(&img_selector)
+            .next()
+            .and_then(|img| img.attr("src"))
+            .map(|img_url| {
+                if img_url.starts_with("//") {
+                    format!("https:{}", img_url)
+                } else {
+                    img_url.to_string()
+                }
+            });
+        trace!("Found img URL: {:#?}", img_url);
+
+        Ok(img_url)
+    }
+
     fn get_aliases(aliases: &str) -> Option<Vec<String>> {
         let alias_vec = aliases
             .split(',')
@@ -115,8 +171,8 @@ impl InmateProfile {
             .collect::<Vec<String>>();
 
         match alias_vec.len() {
-            0 => return None,
-            _ => return Some(alias_vec),
+            0 => None,
+            _ => Some(alias_vec),
         }
     }
 
@@ -143,7 +199,7 @@ impl InmateProfile {
                             found_dts += 1;
                         }
                         "middle:" => {
-                            self.middle_name = (!dd_text.is_empty()).then(|| dd_text);
+                            self.middle_name = (!dd_text.is_empty()).then_some(dd_text);
                             found_dts += 1;
                         }
                         "last:" => {
@@ -151,15 +207,15 @@ impl InmateProfile {
                             found_dts += 1;
                         }
                         "affix:" => {
-                            self.affix = (!dd_text.is_empty()).then(|| dd_text);
+                            self.affix = (!dd_text.is_empty()).then_some(dd_text);
                             found_dts += 1;
                         }
                         "permanent id:" => {
-                            self.perm_id = (!dd_text.is_empty()).then(|| dd_text);
+                            self.perm_id = (!dd_text.is_empty()).then_some(dd_text);
                             found_dts += 1;
                         }
                         "sex:" => {
-                            self.sex = (!dd_text.is_empty()).then(|| dd_text);
+                            self.sex = (!dd_text.is_empty()).then_some(dd_text);
                             found_dts += 1;
                         }
                         "date of birth:" => {
@@ -171,15 +227,15 @@ impl InmateProfile {
                             found_dts += 1;
                         }
                         "weight:" => {
-                            self.weight = (!dd_text.is_empty()).then(|| dd_text);
+                            self.weight = (!dd_text.is_empty()).then_some(dd_text);
                             found_dts += 1;
                         }
                         "race:" => {
-                            self.race = (!dd_text.is_empty()).then(|| dd_text);
+                            self.race = (!dd_text.is_empty()).then_some(dd_text);
                             found_dts += 1;
                         }
                         "eye color:" => {
-                            self.eye_color = (!dd_text.is_empty()).then(|| dd_text);
+                            self.eye_color = (!dd_text.is_empty()).then_some(dd_text);
                             found_dts += 1;
                         }
                         "alias(es):" => {
@@ -187,7 +243,7 @@ impl InmateProfile {
                             found_dts += 1;
                         }
                         "committing agency:" => {
-                            self.arrest_agency = (!dd_text.is_empty()).then(|| dd_text);
+                            self.arrest_agency = (!dd_text.is_empty()).then_some(dd_text);
                             found_dts += 1;
                         }
                         "booking date time:" => {
@@ -195,7 +251,7 @@ impl InmateProfile {
                             found_dts += 1;
                         }
                         "booking number:" => {
-                            self.booking_number = (!dd_text.is_empty()).then(|| dd_text);
+                            self.booking_number = (!dd_text.is_empty()).then_some(dd_text);
                             found_dts += 1;
                         }
                         _ => {
@@ -240,6 +296,16 @@ impl InmateProfile {
         )
     }
 
+    /// Returns the content addressed storage key of the profile's image, if it has a validated
+    /// image. See [`mugshot::content_key`].
+    pub fn get_img_key(&self) -> Option<String> {
+        self.img_metadata
+            .as_ref()
+            .map(|metadata| mugshot::content_key(&metadata.sha256))
+    }
+
+    /// Returns the legacy storage key of the profile's image, derived from the core attributes
+    /// instead of the image bytes. New images are stored under [`InmateProfile::get_img_key`].
     pub fn get_hash_on_core_attributes(&self) -> String {
         let inmate_img_hash_input = format!(
             "{}{}{}{}",
@@ -281,6 +347,7 @@ impl std::fmt::Debug for InmateProfile {
                     &"None"
                 },
             )
+            .field("img_metadata", &self.img_metadata)
             .finish()
     }
 }
@@ -289,6 +356,26 @@ impl std::fmt::Debug for InmateProfile {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_detail_url() {
+        let listing_url = format!(
+            "{}?comdate=10%2F06%2F2024",
+            crate::SCOTT_COUNTY_INMATE_TRAVERSAL_ROOT
+        );
+        assert_eq!(
+            Record::detail_url(&listing_url, "?sysid=100005").unwrap(),
+            "https://www.scottcountyiowa.us/sheriff/inmates.php?sysid=100005"
+        );
+        assert_eq!(
+            Record::detail_url("http://127.0.0.1:8080/sheriff/inmates.php", "?sysid=1").unwrap(),
+            "http://127.0.0.1:8080/sheriff/inmates.php?sysid=1"
+        );
+        assert!(matches!(
+            Record::detail_url("not a url", "?sysid=1"),
+            Err(Error::ArgumentError)
+        ));
+    }
+
     #[test]
     fn test_get_aliases_basic() {
         let aliases = "John Doe, Jane Doe";
@@ -347,6 +434,8 @@ mod tests {
 pub struct DbInmateProfile {
     pub id: i64,
     pub profile: InmateProfile,
+    /// The image's object store key, once it's uploaded
+    pub img_url: Option<String>,
 }
 
 impl DbInmateProfile {
@@ -354,6 +443,7 @@ impl DbInmateProfile {
         DbInmateProfile {
             id,
             profile: inmate_profile,
+            img_url: None,
         }
     }
 }
@@ -363,51 +453,140 @@ impl sqlx::FromRow<'_, sqlx::sqlite::SqliteRow> for DbInmateProfile {
     /// Create an InmateProfile from a SqliteRow, assuming the row has been joined several times to
     /// aggregate all the necessary data.
     fn from_row(row: &sqlx::sqlite::SqliteRow) -> Result<Self, sqlx::Error> {
+        let mut profile = InmateProfile {
+            first_name: row.get("first_name"),
+            middle_name: row.get("middle_name"),
+            last_name: row.get("last_name"),
+            affix: row.get("affix"),
+            perm_id: row.get("permanent_id"),
+            sex: row.get("sex"),
+            dob: row.get("dob"),
+            arrest_agency: row.get("arresting_agency"),
+            booking_date_iso8601: row.get("booking_date"),
+            booking_number: row.get("booking_number"),
+            height: row.get("height"),
+            weight: row.get("weight"),
+            race: row.get("race"),
+            eye_color: row.get("eye_color"),
+            aliases: row
+                .get::<Option<String>, _>("aliases")
+                .and_then(|aliases: String| InmateProfile::get_aliases(&aliases)),
+            img_blob: None,
+            img_metadata: None,
+            scil_sys_id: row.get("scil_sysid"),
+            embedding: Option::None,
+        };
+        if let Some(img_blob) = row.get::<Option<Vec<u8>>, _>("img") {
+            profile.set_img_blob(img_blob);
+        }
+
         Ok(DbInmateProfile {
             id: row.get("id"),
-            profile: InmateProfile {
-                first_name: row.get("first_name"),
-                middle_name: row.get("middle_name"),
-                last_name: row.get("last_name"),
-                affix: row.get("affix"),
-                perm_id: row.get("permanent_id"),
-                sex: row.get("sex"),
-                dob: row.get("dob"),
-                arrest_agency: row.get("arresting_agency"),
-                booking_date_iso8601: row.get("booking_date"),
-                booking_number: row.get("booking_number"),
-                height: row.get("height"),
-                weight: row.get("weight"),
-                race: row.get("race"),
-                eye_color: row.get("eye_color"),
-                aliases: row
-                    .get::<Option<String>, _>("aliases")
-                    .map(|aliases: String| InmateProfile::get_aliases(&aliases))
-                    .flatten(),
-                img_blob: row.get("img"),
-                scil_sys_id: row.get("scil_sysid"),
-                embedding: Option::None,
-            },
+            profile,
+            img_url: None,
         })
     }
 }
 
-#[derive(Debug)]
+impl sqlx::FromRow<'_, sqlx::postgres::PgRow> for DbInmateProfile {
+    /// Create an InmateProfile from a PgRow selected by [`crate::records`], with dates formatted
+    /// the way the site shows them, the aliases aggregated and the latest image joined. The image
+    /// is referenced by its metadata; its bytes and average hash aren't loaded.
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        let aliases: Vec<String> = row.try_get("aliases")?;
+        let img_metadata = match row.try_get::<Option<String>, _>("img_sha256")? {
+            Some(sha256) => Some(MugshotMetadata {
+                format: row
+                    .try_get::<String, _>("img_format")?
+                    .parse()
+                    .map_err(|e| sqlx::Error::ColumnDecode {
+                        index: "img_format".to_string(),
+                        source: Box::new(e),
+                    })?,
+                width: row.try_get::<i32, _>("img_width")? as u32,
+                height: row.try_get::<i32, _>("img_height")? as u32,
+                sha256,
+                ahash: None,
+            }),
+            None => None,
+        };
+
+        let profile = InmateProfile {
+            first_name: row.try_get("first_name")?,
+            middle_name: row.try_get("middle_name")?,
+            last_name: row.try_get("last_name")?,
+            affix: row.try_get("affix")?,
+            perm_id: row.try_get("permanent_id")?,
+            sex: row.try_get("sex")?,
+            dob: row.try_get("dob")?,
+            arrest_agency: row.try_get("arresting_agency")?,
+            booking_date_iso8601: row.try_get("booking_date")?,
+            booking_number: row.try_get("booking_number")?,
+            height: row.try_get("height")?,
+            weight: row.try_get("weight")?,
+            race: row.try_get("race")?,
+            eye_color: row.try_get("eye_color")?,
+            aliases: (!aliases.is_empty()).then_some(aliases),
+            img_blob: None,
+            img_metadata,
+            scil_sys_id: row.try_get("scil_sysid")?,
+            embedding: None,
+        };
+
+        Ok(DbInmateProfile {
+            id: row.try_get::<i32, _>("id")? as i64,
+            profile,
+            img_url: row
+                .try_get::<Option<String>, _>("img_url")?
+                .filter(|img_url| !img_url.is_empty()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Bond {
     pub bond_type: String,
-    pub bond_amount: u64,
+    pub bond_amount: Money,
+    /// Set when the site's amount couldn't be parsed, in which case `bond_amount` is zero
+    pub amount_unparseable: bool,
+    /// Set when the site shows no amount, e.g. "N/A" or blank, in which case `bond_amount` is zero
+    pub amount_not_set: bool,
 }
 
 impl sqlx::FromRow<'_, sqlx::sqlite::SqliteRow> for Bond {
     fn from_row(row: &sqlx::sqlite::SqliteRow) -> Result<Self, sqlx::Error> {
         Ok(Bond {
             bond_type: row.get("type"),
-            bond_amount: row.get::<i64, &str>("amount_pennies") as u64,
+            bond_amount: Money::try_from(row.try_get::<i64, &str>("amount_pennies")?).map_err(
+                |e| sqlx::Error::ColumnDecode {
+                    index: "amount_pennies".to_string(),
+                    source: Box::new(e),
+                },
+            )?,
+            // Older SQLite databases predate the flags
+            amount_unparseable: row.try_get("amount_unparseable").unwrap_or(false),
+            amount_not_set: row.try_get("amount_not_set").unwrap_or(false),
         })
     }
 }
 
-#[derive(Debug)]
+impl sqlx::FromRow<'_, sqlx::postgres::PgRow> for Bond {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Bond {
+            bond_type: row.try_get("type")?,
+            bond_amount: Money::try_from(row.try_get::<i64, &str>("amount_pennies")?).map_err(
+                |e| sqlx::Error::ColumnDecode {
+                    index: "amount_pennies".to_string(),
+                    source: Box::new(e),
+                },
+            )?,
+            amount_unparseable: row.try_get("amount_unparseable")?,
+            amount_not_set: row.try_get("amount_not_set")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct BondInformation {
     pub bonds: Vec<Bond>,
 }
@@ -431,8 +610,21 @@ impl BondInformation {
                     continue;
                 }
             };
-            let bond_amount = match td.nth(0) {
-                Some(td) => dollars_to_cents(&td.text().collect::<String>()),
+            let (bond_amount, amount_unparseable, amount_not_set) = match td.nth(0) {
+                Some(td) => {
+                    let dollars = td.text().collect::<String>();
+                    match parse_bond_amount(&dollars) {
+                        Ok(BondAmount::Amount(bond_amount)) => (bond_amount, false, false),
+                        Ok(BondAmount::NotSet) => (Money::ZERO, false, true),
+                        Err(_) => {
+                            warn!(
+                                "Unparseable bond amount {:?} for {}. Storing it as unparseable",
+                                dollars, bond_type
+                            );
+                            (Money::ZERO, true, false)
+                        }
+                    }
+                }
                 None => {
                     warn!("No bond amount found in row: {:#?}. Continuing in hope there is a non-corrupt bond amount", row);
                     continue;
@@ -442,6 +634,8 @@ impl BondInformation {
             bonds.push(Bond {
                 bond_type,
                 bond_amount,
+                amount_unparseable,
+                amount_not_set,
             });
         }
 
@@ -458,15 +652,31 @@ impl BondInformation {
             .iter()
             .any(|b| b.bond_type.to_lowercase() == "unbondable");
         if unbondable {
-            return "unbondable".to_string();
+            "unbondable".to_string()
+        } else if self.bonds.iter().all(|b| b.amount_not_set) {
+            "no amount set".to_string()
         } else {
-            let amount_pennies = self.bonds.iter().map(|b| b.bond_amount).sum::<u64>();
-            return cents_to_dollars(amount_pennies);
+            let amount_pennies = Money::checked_sum(self.bonds.iter().map(|b| b.bond_amount));
+            match amount_pennies {
+                Some(amount_pennies)
+                    if !self
+                        .bonds
+                        .iter()
+                        .any(|b| b.amount_unparseable || b.amount_not_set) =>
+                {
+                    cents_to_dollars(amount_pennies)
+                }
+                Some(_) => "an unknown amount".to_string(),
+                None => {
+                    warn!("Bond total overflows: {:#?}", self.bonds);
+                    "an unknown amount".to_string()
+                }
+            }
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub enum ChargeGrade {
     Felony,
     Misdemeanor,
@@ -494,7 +704,7 @@ impl std::fmt::Display for ChargeGrade {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Charge {
     pub description: String,
     pub grade: ChargeGrade,
@@ -511,7 +721,24 @@ impl sqlx::FromRow<'_, sqlx::sqlite::SqliteRow> for Charge {
     }
 }
 
-#[derive(Debug)]
+impl sqlx::FromRow<'_, sqlx::postgres::PgRow> for Charge {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Charge {
+            description: row
+                .try_get::<Option<String>, _>("description")?
+                .unwrap_or_default(),
+            grade: ChargeGrade::from_string(
+                &row.try_get::<Option<String>, _>("grade")?
+                    .unwrap_or_default(),
+            ),
+            offense_date: row
+                .try_get::<Option<String>, _>("offense_date")?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ChargeInformation {
     pub charges: Vec<Charge>,
 }
@@ -540,7 +767,7 @@ impl ChargeInformation {
             };
 
             let grade = match td.nth(0) {
-                Some(grade) => ChargeGrade::from_string(&grade.text().collect::<String>().trim()),
+                Some(grade) => ChargeGrade::from_string(grade.text().collect::<String>().trim()),
                 None => {
                     warn!(
                         "No grade found in row: {:#?}. Defaulting to Misdemeanor!",
@@ -578,7 +805,7 @@ impl ChargeInformation {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Record {
     pub url: String,
     pub profile: InmateProfile,
@@ -590,10 +817,20 @@ impl Record {
     // We should probably update this code to return an option type
     // There is so many different ways to fail here, we can write our own error types, or just return an option
     pub async fn build(client: &reqwest::Client, sys_id: &str) -> Result<Record, Error> {
-        let request_url = format!(
-            "https://www.scottcountyiowa.us/sheriff/inmates.php{}",
-            sys_id
-        );
+        Record::build_from(client, crate::SCOTT_COUNTY_INMATE_TRAVERSAL_ROOT, sys_id).await
+    }
+
+    /// Builds the record of a sys_id (e.g. "?sysid=...") linked from the listing page at
+    /// `listing_url`. The detail page's URL is the link resolved against the listing's.
+    ///
+    /// # Errors
+    /// ArgumentError: If the listing URL or the link isn't a valid URL
+    pub async fn build_from(
+        client: &reqwest::Client,
+        listing_url: &str,
+        sys_id: &str,
+    ) -> Result<Record, Error> {
+        let request_url = Record::detail_url(listing_url, sys_id)?;
         info!("Building record for URL: {:#?}", request_url);
         let record_body = client
             .get(&request_url)
@@ -614,6 +851,50 @@ impl Record {
         })
     }
 
+    /// Resolves a sys_id link against the listing page it's on. Links on the site's day listings
+    /// resolve to the same URL [`Record::build`] requests.
+    fn detail_url(listing_url: &str, sys_id: &str) -> Result<String, Error> {
+        Ok(reqwest::Url::parse(listing_url)
+            .and_then(|url| url.join(sys_id))
+            .map_err(|_| Error::ArgumentError)?
+            .to_string())
+    }
+
+    /// Loads a stored booking back into a record, so it can be compared with a fresh crawl. See
+    /// [`crate::records::load_record`]. The image's bytes and average hash are loaded too, if the
+    /// database still has them. The embedding isn't loaded.
+    ///
+    /// Returns None if there's no inmate with the id.
+    pub async fn load(pool: &sqlx::PgPool, inmate_id: i32) -> Result<Option<Record>, Error> {
+        let Some(mut record) = crate::records::load_record(pool, inmate_id)
+            .await?
+            .map(|db_record| db_record.record)
+        else {
+            return Ok(None);
+        };
+
+        if let Some(img_metadata) = record.profile.img_metadata.as_mut() {
+            let img_blob: Option<Vec<u8>> =
+                sqlx::query_scalar("SELECT img FROM image WHERE sha256 = $1")
+                    .bind(&img_metadata.sha256)
+                    .fetch_optional(pool)
+                    .await?
+                    .flatten();
+            img_metadata.ahash = img_blob
+                .as_deref()
+                .and_then(|img_blob| mugshot::validate_mugshot(img_blob).ok())
+                .and_then(|metadata| metadata.ahash);
+            record.profile.img_blob = img_blob;
+        }
+        Ok(Some(record))
+    }
+
+    /// Returns the changes going from this record to `other`, e.g. from a stored booking to its
+    /// latest crawl.
+    pub fn diff(&self, other: &Record) -> RecordDiff {
+        RecordDiff::between(self, other)
+    }
+
     pub async fn gather_openai_embedding<C>(
         &mut self,
         openai_client: &async_openai::Client<C>,
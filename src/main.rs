@@ -1,40 +1,55 @@
-use async_openai::config::OpenAIConfig;
 use async_openai::Client as OaiClient;
 use log::{info, trace, warn};
-use sqlx::postgres::PgPoolOptions;
 use std::env;
 
-use scjail_crawler_service::serialize::{create_dbs, serialize_records};
-use scjail_crawler_service::{
-    fetch_last_two_days_filtered, fetch_records_filtered, s3_utils, utils::get_blacklist_and_updatelist, serialize::update_null_img_records,
-    Error,
+use scjail_crawler_service::migrations::latest_version;
+use scjail_crawler_service::serialize::{ImgStoragePolicy, SerializeOptions};
+use scjail_crawler_service::object_store::{
+    object_store_from_env, LocalObjectStore, ObjectStore, S3ObjectStore,
 };
+use scjail_crawler_service::store::{record_store_from_url, store_records};
+use scjail_crawler_service::{fetch_last_two_days_filtered, fetch_records_filtered, Error};
 
 #[tokio::main]
 async fn main() -> Result<(), crate::Error> {
     pretty_env_logger::init();
     info!("Running {} v{}...", env!("CARGO_PKG_NAME"),env!("CARGO_PKG_VERSION"));
-    info!("Reading (optional) positional arguments: url");
-    info!("Reading ENV Vars--\n -required: DATABASE_URL, \n -optional: AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, OPENAI_API_KEY, DEV_ENV, REQ_DELAY_MS");
+    info!("Reading (optional) positional arguments: url, or `migrate` to only migrate the schema");
+    info!("Reading ENV Vars--\n -required: DATABASE_URL, \n -optional: OBJECT_STORE, OBJECT_STORE_PATH, AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, AWS_BUCKET_NAME, AWS_REGION, S3_ENDPOINT_URL, S3_FORCE_PATH_STYLE, S3_CREDENTIALS_SOURCE, OPENAI_API_KEY, DEV_ENV, REQ_DELAY_MS, PLACEHOLDER_IMG_SHA256, PLACEHOLDER_IMG_AHASH, MUGSHOT_DERIVATIVES, MUGSHOT_DERIVATIVES_WEBP, CRAWL_RUN_ID, UPLOAD_MAX_ATTEMPTS, IMG_STORAGE_POLICY");
 
-    let pg_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set!");
-    info!("DATABASE_URL: {}", pg_url);
-    let pool_res = PgPoolOptions::new().max_connections(5).connect(&pg_url);
+    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set!");
+    info!("DATABASE_URL: {}", db_url);
+    // SQLite keeps images in the database, so it never needs an object store
+    let uses_sqlite = db_url.starts_with("sqlite:");
+    let mut serialize_options = SerializeOptions::default().with_env()?;
 
-    let aws_s3_client = if let Ok(_) = env::var("AWS_ACCESS_KEY_ID") {
-        trace!("AWS_ACCESS_KEY_ID found, initializing default S3 client...");
-        let (_region, client) = s3_utils::get_default_s3_client().await;
-        Some(client)
+    // `migrate` subcommand: bring the schema up to date without crawling
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        let store = record_store_from_url(&db_url, None, serialize_options).await?;
+        store.migrate().await?;
+        if !uses_sqlite {
+            info!("Schema is at version {}", latest_version());
+        }
+        return Ok(());
+    }
+
+    let object_store: Option<Box<dyn ObjectStore>> = if uses_sqlite {
+        None
+    } else if let Some(store) = object_store_from_env().await? {
+        Some(store)
+    } else if env::var("AWS_ACCESS_KEY_ID").is_ok() {
+        trace!("AWS_ACCESS_KEY_ID found, initializing default S3 object store...");
+        Some(Box::new(S3ObjectStore::from_env().await?))
     } else {
-        warn!("No AWS_ACCESS_KEY_ID env var found for S3 client initialization... (Only environment variables are supported for this implementation)");
-        if let Ok(_) = env::var("AWS_SECRET_ACCESS_KEY") {
+        warn!("No OBJECT_STORE or AWS_ACCESS_KEY_ID env var found for S3 client initialization... (Only environment variables are supported for this implementation)");
+        if env::var("AWS_SECRET_ACCESS_KEY").is_ok() {
             warn!("AWS_SECRET_ACCESS_KEY found, but no AWS_ACCESS_KEY_ID found for S3 client initialization... Invalid configuration!");
             panic!("Production requires AWS env vars for S3 client initialization! Check the initial logs for more information.");
         }
         match env::var("DEV_ENV") {
             Ok(_) => {
-                warn!("DEV_ENV found, continuing in dev mode...");
-                None
+                warn!("DEV_ENV found, continuing in dev mode with a local object store...");
+                Some(Box::new(LocalObjectStore::from_env()))
             }
             _ => {
                 panic!("Production requires AWS env vars for S3 client initialization! Did you mean to run in dev mode? If so, set DEV_ENV.");
@@ -42,7 +57,12 @@ async fn main() -> Result<(), crate::Error> {
         }
     };
 
-    let oai_client = if let Ok(_) = env::var("OPENAI_API_KEY") {
+    if !uses_sqlite {
+        serialize_options.img_storage_policy = ImgStoragePolicy::from_env(object_store.is_some())?;
+        info!("Img storage policy: {:?}", serialize_options.img_storage_policy);
+    }
+
+    let oai_client = if env::var("OPENAI_API_KEY").is_ok() {
         trace!("OpenAI API key found, initializing client...");
         Some(OaiClient::new())
     } else {
@@ -67,19 +87,14 @@ async fn main() -> Result<(), crate::Error> {
         .map_err(|_| Error::InternalError(String::from("Building reqwest client failed!")))?;
 
     info!(
-        "Established clients: aws: {:?}, openai: {:?}",
-        aws_s3_client.is_some(), oai_client.is_some()
+        "Established clients: object store: {:?}, openai: {:?}",
+        object_store.is_some(), oai_client.is_some()
     );
 
-    let pool = pool_res.await.map_err(|e| {
-        Error::InternalError(format!(
-            "Failed to connect to database: {}. e: {}",
-            pg_url, e
-        ))
-    })?;
-    create_dbs(&pool).await?;
+    let store = record_store_from_url(&db_url, object_store, serialize_options).await?;
+    store.migrate().await?;
 
-    let (blacklist, updatelist) = get_blacklist_and_updatelist(45, &pool).await?;
+    let (blacklist, updatelist) = store.blacklist_and_updatelist(45).await?;
     info!("Found these records to blacklist: {:#?}", blacklist.len());
     info!("Found these records due for update: {:#?}", updatelist);
 
@@ -91,15 +106,19 @@ async fn main() -> Result<(), crate::Error> {
         fetch_last_two_days_filtered(&reqwest_client, &blacklist, &updatelist).await?
     };
 
-    info!("Serializing records...");
-    match serialize_records::<_, OpenAIConfig>(new_records, &pool, &oai_client, &aws_s3_client).await {
+    info!("Storing records...");
+    match store_records(&*store, new_records, &oai_client).await {
         Ok(_) => (),
-        Err(e) => warn!("Failed serialize_records call. Check logs to view successful inserts or failures: {:?}", e),
+        Err(e) => warn!("Failed store_records call. Check logs to view successful inserts or failures: {:?}", e),
     }
-    match update_null_img_records(update_records, &pool, &aws_s3_client).await {
+    match store.update_null_img_records(update_records).await {
         Ok(_) => (),
         Err(e) => warn!("Failed to update null image records: {:?}", e),
     }
+    match store.flush().await {
+        Ok(_) => (),
+        Err(e) => warn!("Failed to flush the record store: {:?}", e),
+    }
 
     Ok(())
 }
@@ -0,0 +1,342 @@
+use itertools::Itertools;
+
+use crate::diff::{diff_rows, profile_columns, FieldChange};
+use crate::history::{BondVersion, ChargeVersion};
+use crate::inmate::{Bond, Charge, InmateProfile, Record};
+use crate::upsert::{IdentityMatch, RecordChanges};
+
+/// Profile columns a booking's person is resolved by
+const PERSON_FIELDS: [&str; 6] = [
+    "first_name",
+    "middle_name",
+    "last_name",
+    "affix",
+    "dob",
+    "permanent_id",
+];
+
+/// Profile columns a booking is matched by. A crawl that lacks one never clears the stored value.
+const IDENTITY_FIELDS: [&str; 2] = ["scil_sysid", "booking_number"];
+
+/// The stored booking a re-crawled record matched, as [`plan_merge`] needs it. `Id` is whatever
+/// the backend keys its charge and bond rows by.
+#[derive(Debug, Clone)]
+pub struct StoredBooking<Id> {
+    pub inmate_id: i32,
+    pub matched_by: IdentityMatch,
+    /// The compared profile columns and the aliases linked to the booking
+    pub profile: InmateProfile,
+    /// In the order they were stored
+    pub charges: Vec<(Id, ChargeVersion)>,
+    pub bonds: Vec<(Id, BondVersion)>,
+}
+
+/// The row operations that merge a re-crawled record into its stored booking, from
+/// [`plan_merge`]. The profile columns to update are `changes.fields`, and the aliases to link
+/// `changes.aliases_added`.
+#[derive(Debug, Clone)]
+pub struct MergePlan<Id> {
+    /// The booking's old full name, kept as an alias, if it was renamed
+    pub renamed_from: Option<String>,
+    /// The booking number the booking changes to. Another active booking holding it is a
+    /// duplicate the booking takes it over from, and is set aside before the fields are updated.
+    pub takes_booking_number: Option<String>,
+    pub charges_to_delete: Vec<(Id, ChargeVersion)>,
+    pub charges_to_insert: Vec<Charge>,
+    pub bonds_to_delete: Vec<(Id, BondVersion)>,
+    pub bonds_to_insert: Vec<Bond>,
+    pub changes: RecordChanges,
+}
+
+impl<Id> MergePlan<Id> {
+    /// Returns true if the booking's person should be resolved again, because an alias was added
+    /// or a name or id it's matched by changed.
+    pub fn relinks_person(&self) -> bool {
+        !self.changes.aliases_added.is_empty()
+            || self
+                .changes
+                .fields
+                .iter()
+                .any(|change| PERSON_FIELDS.contains(&change.field))
+    }
+
+    pub fn charges_added(&self) -> Vec<ChargeVersion> {
+        self.charges_to_insert
+            .iter()
+            .map(ChargeVersion::from)
+            .collect()
+    }
+
+    pub fn charges_removed(&self) -> Vec<ChargeVersion> {
+        versions(&self.charges_to_delete)
+    }
+
+    pub fn bonds_added(&self) -> Vec<BondVersion> {
+        self.bonds_to_insert.iter().map(BondVersion::from).collect()
+    }
+
+    pub fn bonds_removed(&self) -> Vec<BondVersion> {
+        versions(&self.bonds_to_delete)
+    }
+}
+
+/// Plans merging a re-crawled record into its stored booking, the same for every backend:
+/// changed profile fields are replaced, a corrected name keeps the old spelling as an alias,
+/// aliases are only added, and charges and bonds that differ are deleted and inserted. Duplicate
+/// charges and bonds are matched one for one. The stored image and embedding are left alone, and
+/// so are the sys_id and booking number when the record lacks them.
+pub fn plan_merge<Id: Clone>(stored: &StoredBooking<Id>, record: &Record) -> MergePlan<Id> {
+    let fields: Vec<FieldChange> = profile_columns(&stored.profile)
+        .into_iter()
+        .zip(profile_columns(&record.profile))
+        .filter(|((_, old), (_, new))| old != new)
+        .filter(|((field, _), (_, new))| {
+            !IDENTITY_FIELDS.contains(field) || new.as_deref().is_some_and(|new| !new.is_empty())
+        })
+        .map(|((field, old), (_, new))| FieldChange { field, old, new })
+        .collect();
+
+    // Aliases missing from a new crawl are kept
+    let renamed_from = fields
+        .iter()
+        .any(|change| change.field == "first_name" || change.field == "last_name")
+        .then(|| stored.profile.get_full_name());
+    let takes_booking_number = fields
+        .iter()
+        .find(|change| change.field == "booking_number")
+        .and_then(|change| change.new.clone());
+    let stored_aliases = stored.profile.aliases.as_deref().unwrap_or_default();
+    let aliases_added = renamed_from
+        .iter()
+        .chain(record.profile.aliases.iter().flatten())
+        .filter(|alias| !alias.is_empty() && !stored_aliases.contains(alias))
+        .unique()
+        .cloned()
+        .collect();
+
+    let stored_charges: Vec<ChargeVersion> = stored
+        .charges
+        .iter()
+        .map(|(_, charge)| charge.clone())
+        .collect();
+    let new_charges: Vec<ChargeVersion> = record
+        .charges
+        .charges
+        .iter()
+        .map(ChargeVersion::from)
+        .collect();
+    let (removed, added) = diff_rows(&stored_charges, &new_charges);
+    let charges_to_delete = pick(&stored.charges, &removed);
+    let charges_to_insert = pick(&record.charges.charges, &added);
+
+    let stored_bonds: Vec<BondVersion> =
+        stored.bonds.iter().map(|(_, bond)| bond.clone()).collect();
+    let new_bonds: Vec<BondVersion> = record.bond.bonds.iter().map(BondVersion::from).collect();
+    let (removed, added) = diff_rows(&stored_bonds, &new_bonds);
+    let bonds_to_delete = pick(&stored.bonds, &removed);
+    let bonds_to_insert = pick(&record.bond.bonds, &added);
+
+    let changes = RecordChanges {
+        inmate_id: stored.inmate_id,
+        matched_by: Some(stored.matched_by),
+        fields,
+        aliases_added,
+        charges_added: strings(charges_to_insert.iter().map(ChargeVersion::from)),
+        charges_removed: strings(charges_to_delete.iter().map(|(_, charge)| charge)),
+        bonds_added: strings(bonds_to_insert.iter().map(BondVersion::from)),
+        bonds_removed: strings(bonds_to_delete.iter().map(|(_, bond)| bond)),
+        ..Default::default()
+    };
+    MergePlan {
+        renamed_from,
+        takes_booking_number,
+        charges_to_delete,
+        charges_to_insert,
+        bonds_to_delete,
+        bonds_to_insert,
+        changes,
+    }
+}
+
+fn pick<T: Clone>(rows: &[T], indices: &[usize]) -> Vec<T> {
+    indices.iter().map(|&idx| rows[idx].clone()).collect()
+}
+
+fn versions<Id, T: Clone>(rows: &[(Id, T)]) -> Vec<T> {
+    rows.iter().map(|(_, version)| version.clone()).collect()
+}
+
+fn strings<T: ToString>(versions: impl Iterator<Item = T>) -> Vec<String> {
+    versions.map(|version| version.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inmate::{BondInformation, ChargeGrade, ChargeInformation};
+    use crate::utils::Money;
+
+    fn charge(description: &str, grade: ChargeGrade) -> Charge {
+        Charge {
+            description: description.to_string(),
+            grade,
+            offense_date: "10/01/2024".to_string(),
+        }
+    }
+
+    fn bond(cents: u64) -> Bond {
+        Bond {
+            bond_type: "Cash Only".to_string(),
+            bond_amount: Money::from_cents(cents),
+            amount_unparseable: false,
+            amount_not_set: false,
+        }
+    }
+
+    fn record(first_name: &str, charges: Vec<Charge>, bonds: Vec<Bond>) -> Record {
+        Record {
+            url: String::new(),
+            profile: InmateProfile {
+                first_name: first_name.to_string(),
+                last_name: "DOE".to_string(),
+                dob: "01/02/1990".to_string(),
+                booking_date_iso8601: "10/01/2024 08:15".to_string(),
+                scil_sys_id: Some("?sysid=1".to_string()),
+                aliases: Some(vec!["JOHNNY DOE".to_string()]),
+                ..Default::default()
+            },
+            bond: BondInformation { bonds },
+            charges: ChargeInformation { charges },
+        }
+    }
+
+    /// The booking as a backend would load it, with row ids counting up from 10
+    fn stored(record: &Record) -> StoredBooking<i64> {
+        StoredBooking {
+            inmate_id: 7,
+            matched_by: IdentityMatch::SysId,
+            profile: record.profile.clone(),
+            charges: (10..)
+                .zip(record.charges.charges.iter().map(ChargeVersion::from))
+                .collect(),
+            bonds: (10..)
+                .zip(record.bond.bonds.iter().map(BondVersion::from))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_plan_merge_unchanged() {
+        let theft = charge("THEFT", ChargeGrade::Misdemeanor);
+        let existing = record("JOHN", vec![theft.clone(), theft.clone()], vec![bond(500)]);
+        let plan = plan_merge(&stored(&existing), &existing);
+        assert!(plan.changes.is_unchanged());
+        assert_eq!(plan.changes.inmate_id, 7);
+        assert_eq!(plan.changes.matched_by, Some(IdentityMatch::SysId));
+        assert!(plan.charges_to_delete.is_empty() && plan.charges_to_insert.is_empty());
+        assert!(plan.bonds_to_delete.is_empty() && plan.bonds_to_insert.is_empty());
+        assert!(!plan.relinks_person());
+    }
+
+    #[test]
+    fn test_plan_merge_replaces_changed_rows() {
+        let theft = charge("THEFT", ChargeGrade::Misdemeanor);
+        let existing = record(
+            "JOHN",
+            vec![
+                theft.clone(),
+                theft.clone(),
+                charge("OWI", ChargeGrade::Misdemeanor),
+            ],
+            vec![bond(500), bond(100)],
+        );
+        let mut recrawled = record(
+            "JOHN",
+            vec![theft.clone(), charge("OWI", ChargeGrade::Felony)],
+            vec![bond(500), bond(250)],
+        );
+        recrawled.profile.weight = Some("180".to_string());
+
+        let plan = plan_merge(&stored(&existing), &recrawled);
+        // One of the duplicate thefts is gone, and the regraded charge is replaced
+        let deleted: Vec<i64> = plan.charges_to_delete.iter().map(|(id, _)| *id).collect();
+        assert_eq!(deleted, vec![11, 12]);
+        assert_eq!(
+            plan.charges_added(),
+            vec![ChargeVersion::from(&charge("OWI", ChargeGrade::Felony))]
+        );
+        assert_eq!(plan.bonds_to_delete[0].0, 11);
+        assert_eq!(plan.bonds_removed(), vec![BondVersion::from(&bond(100))]);
+        assert_eq!(plan.bonds_added(), vec![BondVersion::from(&bond(250))]);
+        assert_eq!(plan.changes.charges_removed.len(), 2);
+        assert_eq!(plan.changes.bonds_added, vec!["Cash Only $2.50"]);
+
+        assert_eq!(
+            plan.changes.fields,
+            vec![FieldChange {
+                field: "weight",
+                old: None,
+                new: Some("180".to_string()),
+            }]
+        );
+        assert!(plan.changes.aliases_added.is_empty());
+        assert!(!plan.relinks_person());
+    }
+
+    #[test]
+    fn test_plan_merge_keeps_old_name_as_alias() {
+        let existing = record("JON", Vec::new(), Vec::new());
+        let mut recrawled = record("JOHN", Vec::new(), Vec::new());
+        recrawled.profile.aliases = Some(vec![
+            "JOHNNY DOE".to_string(),
+            String::new(),
+            "J DOE".to_string(),
+            "J DOE".to_string(),
+        ]);
+
+        let plan = plan_merge(&stored(&existing), &recrawled);
+        assert_eq!(plan.renamed_from.as_deref(), Some("JON DOE"));
+        assert_eq!(plan.changes.fields.len(), 1);
+        assert_eq!(plan.changes.fields[0].field, "first_name");
+        // Stored aliases aren't linked again, and empty or repeated ones are skipped
+        assert_eq!(plan.changes.aliases_added, vec!["JON DOE", "J DOE"]);
+        assert!(plan.relinks_person());
+    }
+
+    #[test]
+    fn test_plan_merge_keeps_identity_keys() {
+        let mut existing = record("JOHN", Vec::new(), Vec::new());
+        existing.profile.booking_number = Some("24-1".to_string());
+        for (sys_id, booking_number) in [(None, None), (Some(""), Some(""))] {
+            let mut recrawled = existing.clone();
+            recrawled.profile.scil_sys_id = sys_id.map(str::to_string);
+            recrawled.profile.booking_number = booking_number.map(str::to_string);
+            assert!(plan_merge(&stored(&existing), &recrawled)
+                .changes
+                .is_unchanged());
+        }
+
+        // A changed key is still taken
+        let mut recrawled = existing.clone();
+        recrawled.profile.booking_number = Some("24-2".to_string());
+        let plan = plan_merge(&stored(&existing), &recrawled);
+        assert_eq!(plan.changes.fields.len(), 1);
+        assert_eq!(plan.changes.fields[0].field, "booking_number");
+        assert_eq!(plan.takes_booking_number.as_deref(), Some("24-2"));
+        assert!(plan_merge(&stored(&existing), &existing)
+            .takes_booking_number
+            .is_none());
+    }
+
+    #[test]
+    fn test_plan_merge_relinks_person_on_id_change() {
+        let existing = record("JOHN", Vec::new(), Vec::new());
+        let mut recrawled = existing.clone();
+        recrawled.profile.perm_id = Some("P-1".to_string());
+        assert!(plan_merge(&stored(&existing), &recrawled).relinks_person());
+
+        let mut recrawled = existing.clone();
+        recrawled.profile.height = Some("6' 0\"".to_string());
+        assert!(!plan_merge(&stored(&existing), &recrawled).relinks_person());
+    }
+}
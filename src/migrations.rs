@@ -0,0 +1,303 @@
+use log::{debug, info};
+use sqlx::postgres::PgPool;
+use sqlx::{Connection, Executor, Row};
+
+use crate::Error;
+
+/// A numbered schema change. Migrations run in version order, each exactly once, and are
+/// recorded in the `schema_version` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+macro_rules! migration {
+    ($version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!("../queries/", $name, ".sql")),
+        }
+    };
+}
+
+/// Every migration this binary knows about, oldest first. The SQL lives in `queries/`. Never edit
+/// a migration that has shipped, add a new one instead.
+///
+/// Migrations up to 9 are idempotent, so databases created before `schema_version` existed adopt
+/// it by running them.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "001_create_inmate"),
+    migration!(2, "002_create_alias"),
+    migration!(3, "003_create_inmate_alias"),
+    migration!(4, "004_create_bond"),
+    migration!(5, "005_create_charge"),
+    migration!(6, "006_create_img"),
+    migration!(7, "007_create_image"),
+    migration!(8, "008_create_img_derivative"),
+    migration!(9, "009_create_img_upload_task"),
+    migration!(10, "010_widen_bond_amount"),
+    migration!(11, "011_add_bond_amount_unparseable"),
+    migration!(12, "012_create_booking_history"),
+    migration!(13, "013_add_booking_identity"),
+    migration!(14, "014_create_person"),
+    migration!(15, "015_add_alias_key"),
+    migration!(16, "016_add_bond_amount_not_set"),
+    migration!(17, "017_make_image_img_nullable"),
+];
+
+/// Serializes concurrent migrations, e.g. two crawlers starting at once
+const MIGRATION_LOCK_ID: i64 = 0x7363_6a61_696c;
+
+/// Returns the version this binary migrates databases to.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Returns the database's schema version, or 0 if it has never been migrated.
+pub async fn schema_version(pool: &PgPool) -> Result<i64, Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT COALESCE(MAX(version), 0) AS version
+        FROM schema_version
+        "#,
+    )
+    .fetch_one(pool)
+    .await;
+
+    match row {
+        Ok(row) => Ok(row.try_get("version")?),
+        // undefined_table: the database predates schema_version
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("42P01") => Ok(0),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Returns the migrations still to run on a database at `current` version.
+///
+/// # Errors
+/// InternalError: If the database is newer than this binary understands
+fn pending_migrations(current: i64) -> Result<&'static [Migration], Error> {
+    if current > latest_version() {
+        return Err(Error::InternalError(format!(
+            "Database schema version {} is newer than this binary understands ({}). Upgrade the binary before running it against this database.",
+            current,
+            latest_version()
+        )));
+    }
+
+    Ok(&MIGRATIONS[MIGRATIONS.partition_point(|migration| migration.version <= current)..])
+}
+
+/// Brings the database up to [`latest_version`], running each pending migration in its own
+/// transaction. Returns the versions applied.
+///
+/// # Errors
+/// InternalError: If the database is newer than this binary understands
+/// PostgresError: If a migration fails. Earlier migrations stay applied.
+pub async fn migrate(pool: &PgPool) -> Result<Vec<i64>, Error> {
+    let mut conn = pool.acquire().await?;
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(MIGRATION_LOCK_ID)
+        .execute(&mut *conn)
+        .await?;
+
+    let res = migrate_locked(&mut conn).await;
+
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(MIGRATION_LOCK_ID)
+        .execute(&mut *conn)
+        .await?;
+    res
+}
+
+async fn migrate_locked(conn: &mut sqlx::PgConnection) -> Result<Vec<i64>, Error> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_version (
+          version BIGINT PRIMARY KEY,
+          name TEXT NOT NULL,
+          applied_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+        )
+        "#,
+    )
+    .execute(&mut *conn)
+    .await?;
+    let current: i64 =
+        sqlx::query("SELECT COALESCE(MAX(version), 0) AS version FROM schema_version")
+            .fetch_one(&mut *conn)
+            .await?
+            .try_get("version")?;
+
+    let pending = pending_migrations(current)?;
+    if pending.is_empty() {
+        debug!("Database schema is up to date at version {}", current);
+        return Ok(Vec::new());
+    }
+
+    info!(
+        "Migrating database schema from version {} to {}...",
+        current,
+        latest_version()
+    );
+    let mut applied = Vec::new();
+    for migration in pending {
+        info!(
+            "Running migration {}: {}",
+            migration.version, migration.name
+        );
+        let mut transaction = conn.begin().await?;
+        // Through Executor, since RawSql::execute's future can't be proven Send
+        transaction
+            .execute(sqlx::raw_sql(migration.sql))
+            .await
+            .map_err(|e| {
+                Error::PostgresError(format!(
+                    "Migration {} ({}) failed: {}",
+                    migration.version, migration.name, e
+                ))
+            })?;
+        sqlx::query("INSERT INTO schema_version (version, name) VALUES ($1, $2)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        applied.push(migration.version);
+    }
+
+    info!("Database schema migrated to version {}", latest_version());
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for (idx, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, idx as i64 + 1);
+            assert!(migration
+                .name
+                .starts_with(&format!("{:03}_", migration.version)));
+        }
+    }
+
+    #[test]
+    fn test_every_query_file_is_a_migration() {
+        let queries_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("queries");
+        let mut files: Vec<String> = std::fs::read_dir(queries_dir)
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                name.strip_suffix(".sql").map(String::from)
+            })
+            .collect();
+        files.sort();
+
+        let names: Vec<String> = MIGRATIONS.iter().map(|m| m.name.to_string()).collect();
+        assert_eq!(files, names);
+    }
+
+    #[test]
+    fn test_pending_migrations() {
+        assert_eq!(pending_migrations(0).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(pending_migrations(1).unwrap()[0].version, 2);
+        assert!(pending_migrations(latest_version()).unwrap().is_empty());
+        assert!(pending_migrations(latest_version() + 1).is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, run with `TEST_DATABASE_URL=... cargo test -- --ignored`"]
+    async fn test_booking_identity_backfill() {
+        let pool = crate::test_db::test_pool().await;
+        let mut transaction = pool.begin().await.unwrap();
+        // Temporary tables shadow the real ones, so 013 runs on these rows alone
+        transaction
+            .execute(sqlx::raw_sql(
+                r#"
+                CREATE TEMP TABLE inmate (
+                    id INTEGER PRIMARY KEY,
+                    first_name TEXT NOT NULL,
+                    middle_name TEXT,
+                    last_name TEXT NOT NULL,
+                    affix TEXT,
+                    dob DATE,
+                    booking_date TIMESTAMP WITH TIME ZONE,
+                    booking_number TEXT,
+                    scil_sysid TEXT
+                );
+                CREATE TEMP TABLE alias (id SERIAL PRIMARY KEY, alias TEXT UNIQUE NOT NULL);
+                CREATE TEMP TABLE inmate_alias (
+                    inmate_id INTEGER,
+                    alias_id INTEGER,
+                    PRIMARY KEY (inmate_id, alias_id)
+                );
+                CREATE TEMP TABLE inmate_history (id SERIAL PRIMARY KEY, inmate_id INTEGER);
+
+                INSERT INTO inmate (id, first_name, last_name, booking_number, scil_sysid) VALUES
+                    (1, 'JOSÉ', 'O''DOE', '24-1', '?sysid=1'),
+                    (2, 'JOHN', 'DOE', '24-1', '?sysid=2'),
+                    (3, 'JANE', 'ROE', '24-3', '?sysid=3'),
+                    (4, 'JANE', 'ROE', '24-4', '?sysid=3'),
+                    (5, 'BOB', 'POE', '', NULL),
+                    (6, 'BOB', 'POE', '', NULL);
+                "#,
+            ))
+            .await
+            .unwrap();
+        let migration = MIGRATIONS.iter().find(|m| m.version == 13).unwrap();
+        transaction
+            .execute(sqlx::raw_sql(migration.sql))
+            .await
+            .unwrap();
+
+        let rows = sqlx::query("SELECT id, superseded_by FROM inmate ORDER BY id")
+            .fetch_all(&mut *transaction)
+            .await
+            .unwrap();
+        let superseded_by: Vec<(i32, Option<i32>)> = rows
+            .iter()
+            .map(|row| (row.get("id"), row.get("superseded_by")))
+            .collect();
+        // Duplicates by booking number or sys_id point to the newest row; empty ids don't count
+        assert_eq!(
+            superseded_by,
+            vec![
+                (1, Some(2)),
+                (2, None),
+                (3, Some(4)),
+                (4, None),
+                (5, None),
+                (6, None)
+            ]
+        );
+
+        // The old spelling of a renamed duplicate is kept as an alias of the survivor, keyed the
+        // same as alias::alias_key
+        let aliases: Vec<(i32, String, String)> = sqlx::query(
+            r#"
+            SELECT inmate_alias.inmate_id, alias.alias, alias.alias_key
+            FROM inmate_alias
+            JOIN alias ON alias.id = inmate_alias.alias_id
+            ORDER BY inmate_alias.inmate_id
+            "#,
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("inmate_id"), row.get("alias"), row.get("alias_key")))
+        .collect();
+        assert_eq!(
+            aliases,
+            vec![(2, "JOSÉ O'DOE".to_string(), "JOSE O DOE".to_string())]
+        );
+        assert_eq!(crate::alias::alias_key(&aliases[0].1), aliases[0].2);
+
+        transaction.rollback().await.unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, ImageFormat};
+use log::{debug, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::io::Cursor;
+
+use crate::Error;
+
+/// Max hamming distance between two average hashes for images to be considered the same picture.
+const PLACEHOLDER_AHASH_MAX_DISTANCE: u32 = 5;
+const DEFAULT_DERIVATIVES: &str = "thumb:128,medium:480";
+const DERIVATIVE_JPEG_QUALITY: u8 = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MugshotFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl MugshotFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            MugshotFormat::Jpeg => "image/jpeg",
+            MugshotFormat::Png => "image/png",
+            MugshotFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MugshotFormat::Jpeg => "jpg",
+            MugshotFormat::Png => "png",
+            MugshotFormat::Webp => "webp",
+        }
+    }
+}
+
+impl std::fmt::Display for MugshotFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MugshotFormat::Jpeg => write!(f, "jpeg"),
+            MugshotFormat::Png => write!(f, "png"),
+            MugshotFormat::Webp => write!(f, "webp"),
+        }
+    }
+}
+
+impl std::str::FromStr for MugshotFormat {
+    type Err = Error;
+
+    /// Parses the format's display name, as stored in the database.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpeg" => Ok(MugshotFormat::Jpeg),
+            "png" => Ok(MugshotFormat::Png),
+            "webp" => Ok(MugshotFormat::Webp),
+            _ => Err(Error::ImageError(format!("Unknown mugshot format: {}", s))),
+        }
+    }
+}
+
+impl From<MugshotFormat> for ImageFormat {
+    fn from(format: MugshotFormat) -> Self {
+        match format {
+            MugshotFormat::Jpeg => ImageFormat::Jpeg,
+            MugshotFormat::Png => ImageFormat::Png,
+            MugshotFormat::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// What we learned about a mugshot by decoding it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MugshotMetadata {
+    pub format: MugshotFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Lowercase hex SHA-256 of the raw image bytes
+    pub sha256: String,
+    /// 64 bit average hash (aHash) of the image, used to match visually identical images. None
+    /// when the metadata was loaded from the database without the image bytes.
+    pub ahash: Option<u64>,
+}
+
+/// Decodes the image bytes, verifying they're a real JPEG or PNG, and returns their metadata.
+///
+/// # Errors
+/// ImageError: If the bytes are empty, aren't a JPEG or PNG, or fail to decode
+pub fn validate_mugshot(img_blob: &[u8]) -> Result<MugshotMetadata, Error> {
+    if img_blob.is_empty() {
+        return Err(Error::ImageError("Image is empty".to_string()));
+    }
+
+    let format = match image::guess_format(img_blob) {
+        Ok(ImageFormat::Jpeg) => MugshotFormat::Jpeg,
+        Ok(ImageFormat::Png) => MugshotFormat::Png,
+        Ok(other) => {
+            return Err(Error::ImageError(format!(
+                "Unsupported image format: {:?}",
+                other
+            )))
+        }
+        Err(e) => return Err(Error::ImageError(format!("Unknown image format: {}", e))),
+    };
+
+    let img = image::load(Cursor::new(img_blob), ImageFormat::from(format))
+        .map_err(|e| Error::ImageError(format!("Failed to decode {} image: {}", format, e)))?;
+
+    let metadata = MugshotMetadata {
+        format,
+        width: img.width(),
+        height: img.height(),
+        sha256: format!("{:x}", Sha256::digest(img_blob)),
+        ahash: Some(average_hash(&img)),
+    };
+    debug!("Validated mugshot: {:#?}", metadata);
+
+    Ok(metadata)
+}
+
+/// Returns the storage key of an image, given the SHA-256 of its bytes. Keys are content
+/// addressed, so the same image is only stored once no matter how many bookings use it.
+pub fn content_key(sha256: &str) -> String {
+    format!("mugshots/{}", sha256)
+}
+
+/// Returns true if the storage key matches the SHA-256 of the image bytes.
+pub fn verify_content_key(key: &str, img_blob: &[u8]) -> bool {
+    key == content_key(&format!("{:x}", Sha256::digest(img_blob)))
+}
+
+/// Computes the average hash of an image: shrink to 8x8 grayscale, then set one bit per pixel
+/// that is brighter than the mean.
+fn average_hash(img: &image::DynamicImage) -> u64 {
+    let small = img.resize_exact(8, 8, FilterType::Triangle).to_luma8();
+    let mean = small.pixels().map(|p| p.0[0] as u32).sum::<u32>() / 64;
+
+    small
+        .pixels()
+        .enumerate()
+        .filter(|(_, p)| p.0[0] as u32 > mean)
+        .fold(0u64, |hash, (idx, _)| hash | (1 << idx))
+}
+
+/// Returns true if the mugshot matches one of the given placeholder SHA-256s exactly, or is
+/// visually identical to one of the given placeholder average hashes.
+pub fn is_placeholder(
+    metadata: &MugshotMetadata,
+    placeholder_sha256s: &[String],
+    placeholder_ahashes: &[u64],
+) -> bool {
+    placeholder_sha256s
+        .iter()
+        .any(|sha| sha.eq_ignore_ascii_case(&metadata.sha256))
+        || metadata.ahash.is_some_and(|hash| {
+            placeholder_ahashes
+                .iter()
+                .any(|ahash| (ahash ^ hash).count_ones() <= PLACEHOLDER_AHASH_MAX_DISTANCE)
+        })
+}
+
+/// Returns true if the mugshot is the county's "no photo" placeholder, according to the
+/// comma separated `PLACEHOLDER_IMG_SHA256` and `PLACEHOLDER_IMG_AHASH` (hex) env vars.
+pub fn is_env_placeholder(metadata: &MugshotMetadata) -> bool {
+    let placeholder_sha256s = env::var("PLACEHOLDER_IMG_SHA256")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<String>>();
+    let placeholder_ahashes = env::var("PLACEHOLDER_IMG_AHASH")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| match u64::from_str_radix(s, 16) {
+            Ok(ahash) => Some(ahash),
+            Err(_) => {
+                warn!("Ignoring invalid PLACEHOLDER_IMG_AHASH entry: {s}");
+                None
+            }
+        })
+        .collect::<Vec<u64>>();
+
+    let placeholder = is_placeholder(metadata, &placeholder_sha256s, &placeholder_ahashes);
+    if placeholder {
+        info!(
+            "Mugshot {} matches a known placeholder image",
+            metadata.sha256
+        );
+    }
+    placeholder
+}
+
+/// A resized copy of a mugshot to generate alongside the original.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivativeSpec {
+    /// Name used in the derivative's key and database row, e.g. "thumb"
+    pub name: String,
+    /// Longest side of the derivative, in pixels. Images are never upscaled.
+    pub max_dimension: u32,
+    pub format: MugshotFormat,
+}
+
+impl DerivativeSpec {
+    /// Returns the key of this derivative, stored next to the original image's key.
+    pub fn key(&self, original_key: &str) -> String {
+        format!("{}-{}.{}", original_key, self.name, self.format.extension())
+    }
+}
+
+/// An encoded derivative, ready for upload.
+#[derive(Debug)]
+pub struct Derivative {
+    pub spec: DerivativeSpec,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Returns the derivatives to generate, according to the `MUGSHOT_DERIVATIVES` env var (comma
+/// separated `name:max_px` pairs, default "thumb:128,medium:480"). Derivatives are JPEGs; when
+/// `MUGSHOT_DERIVATIVES_WEBP` is set, a WebP copy of each one is generated too.
+pub fn derivative_specs_from_env() -> Vec<DerivativeSpec> {
+    let derivatives = env::var("MUGSHOT_DERIVATIVES").unwrap_or(DEFAULT_DERIVATIVES.to_string());
+    let mut specs = Vec::new();
+
+    for entry in derivatives
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+    {
+        let parsed = entry
+            .split_once(':')
+            .and_then(|(name, px)| Some((name.trim(), px.trim().parse::<u32>().ok()?)));
+        match parsed {
+            Some((name, max_dimension)) if !name.is_empty() && max_dimension > 0 => {
+                specs.push(DerivativeSpec {
+                    name: name.to_string(),
+                    max_dimension,
+                    format: MugshotFormat::Jpeg,
+                });
+            }
+            _ => warn!("Ignoring invalid MUGSHOT_DERIVATIVES entry: {entry}"),
+        }
+    }
+
+    if env::var("MUGSHOT_DERIVATIVES_WEBP").is_ok() {
+        let webp_specs = specs
+            .iter()
+            .map(|spec| DerivativeSpec {
+                format: MugshotFormat::Webp,
+                ..spec.clone()
+            })
+            .collect::<Vec<DerivativeSpec>>();
+        specs.extend(webp_specs);
+    }
+
+    specs
+}
+
+/// Decodes the original image once and encodes a resized copy for each spec.
+///
+/// # Errors
+/// ImageError: If the original fails to decode, or a derivative fails to encode
+pub fn generate_derivatives(
+    img_blob: &[u8],
+    specs: &[DerivativeSpec],
+) -> Result<Vec<Derivative>, Error> {
+    if specs.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let img = image::load_from_memory(img_blob)
+        .map_err(|e| Error::ImageError(format!("Failed to decode image: {}", e)))?;
+    let mut derivatives = Vec::new();
+
+    for spec in specs {
+        let resized = if img.width().max(img.height()) > spec.max_dimension {
+            img.resize(spec.max_dimension, spec.max_dimension, FilterType::Lanczos3)
+        } else {
+            img.clone()
+        };
+
+        let mut bytes = Cursor::new(Vec::new());
+        let encoded = match spec.format {
+            // JPEG has no alpha channel, and we want a smaller quality than the default
+            MugshotFormat::Jpeg => {
+                resized
+                    .to_rgb8()
+                    .write_with_encoder(JpegEncoder::new_with_quality(
+                        &mut bytes,
+                        DERIVATIVE_JPEG_QUALITY,
+                    ))
+            }
+            MugshotFormat::Png => resized.write_to(&mut bytes, ImageFormat::Png),
+            // The WebP encoder only supports 8 bit RGB(A)
+            MugshotFormat::Webp => resized.to_rgba8().write_to(&mut bytes, ImageFormat::WebP),
+        };
+        encoded.map_err(|e| {
+            Error::ImageError(format!("Failed to encode {} derivative: {}", spec.name, e))
+        })?;
+
+        debug!(
+            "Generated {} derivative: {}x{} {}",
+            spec.name,
+            resized.width(),
+            resized.height(),
+            spec.format
+        );
+        derivatives.push(Derivative {
+            spec: spec.clone(),
+            width: resized.width(),
+            height: resized.height(),
+            bytes: bytes.into_inner(),
+        });
+    }
+
+    Ok(derivatives)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::encode;
+
+    #[test]
+    fn test_validate_mugshot_png() {
+        let metadata = validate_mugshot(&encode(40, 60, ImageFormat::Png)).unwrap();
+        assert_eq!(metadata.format, MugshotFormat::Png);
+        assert_eq!((metadata.width, metadata.height), (40, 60));
+        assert_eq!(metadata.sha256.len(), 64);
+    }
+
+    #[test]
+    fn test_validate_mugshot_jpeg() {
+        let metadata = validate_mugshot(&encode(32, 48, ImageFormat::Jpeg)).unwrap();
+        assert_eq!(metadata.format, MugshotFormat::Jpeg);
+        assert_eq!((metadata.width, metadata.height), (32, 48));
+    }
+
+    #[test]
+    fn test_validate_mugshot_rejects_garbage() {
+        assert!(validate_mugshot(&[]).is_err());
+        assert!(validate_mugshot(b"<html>Not found</html>").is_err());
+        // Valid PNG signature, truncated body
+        let mut truncated = encode(40, 60, ImageFormat::Png);
+        truncated.truncate(20);
+        assert!(validate_mugshot(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_generate_derivatives() {
+        let specs = vec![
+            DerivativeSpec {
+                name: "thumb".to_string(),
+                max_dimension: 30,
+                format: MugshotFormat::Jpeg,
+            },
+            DerivativeSpec {
+                name: "medium".to_string(),
+                max_dimension: 480,
+                format: MugshotFormat::Webp,
+            },
+        ];
+        let derivatives = generate_derivatives(&encode(40, 60, ImageFormat::Png), &specs).unwrap();
+        assert_eq!(derivatives.len(), 2);
+
+        // Aspect ratio is kept when shrinking
+        assert_eq!((derivatives[0].width, derivatives[0].height), (20, 30));
+        assert_eq!(
+            image::guess_format(&derivatives[0].bytes).unwrap(),
+            ImageFormat::Jpeg
+        );
+
+        // Smaller images aren't upscaled
+        assert_eq!((derivatives[1].width, derivatives[1].height), (40, 60));
+        assert_eq!(
+            image::guess_format(&derivatives[1].bytes).unwrap(),
+            ImageFormat::WebP
+        );
+    }
+
+    #[test]
+    fn test_derivative_key() {
+        let spec = DerivativeSpec {
+            name: "thumb".to_string(),
+            max_dimension: 128,
+            format: MugshotFormat::Webp,
+        };
+        assert_eq!(spec.key("mugshots/abc123"), "mugshots/abc123-thumb.webp");
+    }
+
+    #[test]
+    fn test_content_key() {
+        let img_blob = encode(40, 60, ImageFormat::Png);
+        let metadata = validate_mugshot(&img_blob).unwrap();
+        let key = content_key(&metadata.sha256);
+        assert_eq!(key, format!("mugshots/{}", metadata.sha256));
+        assert!(verify_content_key(&key, &img_blob));
+        assert!(!verify_content_key(&key, &encode(40, 61, ImageFormat::Png)));
+    }
+
+    #[test]
+    fn test_is_placeholder() {
+        let metadata = validate_mugshot(&encode(40, 60, ImageFormat::Png)).unwrap();
+        assert!(!is_placeholder(&metadata, &[], &[]));
+        assert!(is_placeholder(
+            &metadata,
+            &[metadata.sha256.to_uppercase()],
+            &[]
+        ));
+
+        // Same picture re-encoded has different bytes, but the same average hash
+        let jpeg_metadata = validate_mugshot(&encode(40, 60, ImageFormat::Jpeg)).unwrap();
+        assert_ne!(metadata.sha256, jpeg_metadata.sha256);
+        assert!(is_placeholder(&jpeg_metadata, &[], &[metadata.ahash.unwrap()]));
+        assert!(!is_placeholder(&jpeg_metadata, &[], &[!metadata.ahash.unwrap()]));
+    }
+}
@@ -0,0 +1,585 @@
+use async_trait::async_trait;
+use aws_sdk_s3::{presigning::PresigningConfig, primitives::ByteStream, Client};
+use log::{debug, error, info, trace};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    s3_utils::{self, S3Config},
+    Error,
+};
+
+pub const DEFAULT_LOCAL_OBJECT_STORE_PATH: &str = "object_store";
+
+/// How an object is stored by [`ObjectStore::put_with_options`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PutOptions {
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+    /// User metadata stored alongside the object. Values should be ASCII for S3.
+    pub metadata: BTreeMap<String, String>,
+    /// Leave an existing object untouched instead of replacing it
+    pub if_not_exists: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PutOutcome {
+    Stored,
+    /// `if_not_exists` was set and an object already existed under the key
+    AlreadyExists,
+}
+
+/// Storage for image objects, addressed by '/' separated keys (e.g. "mugshots/<sha256>").
+#[async_trait]
+pub trait ObjectStore: Send + Sync + std::fmt::Debug {
+    /// Stores the bytes under the key according to the options.
+    async fn put_with_options(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        options: &PutOptions,
+    ) -> Result<PutOutcome, Error>;
+
+    /// Stores the bytes under the key, replacing any existing object.
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error> {
+        self.put_with_options(key, bytes, &PutOptions::default())
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns the object's bytes, or None if there is no object under the key.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Deletes the object under the key. Deleting a missing object is not an error.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// Returns every key starting with the prefix, in lexicographic order.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error>;
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.get(key).await?.is_some())
+    }
+
+    /// Returns a URL that grants read access to the object until `expires_in` has passed, or None
+    /// if the store can't hand out URLs. The object isn't checked for existence.
+    async fn presigned_get_url(
+        &self,
+        _key: &str,
+        _expires_in: Duration,
+    ) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+}
+
+/// Builds the object store selected by the `OBJECT_STORE` env var:
+/// - "s3": the bucket described by [`S3Config::from_env`]
+/// - "local": a directory at `OBJECT_STORE_PATH` (default "object_store")
+/// - "memory": an in-memory map, lost on exit
+///
+/// Returns None when `OBJECT_STORE` is unset, so callers can fall back to their own defaults.
+///
+/// # Errors
+/// ArgumentError: If `OBJECT_STORE` names an unknown backend
+pub async fn object_store_from_env() -> Result<Option<Box<dyn ObjectStore>>, Error> {
+    let backend = match env::var("OBJECT_STORE") {
+        Ok(backend) => backend,
+        Err(_) => return Ok(None),
+    };
+
+    let store: Box<dyn ObjectStore> = match backend.to_lowercase().as_str() {
+        "s3" => Box::new(S3ObjectStore::from_env().await?),
+        "local" => Box::new(LocalObjectStore::from_env()),
+        "memory" => Box::new(InMemoryObjectStore::new()),
+        _ => {
+            error!(
+                "Unknown OBJECT_STORE: {}. Expected one of: s3, local, memory",
+                backend
+            );
+            return Err(Error::ArgumentError);
+        }
+    };
+    info!("Using object store: {:?}", store);
+
+    Ok(Some(store))
+}
+
+/// Objects stored in an S3 bucket.
+#[derive(Debug)]
+pub struct S3ObjectStore {
+    client: Client,
+    bucket: String,
+}
+
+impl S3ObjectStore {
+    pub fn new(client: Client, bucket: &str) -> S3ObjectStore {
+        S3ObjectStore {
+            client,
+            bucket: bucket.to_string(),
+        }
+    }
+
+    pub async fn from_config(config: &S3Config) -> S3ObjectStore {
+        S3ObjectStore::new(config.build_client().await, &config.bucket)
+    }
+
+    /// Uses the client and bucket described by [`S3Config::from_env`].
+    ///
+    /// # Errors
+    /// ArgumentError: If the S3 env config is invalid
+    pub async fn from_env() -> Result<S3ObjectStore, Error> {
+        Ok(S3ObjectStore::from_config(&S3Config::from_env()?).await)
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn put_with_options(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        options: &PutOptions,
+    ) -> Result<PutOutcome, Error> {
+        let mut put = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(bytes))
+            .set_content_type(options.content_type.clone())
+            .set_cache_control(options.cache_control.clone());
+        for (name, value) in options.metadata.iter() {
+            put = put.metadata(name, value);
+        }
+        if options.if_not_exists {
+            put = put.if_none_match("*");
+        }
+
+        match put.send().await {
+            Ok(put_obj) => {
+                trace!("Put object {} in S3: {:#?}", key, put_obj);
+                Ok(PutOutcome::Stored)
+            }
+            // S3 answers a failed If-None-Match with 412 Precondition Failed
+            Err(e)
+                if options.if_not_exists
+                    && e.raw_response()
+                        .is_some_and(|resp| resp.status().as_u16() == 412) =>
+            {
+                debug!("Object {} already exists in S3, leaving it untouched", key);
+                Ok(PutOutcome::AlreadyExists)
+            }
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match s3_utils::download_object(&self.client, &self.bucket, key).await {
+            Ok(object) => {
+                let bytes = object.body.collect().await.map_err(|e| {
+                    Error::S3Error(format!("Failed to read body of {}: {}", key, e))
+                })?;
+                Ok(Some(bytes.into_bytes().to_vec()))
+            }
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        Ok(s3_utils::list_objects(&self.client, &self.bucket, prefix)
+            .await?
+            .into_iter()
+            .map(|object| object.key)
+            .collect())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    async fn presigned_get_url(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<Option<String>, Error> {
+        let presigning_config = PresigningConfig::expires_in(expires_in)
+            .map_err(|e| Error::S3Error(format!("Invalid presigning config: {}", e)))?;
+        let presigned = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .presigned(presigning_config)
+            .await?;
+
+        Ok(Some(presigned.uri().to_string()))
+    }
+}
+
+/// Objects stored as files under a root directory, with keys as relative paths. Content type,
+/// cache control and metadata are not stored.
+#[derive(Debug)]
+pub struct LocalObjectStore {
+    root: PathBuf,
+}
+
+impl LocalObjectStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> LocalObjectStore {
+        LocalObjectStore {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Uses the directory named by the `OBJECT_STORE_PATH` env var, or "object_store".
+    pub fn from_env() -> LocalObjectStore {
+        LocalObjectStore::new(
+            env::var("OBJECT_STORE_PATH").unwrap_or(DEFAULT_LOCAL_OBJECT_STORE_PATH.to_string()),
+        )
+    }
+
+    /// Returns the file path of a key, refusing keys that would escape the root directory.
+    fn path_of(&self, key: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(key);
+        let is_plain_relative = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if key.is_empty() || !is_plain_relative {
+            return Err(Error::InternalError(format!("Invalid object key: {}", key)));
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    fn io_error(key: &str, e: std::io::Error) -> Error {
+        Error::InternalError(format!("Local object store error on {}: {}", key, e))
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalObjectStore {
+    async fn put_with_options(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        options: &PutOptions,
+    ) -> Result<PutOutcome, Error> {
+        let path = self.path_of(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| LocalObjectStore::io_error(key, e))?;
+        }
+
+        // create_new fails if the file exists, so the check and the write are one atomic step
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .create_new(options.if_not_exists)
+            .open(&path)
+            .await;
+        let mut file = match file {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                debug!("Object {} already exists, leaving it untouched", key);
+                return Ok(PutOutcome::AlreadyExists);
+            }
+            Err(e) => return Err(LocalObjectStore::io_error(key, e)),
+        };
+        file.write_all(&bytes)
+            .await
+            .map_err(|e| LocalObjectStore::io_error(key, e))?;
+        debug!("Put object {} at {}", key, path.display());
+
+        Ok(PutOutcome::Stored)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match tokio::fs::read(self.path_of(key)?).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(LocalObjectStore::io_error(key, e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.path_of(key)?).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(LocalObjectStore::io_error(key, e)),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![self.root.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(LocalObjectStore::io_error(prefix, e)),
+            };
+
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .map_err(|e| LocalObjectStore::io_error(prefix, e))?
+            {
+                let path = entry.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                // Keys always use '/' regardless of the platform's separator
+                let key = path
+                    .strip_prefix(&self.root)
+                    .expect("Expect listed path to be under the object store root")
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                if key.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+
+        Ok(keys)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(tokio::fs::try_exists(self.path_of(key)?)
+            .await
+            .map_err(|e| LocalObjectStore::io_error(key, e))?)
+    }
+
+    /// Returns a file:// URL. Local files have no access control, so the URL never expires.
+    async fn presigned_get_url(
+        &self,
+        key: &str,
+        _expires_in: Duration,
+    ) -> Result<Option<String>, Error> {
+        let path = std::path::absolute(self.path_of(key)?)
+            .map_err(|e| LocalObjectStore::io_error(key, e))?;
+
+        Ok(Some(format!("file://{}", path.display())))
+    }
+}
+
+/// Objects stored in memory, for tests and throwaway runs.
+#[derive(Debug, Default)]
+pub struct InMemoryObjectStore {
+    objects: Mutex<BTreeMap<String, (Vec<u8>, PutOptions)>>,
+}
+
+impl InMemoryObjectStore {
+    pub fn new() -> InMemoryObjectStore {
+        InMemoryObjectStore::default()
+    }
+
+    /// Returns the options the object was stored with, so tests can check its metadata.
+    pub fn options(&self, key: &str) -> Option<PutOptions> {
+        self.objects().get(key).map(|(_, options)| options.clone())
+    }
+
+    fn objects(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, (Vec<u8>, PutOptions)>> {
+        self.objects
+            .lock()
+            .expect("Expect in-memory object store lock to not be poisoned")
+    }
+}
+
+#[async_trait]
+impl ObjectStore for InMemoryObjectStore {
+    async fn put_with_options(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        options: &PutOptions,
+    ) -> Result<PutOutcome, Error> {
+        let mut objects = self.objects();
+        if options.if_not_exists && objects.contains_key(key) {
+            return Ok(PutOutcome::AlreadyExists);
+        }
+        objects.insert(key.to_string(), (bytes, options.clone()));
+
+        Ok(PutOutcome::Stored)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.objects().get(key).map(|(bytes, _)| bytes.clone()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.objects().remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .objects()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.objects().contains_key(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn check_object_store(store: &dyn ObjectStore) {
+        assert_eq!(store.get("mugshots/abc").await.unwrap(), None);
+        assert!(!store.exists("mugshots/abc").await.unwrap());
+        assert!(store.list("").await.unwrap().is_empty());
+
+        store.put("mugshots/abc", vec![1, 2, 3]).await.unwrap();
+        store.put("mugshots/abc-thumb.jpg", vec![4]).await.unwrap();
+        store.put("other/abc", vec![5]).await.unwrap();
+        assert_eq!(store.get("mugshots/abc").await.unwrap(), Some(vec![1, 2, 3]));
+        assert!(store.exists("mugshots/abc").await.unwrap());
+        assert_eq!(
+            store.list("mugshots/").await.unwrap(),
+            vec!["mugshots/abc", "mugshots/abc-thumb.jpg"]
+        );
+
+        // put replaces existing objects, unless asked not to
+        store.put("mugshots/abc", vec![9]).await.unwrap();
+        assert_eq!(store.get("mugshots/abc").await.unwrap(), Some(vec![9]));
+        let if_not_exists = PutOptions {
+            if_not_exists: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            store
+                .put_with_options("mugshots/abc", vec![7], &if_not_exists)
+                .await
+                .unwrap(),
+            PutOutcome::AlreadyExists
+        );
+        assert_eq!(store.get("mugshots/abc").await.unwrap(), Some(vec![9]));
+        assert_eq!(
+            store
+                .put_with_options("mugshots/new", vec![7], &if_not_exists)
+                .await
+                .unwrap(),
+            PutOutcome::Stored
+        );
+        store.delete("mugshots/new").await.unwrap();
+
+        store.delete("mugshots/abc").await.unwrap();
+        store.delete("mugshots/abc").await.unwrap();
+        assert!(!store.exists("mugshots/abc").await.unwrap());
+        assert_eq!(
+            store.list("").await.unwrap(),
+            vec!["mugshots/abc-thumb.jpg", "other/abc"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_object_store() {
+        check_object_store(&InMemoryObjectStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_object_store_keeps_options() {
+        let store = InMemoryObjectStore::new();
+        let options = PutOptions {
+            content_type: Some("image/jpeg".to_string()),
+            cache_control: Some("no-cache".to_string()),
+            metadata: BTreeMap::from([("inmate-id".to_string(), "1".to_string())]),
+            if_not_exists: false,
+        };
+        store
+            .put_with_options("mugshots/abc", vec![1], &options)
+            .await
+            .unwrap();
+        assert_eq!(store.options("mugshots/abc"), Some(options));
+    }
+
+    #[tokio::test]
+    async fn test_local_object_store() {
+        let root = tempfile::tempdir().unwrap();
+        check_object_store(&LocalObjectStore::new(root.path())).await;
+    }
+
+    #[tokio::test]
+    async fn test_s3_presigned_get_url() {
+        let config = S3Config {
+            bucket: "scjailio-test".to_string(),
+            region: "us-east-1".to_string(),
+            endpoint_url: Some("http://localhost:9000".to_string()),
+            force_path_style: true,
+            credentials: s3_utils::S3CredentialsSource::Static {
+                access_key_id: "minioadmin".to_string(),
+                secret_access_key: "minioadmin".to_string(),
+            },
+        };
+        let store = S3ObjectStore::from_config(&config).await;
+
+        let url = store
+            .presigned_get_url("mugshots/abc", Duration::from_secs(900))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(url.starts_with("http://localhost:9000/scjailio-test/mugshots/abc?"));
+        assert!(url.contains("X-Amz-Expires=900"));
+        assert!(url.contains("X-Amz-Signature="));
+
+        // SigV4 caps presigned URLs at a week
+        assert!(store
+            .presigned_get_url("mugshots/abc", Duration::from_secs(8 * 24 * 60 * 60))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_object_store_rejects_escaping_keys() {
+        let root = tempfile::tempdir().unwrap();
+        let store = LocalObjectStore::new(root.path().join("store"));
+        assert!(store.put("../escaped", vec![1]).await.is_err());
+        assert!(store.put("/etc/escaped", vec![1]).await.is_err());
+        assert!(store.get("").await.is_err());
+    }
+}
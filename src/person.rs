@@ -0,0 +1,568 @@
+use itertools::Itertools;
+use log::{debug, info, warn};
+use serde::Serialize;
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::Row;
+
+use crate::alias::alias_key;
+use crate::inmate::InmateProfile;
+use crate::records::{load_records_by_person, DbRecord};
+use crate::Error;
+
+/// Why a booking was linked to its person, strongest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PersonMatch {
+    /// Set by hand in `person_override`
+    Override,
+    /// Same permanent id as another booking
+    PermanentId,
+    /// Same first name, last name and dob as another booking
+    NameAndDob,
+    /// Same dob, and a name or alias shared with another booking
+    AliasAndDob,
+    /// Nothing matched, so the booking got a person of its own
+    New,
+}
+
+impl PersonMatch {
+    /// How sure the resolver is that the booking belongs to the person, between 0 and 1.
+    pub fn confidence(self) -> f32 {
+        match self {
+            PersonMatch::Override | PersonMatch::New => 1.0,
+            PersonMatch::PermanentId => 0.98,
+            PersonMatch::NameAndDob => 0.9,
+            PersonMatch::AliasAndDob => 0.6,
+        }
+    }
+
+    /// The name stored in `inmate.person_match`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PersonMatch::Override => "override",
+            PersonMatch::PermanentId => "permanent_id",
+            PersonMatch::NameAndDob => "name_and_dob",
+            PersonMatch::AliasAndDob => "alias_and_dob",
+            PersonMatch::New => "new",
+        }
+    }
+}
+
+impl std::str::FromStr for PersonMatch {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "override" => Ok(PersonMatch::Override),
+            "permanent_id" => Ok(PersonMatch::PermanentId),
+            "name_and_dob" => Ok(PersonMatch::NameAndDob),
+            "alias_and_dob" => Ok(PersonMatch::AliasAndDob),
+            "new" => Ok(PersonMatch::New),
+            _ => Err(Error::InternalError(format!("Unknown person match: {}", s))),
+        }
+    }
+}
+
+/// A booking's link to its person.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PersonLink {
+    pub person_id: i32,
+    pub matched_by: PersonMatch,
+    pub confidence: f32,
+}
+
+/// Selects what [`PersonBooking::from_row`] expects. Callers append the WHERE clause.
+const PERSON_BOOKING_SELECT: &str = r#"
+    SELECT id, person_id, first_name, middle_name, last_name, affix,
+        NULLIF(permanent_id, '') AS permanent_id, dob,
+        ARRAY(
+            SELECT alias.alias
+            FROM inmate_alias
+            JOIN alias ON alias.id = inmate_alias.alias_id
+            WHERE inmate_alias.inmate_id = inmate.id
+        ) AS aliases
+    FROM inmate
+"#;
+
+/// A booking as the person resolver compares it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PersonBooking {
+    pub inmate_id: i32,
+    /// None until the booking is linked to a person
+    pub person_id: Option<i32>,
+    pub first_name: String,
+    pub last_name: String,
+    pub dob: chrono::NaiveDate,
+    /// None if it's empty
+    pub permanent_id: Option<String>,
+    /// See [`name_keys`]
+    pub name_keys: Vec<String>,
+}
+
+impl PersonBooking {
+    fn from_row(row: &PgRow) -> Result<PersonBooking, Error> {
+        let profile = InmateProfile {
+            first_name: row.try_get("first_name")?,
+            middle_name: row.try_get("middle_name")?,
+            last_name: row.try_get("last_name")?,
+            affix: row.try_get("affix")?,
+            aliases: Some(row.try_get("aliases")?),
+            ..Default::default()
+        };
+        Ok(PersonBooking {
+            inmate_id: row.try_get("id")?,
+            person_id: row.try_get("person_id")?,
+            name_keys: name_keys(&profile),
+            first_name: profile.first_name,
+            last_name: profile.last_name,
+            dob: row.try_get("dob")?,
+            permanent_id: row.try_get("permanent_id")?,
+        })
+    }
+}
+
+/// Links the booking to a person, creating one if no other booking matches. See
+/// [`match_person`].
+///
+/// Resolving a booking again moves it if a stronger match turned up, e.g. after a name
+/// correction.
+pub(crate) async fn resolve_person(
+    inmate_id: i32,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<PersonLink, Error> {
+    let query = format!("{} WHERE id = $1 FOR UPDATE", PERSON_BOOKING_SELECT);
+    let row = sqlx::query(&query)
+        .bind(inmate_id)
+        .fetch_one(&mut **transaction)
+        .await?;
+    let booking = PersonBooking::from_row(&row)?;
+
+    let overridden: Option<Option<i32>> =
+        sqlx::query_scalar("SELECT person_id FROM person_override WHERE inmate_id = $1")
+            .bind(inmate_id)
+            .fetch_optional(&mut **transaction)
+            .await?;
+
+    // Every match needs the same permanent id or dob
+    let query = format!(
+        "{} WHERE id <> $1 AND person_id IS NOT NULL AND superseded_by IS NULL \
+        AND (dob = $2 OR permanent_id = $3)",
+        PERSON_BOOKING_SELECT
+    );
+    let candidates = sqlx::query(&query)
+        .bind(inmate_id)
+        .bind(booking.dob)
+        .bind(&booking.permanent_id)
+        .fetch_all(&mut **transaction)
+        .await?
+        .iter()
+        .map(PersonBooking::from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (person_id, matched_by) = match_person(&booking, overridden, &candidates);
+    let person_id = match person_id {
+        Some(person_id) => person_id,
+        None => own_person(inmate_id, booking.person_id, transaction).await?,
+    };
+
+    let link = PersonLink {
+        person_id,
+        matched_by,
+        confidence: matched_by.confidence(),
+    };
+    sqlx::query(
+        r#"
+        UPDATE inmate
+        SET person_id = $2, person_match = $3, person_confidence = $4
+        WHERE id = $1
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(link.person_id)
+    .bind(link.matched_by.as_str())
+    .bind(link.confidence)
+    .execute(&mut **transaction)
+    .await?;
+
+    debug!("Linked inmate {} to person: {:?}", inmate_id, link);
+    Ok(link)
+}
+
+/// Picks the person the booking belongs to from other bookings linked to people, and how it
+/// matched. `overridden` is the booking's `person_override`, if it has one. Matches are tried
+/// strongest first, see [`PersonMatch`], and the newest matching booking wins. Bookings with
+/// different permanent ids are never linked.
+///
+/// The person is None if the booking should have one of its own.
+pub(crate) fn match_person(
+    booking: &PersonBooking,
+    overridden: Option<Option<i32>>,
+    candidates: &[PersonBooking],
+) -> (Option<i32>, PersonMatch) {
+    if let Some(person_id) = overridden {
+        return (person_id, PersonMatch::Override);
+    }
+
+    // Bookings with a different permanent id are someone else with the same name
+    let candidates: Vec<&PersonBooking> = candidates
+        .iter()
+        .filter(|other| other.inmate_id != booking.inmate_id && other.person_id.is_some())
+        .filter(|other| match (&booking.permanent_id, &other.permanent_id) {
+            (Some(permanent_id), Some(other_permanent_id)) => permanent_id == other_permanent_id,
+            _ => true,
+        })
+        .sorted_by_key(|other| std::cmp::Reverse(other.inmate_id))
+        .collect();
+
+    for matched_by in [
+        PersonMatch::PermanentId,
+        PersonMatch::NameAndDob,
+        PersonMatch::AliasAndDob,
+    ] {
+        if let Some(other) = candidates
+            .iter()
+            .find(|other| is_match(matched_by, booking, other))
+        {
+            return (other.person_id, matched_by);
+        }
+    }
+    (None, PersonMatch::New)
+}
+
+/// Returns true if the bookings match the way `matched_by` says.
+fn is_match(matched_by: PersonMatch, booking: &PersonBooking, other: &PersonBooking) -> bool {
+    match matched_by {
+        PersonMatch::PermanentId => {
+            booking.permanent_id.is_some() && booking.permanent_id == other.permanent_id
+        }
+        PersonMatch::NameAndDob => {
+            booking.first_name == other.first_name
+                && booking.last_name == other.last_name
+                && booking.dob == other.dob
+        }
+        PersonMatch::AliasAndDob => {
+            booking.dob == other.dob
+                && booking
+                    .name_keys
+                    .iter()
+                    .any(|key| other.name_keys.contains(key))
+        }
+        PersonMatch::Override | PersonMatch::New => false,
+    }
+}
+
+/// Returns a person for the booking alone: the one it's on if no other booking shares it, or a
+/// new one.
+async fn own_person(
+    inmate_id: i32,
+    current: Option<i32>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<i32, Error> {
+    if let Some(current) = current {
+        let shared: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM inmate WHERE person_id = $1 AND id <> $2)",
+        )
+        .bind(current)
+        .bind(inmate_id)
+        .fetch_one(&mut **transaction)
+        .await?;
+        if !shared {
+            return Ok(current);
+        }
+    }
+
+    let person_id = sqlx::query_scalar("INSERT INTO person DEFAULT VALUES RETURNING id")
+        .fetch_one(&mut **transaction)
+        .await?;
+    Ok(person_id)
+}
+
+/// The alias keys of the booking's full name and aliases, for matching against other bookings.
+fn name_keys(profile: &InmateProfile) -> Vec<String> {
+    let mut keys: Vec<String> = std::iter::once(profile.get_full_name())
+        .chain(profile.aliases.iter().flatten().cloned())
+        .map(|name| alias_key(&name))
+        .filter(|key| !key.is_empty())
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// Links every booking that isn't linked to a person yet, e.g. bookings stored before people
+/// were tracked, oldest first. Returns how many were linked.
+pub async fn link_unresolved_bookings(pool: &PgPool) -> Result<usize, Error> {
+    let ids: Vec<i32> = sqlx::query_scalar(
+        "SELECT id FROM inmate WHERE person_id IS NULL AND superseded_by IS NULL ORDER BY id",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut linked = 0;
+    for inmate_id in ids {
+        let mut transaction = pool.begin().await?;
+        match resolve_person(inmate_id, &mut transaction).await {
+            Ok(_) => {
+                transaction.commit().await?;
+                linked += 1;
+            }
+            Err(e) => warn!("Failed to link inmate {} to a person: {:?}", inmate_id, e),
+        }
+    }
+
+    if linked > 0 {
+        info!("Linked {} bookings to people", linked);
+    }
+    Ok(linked)
+}
+
+/// Pins the booking to a person, or with `None` keeps it on a person of its own, then links it
+/// again. The resolver never overrides this.
+pub async fn set_person_override(
+    pool: &PgPool,
+    inmate_id: i32,
+    person_id: Option<i32>,
+    note: Option<&str>,
+) -> Result<PersonLink, Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query(
+        r#"
+        INSERT INTO person_override
+        (inmate_id, person_id, note)
+        VALUES
+        ($1, $2, $3)
+        ON CONFLICT (inmate_id) DO UPDATE
+        SET person_id = EXCLUDED.person_id, note = EXCLUDED.note, created_at = now()
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(person_id)
+    .bind(note)
+    .execute(&mut *transaction)
+    .await?;
+
+    let link = resolve_person(inmate_id, &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(link)
+}
+
+/// Removes the booking's override and lets the resolver link it again.
+pub async fn clear_person_override(pool: &PgPool, inmate_id: i32) -> Result<PersonLink, Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query("DELETE FROM person_override WHERE inmate_id = $1")
+        .bind(inmate_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    let link = resolve_person(inmate_id, &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(link)
+}
+
+/// Loads every booking of the person the booking belongs to, oldest first. Returns an empty list
+/// if there's no such booking or it isn't linked yet.
+pub async fn booking_history(pool: &PgPool, inmate_id: i32) -> Result<Vec<DbRecord>, Error> {
+    let person_id: Option<i32> = sqlx::query_scalar("SELECT person_id FROM inmate WHERE id = $1")
+        .bind(inmate_id)
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+
+    match person_id {
+        Some(person_id) => load_records_by_person(pool, person_id).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_person_match_round_trip() {
+        for matched_by in [
+            PersonMatch::Override,
+            PersonMatch::PermanentId,
+            PersonMatch::NameAndDob,
+            PersonMatch::AliasAndDob,
+            PersonMatch::New,
+        ] {
+            assert_eq!(
+                PersonMatch::from_str(matched_by.as_str()).unwrap(),
+                matched_by
+            );
+            assert!((0.0..=1.0).contains(&matched_by.confidence()));
+        }
+        assert!(PersonMatch::from_str("nope").is_err());
+        assert!(PersonMatch::PermanentId.confidence() > PersonMatch::NameAndDob.confidence());
+        assert!(PersonMatch::NameAndDob.confidence() > PersonMatch::AliasAndDob.confidence());
+    }
+
+    #[test]
+    fn test_name_keys() {
+        let profile = InmateProfile {
+            first_name: "John".to_string(),
+            middle_name: Some("Q".to_string()),
+            last_name: "Doe".to_string(),
+            aliases: Some(vec![
+                " johnny doe ".to_string(),
+                "JOHN Q DOE".to_string(),
+                String::new(),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(name_keys(&profile), vec!["JOHN Q DOE", "JOHNNY DOE"]);
+    }
+
+    fn booking(
+        inmate_id: i32,
+        person_id: Option<i32>,
+        permanent_id: Option<&str>,
+    ) -> PersonBooking {
+        PersonBooking {
+            inmate_id,
+            person_id,
+            first_name: "JOHN".to_string(),
+            last_name: "DOE".to_string(),
+            dob: chrono::NaiveDate::from_ymd_opt(1990, 1, 2).unwrap(),
+            permanent_id: permanent_id.map(String::from),
+            name_keys: vec!["JOHN DOE".to_string(), "JOHNNY DOE".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_match_person_order() {
+        let new = booking(20, None, Some("P-1"));
+        let by_alias = PersonBooking {
+            first_name: "JOHNNY".to_string(),
+            name_keys: vec!["JOHNNY DOE".to_string()],
+            ..booking(12, Some(1), None)
+        };
+        let by_name = booking(11, Some(2), None);
+        let by_permanent_id = PersonBooking {
+            first_name: "JACK".to_string(),
+            dob: chrono::NaiveDate::from_ymd_opt(1991, 1, 2).unwrap(),
+            name_keys: vec!["JACK DOE".to_string()],
+            ..booking(10, Some(3), Some("P-1"))
+        };
+
+        let mut candidates = vec![by_alias, by_name, by_permanent_id];
+        assert_eq!(
+            match_person(&new, None, &candidates),
+            (Some(3), PersonMatch::PermanentId)
+        );
+        candidates.pop();
+        assert_eq!(
+            match_person(&new, None, &candidates),
+            (Some(2), PersonMatch::NameAndDob)
+        );
+        candidates.pop();
+        assert_eq!(
+            match_person(&new, None, &candidates),
+            (Some(1), PersonMatch::AliasAndDob)
+        );
+        candidates.pop();
+        assert_eq!(
+            match_person(&new, None, &candidates),
+            (None, PersonMatch::New)
+        );
+    }
+
+    #[test]
+    fn test_match_person_prefers_newest_booking() {
+        let new = booking(20, None, None);
+        let candidates = vec![booking(4, Some(1), None), booking(8, Some(2), None)];
+        assert_eq!(
+            match_person(&new, None, &candidates),
+            (Some(2), PersonMatch::NameAndDob)
+        );
+    }
+
+    #[test]
+    fn test_match_person_never_links_different_permanent_ids() {
+        let new = booking(20, None, Some("P-1"));
+        let candidates = vec![booking(10, Some(1), Some("P-2"))];
+        assert_eq!(
+            match_person(&new, None, &candidates),
+            (None, PersonMatch::New)
+        );
+
+        // A booking without a permanent id can still be the same person
+        let candidates = vec![booking(10, Some(1), Some("P-2")), booking(9, Some(2), None)];
+        assert_eq!(
+            match_person(&new, None, &candidates),
+            (Some(2), PersonMatch::NameAndDob)
+        );
+    }
+
+    #[test]
+    fn test_match_person_skips_itself_and_unlinked_bookings() {
+        let new = booking(20, Some(5), None);
+        let candidates = vec![booking(20, Some(5), None), booking(10, None, None)];
+        assert_eq!(
+            match_person(&new, None, &candidates),
+            (None, PersonMatch::New)
+        );
+    }
+
+    #[test]
+    fn test_match_person_override() {
+        let new = booking(20, None, Some("P-1"));
+        let candidates = vec![booking(10, Some(1), Some("P-1"))];
+        assert_eq!(
+            match_person(&new, Some(Some(7)), &candidates),
+            (Some(7), PersonMatch::Override)
+        );
+        assert_eq!(
+            match_person(&new, Some(None), &candidates),
+            (None, PersonMatch::Override)
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, run with `TEST_DATABASE_URL=... cargo test -- --ignored`"]
+    async fn test_resolve_person_links_repeat_bookings() {
+        let pool = crate::test_db::test_pool().await;
+        let policy = crate::serialize::ImgStoragePolicy::DbOnly;
+        let first = crate::test_db::test_record(&crate::test_db::unique_suffix());
+        let first_link = crate::upsert::upsert_record(first.clone(), &pool, &None, policy)
+            .await
+            .unwrap()
+            .person
+            .unwrap();
+        assert_eq!(first_link.matched_by, PersonMatch::New);
+
+        // Booked again later, under new ids
+        let mut again = crate::test_db::test_record(&crate::test_db::unique_suffix());
+        again.profile.last_name = first.profile.last_name.clone();
+        again.profile.booking_date_iso8601 = "11/01/2024 09:00".to_string();
+        let link = crate::upsert::upsert_record(again.clone(), &pool, &None, policy)
+            .await
+            .unwrap()
+            .person
+            .unwrap();
+        assert_eq!(link.person_id, first_link.person_id);
+        assert_eq!(link.matched_by, PersonMatch::NameAndDob);
+
+        // The jail assigns a permanent id to both bookings
+        for mut record in [first.clone(), again] {
+            record.profile.perm_id = Some(format!("P{}", first.profile.last_name));
+            crate::upsert::upsert_record(record, &pool, &None, policy)
+                .await
+                .unwrap();
+        }
+
+        // Same name and dob, but the jail knows them apart
+        let mut other = crate::test_db::test_record(&crate::test_db::unique_suffix());
+        other.profile.booking_date_iso8601 = "12/01/2024 09:00".to_string();
+        other.profile.perm_id = Some(format!("P{}", other.profile.last_name));
+        other.profile.last_name = first.profile.last_name.clone();
+        let link = crate::upsert::upsert_record(other, &pool, &None, policy)
+            .await
+            .unwrap()
+            .person
+            .unwrap();
+        assert_eq!(link.matched_by, PersonMatch::New);
+        assert_ne!(link.person_id, first_link.person_id);
+    }
+}
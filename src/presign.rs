@@ -0,0 +1,142 @@
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::time::Duration;
+
+use crate::object_store::ObjectStore;
+use crate::Error;
+
+pub const DEFAULT_PRESIGN_EXPIRY: Duration = Duration::from_secs(15 * 60);
+/// SigV4 presigned URLs are valid for at most a week
+pub const MAX_PRESIGN_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A time-limited URL for one of an inmate's stored images.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PresignedImgUrl {
+    /// "original", or the name of the derivative (e.g. "thumb")
+    pub name: String,
+    pub format: String,
+    pub key: String,
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Checks that the expiry is usable for a presigned URL.
+///
+/// # Errors
+/// ArgumentError: If the expiry is zero or longer than [`MAX_PRESIGN_EXPIRY`]
+pub fn validate_presign_expiry(expires_in: Duration) -> Result<Duration, Error> {
+    if expires_in.is_zero() || expires_in > MAX_PRESIGN_EXPIRY {
+        return Err(Error::ArgumentError);
+    }
+
+    Ok(expires_in)
+}
+
+/// Returns the presign expiry from the `PRESIGN_EXPIRES_SECS` env var, or the default if unset.
+///
+/// # Errors
+/// ArgumentError: If the env var isn't a valid expiry
+pub fn presign_expiry_from_env() -> Result<Duration, Error> {
+    match std::env::var("PRESIGN_EXPIRES_SECS") {
+        Ok(secs) => validate_presign_expiry(Duration::from_secs(
+            secs.trim().parse::<u64>().map_err(|_| Error::ArgumentError)?,
+        )),
+        Err(_) => Ok(DEFAULT_PRESIGN_EXPIRY),
+    }
+}
+
+/// Returns the (name, format, key) of each stored image of an inmate: the original image, then
+/// its derivatives. Inmates stored before content addressed storage only have their legacy
+/// `inmate.img_url` key, which is returned as the original.
+pub async fn get_inmate_img_keys(
+    inmate_id: i32,
+    pool: &PgPool,
+) -> Result<Vec<(String, String, String)>, Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT 'original' AS name, image.format, image.img_url, 0 AS ord
+        FROM inmate_image
+        JOIN image ON image.id = inmate_image.image_id
+        WHERE inmate_image.inmate_id = $1 AND image.img_url <> ''
+        UNION ALL
+        SELECT img_derivative.name, img_derivative.format, img_derivative.img_url, 1 AS ord
+        FROM inmate_image
+        JOIN img_derivative ON img_derivative.image_id = inmate_image.image_id
+        WHERE inmate_image.inmate_id = $1
+        UNION ALL
+        -- Legacy mugshots were all JPEGs from the county site
+        SELECT 'original' AS name, 'jpeg' AS format, inmate.img_url, 0 AS ord
+        FROM inmate
+        WHERE inmate.id = $1 AND inmate.img_url <> ''
+            AND NOT EXISTS (SELECT 1 FROM image WHERE image.img_url = inmate.img_url)
+        ORDER BY ord, name, format
+        "#,
+    )
+    .bind(inmate_id)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| Ok((row.try_get("name")?, row.try_get("format")?, row.try_get("img_url")?)))
+        .collect()
+}
+
+/// Returns time-limited URLs for each of an inmate's stored images, so the bucket doesn't need
+/// to be public. An inmate without stored images yields no URLs.
+///
+/// # Errors
+/// ArgumentError: If the expiry is invalid
+/// InternalError: If the object store can't presign URLs
+pub async fn presign_inmate_img_urls(
+    inmate_id: i32,
+    pool: &PgPool,
+    object_store: &dyn ObjectStore,
+    expires_in: Duration,
+) -> Result<Vec<PresignedImgUrl>, Error> {
+    let expires_in = validate_presign_expiry(expires_in)?;
+    let expires_at = Utc::now()
+        + chrono::Duration::from_std(expires_in).map_err(|_| Error::ArgumentError)?;
+
+    let mut urls = Vec::new();
+    for (name, format, key) in get_inmate_img_keys(inmate_id, pool).await? {
+        let url = object_store
+            .presigned_get_url(&key, expires_in)
+            .await?
+            .ok_or(Error::InternalError(format!(
+                "Object store {:?} can't presign URLs",
+                object_store
+            )))?;
+        urls.push(PresignedImgUrl {
+            name,
+            format,
+            key,
+            url,
+            expires_at,
+        });
+    }
+    debug!("Presigned {} img urls for inmate {}", urls.len(), inmate_id);
+
+    Ok(urls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_presign_expiry() {
+        assert!(validate_presign_expiry(Duration::ZERO).is_err());
+        assert!(validate_presign_expiry(MAX_PRESIGN_EXPIRY + Duration::from_secs(1)).is_err());
+        assert_eq!(
+            validate_presign_expiry(MAX_PRESIGN_EXPIRY).unwrap(),
+            MAX_PRESIGN_EXPIRY
+        );
+        assert_eq!(
+            validate_presign_expiry(DEFAULT_PRESIGN_EXPIRY).unwrap(),
+            DEFAULT_PRESIGN_EXPIRY
+        );
+    }
+}
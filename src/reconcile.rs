@@ -0,0 +1,532 @@
+use log::{debug, info, warn};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::collections::BTreeSet;
+
+use crate::mugshot::{self, validate_mugshot};
+use crate::object_store::{ObjectStore, PutOptions};
+use crate::serialize::{upload_image, ImgProvenance, ImgStoragePolicy};
+use crate::Error;
+
+pub const DEFAULT_RECONCILE_PREFIX: &str = "mugshots/";
+
+#[derive(Debug, Clone)]
+pub struct ReconcileOptions {
+    /// Only objects under this prefix are listed, and only they can be orphans
+    pub prefix: String,
+    /// Report what's inconsistent without changing the object store or the database
+    pub dry_run: bool,
+    pub delete_orphans: bool,
+    /// Images that were never uploaded are only expected in the object store if the policy
+    /// uploads them
+    pub img_storage_policy: ImgStoragePolicy,
+}
+
+impl Default for ReconcileOptions {
+    fn default() -> Self {
+        ReconcileOptions {
+            prefix: DEFAULT_RECONCILE_PREFIX.to_string(),
+            dry_run: true,
+            delete_orphans: false,
+            img_storage_policy: ImgStoragePolicy::ObjectStoreOnly,
+        }
+    }
+}
+
+/// What a reconciliation found, and what it did about it.
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    pub listed_objects: usize,
+    pub referenced_objects: usize,
+    /// Keys referenced by the database, but not in the object store
+    pub missing: Vec<String>,
+    /// Keys uploaded again from stored blobs
+    pub restored: Vec<String>,
+    /// Keys that couldn't be restored, with the reason
+    pub unrestorable: Vec<String>,
+    /// Inmates whose empty img_url was pointed back at their stored image
+    pub relinked_inmates: u64,
+    /// Objects no database row references
+    pub orphans: Vec<String>,
+    pub deleted_orphans: Vec<String>,
+}
+
+/// Returns the referenced keys missing from the listing, and the listed keys nothing references.
+fn diff_keys(
+    listed: &BTreeSet<String>,
+    referenced: &BTreeSet<String>,
+) -> (Vec<String>, Vec<String>) {
+    let missing = referenced.difference(listed).cloned().collect();
+    let orphans = listed.difference(referenced).cloned().collect();
+
+    (missing, orphans)
+}
+
+struct StoredImage {
+    id: i32,
+    /// The key the image is stored under, or None if its upload never completed
+    img_url: Option<String>,
+    sha256: String,
+    derivative_urls: Vec<String>,
+}
+
+async fn get_stored_images(pool: &PgPool) -> Result<Vec<StoredImage>, Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT image.id, image.sha256, image.img_url,
+            COALESCE(
+                array_agg(img_derivative.img_url) FILTER (WHERE img_derivative.img_url IS NOT NULL),
+                '{}'
+            ) AS derivative_urls
+        FROM image
+        LEFT JOIN img_derivative ON img_derivative.image_id = image.id
+        GROUP BY image.id
+        ORDER BY image.id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(StoredImage {
+                id: row.try_get("id")?,
+                img_url: row
+                    .try_get::<Option<String>, _>("img_url")?
+                    .filter(|img_url| !img_url.is_empty()),
+                sha256: row.try_get("sha256")?,
+                derivative_urls: row.try_get("derivative_urls")?,
+            })
+        })
+        .collect()
+}
+
+/// Returns the keys under `prefix` that the stored images, their derivatives and the legacy
+/// inmate img_urls expect in the object store. An image whose upload never completed is expected
+/// under its content key.
+fn referenced_keys(
+    images: &[StoredImage],
+    legacy_img_urls: &[(i32, String)],
+    prefix: &str,
+) -> BTreeSet<String> {
+    let mut referenced = BTreeSet::new();
+    for image in images.iter() {
+        referenced.insert(
+            image
+                .img_url
+                .clone()
+                .unwrap_or_else(|| mugshot::content_key(&image.sha256)),
+        );
+        referenced.extend(image.derivative_urls.iter().cloned());
+    }
+    referenced.extend(legacy_img_urls.iter().map(|(_, img_url)| img_url.clone()));
+    referenced.retain(|key| key.starts_with(prefix));
+
+    referenced
+}
+
+/// Returns true if the image's upload never completed, or any of its objects are missing.
+fn needs_restore(image: &StoredImage, missing: &BTreeSet<&String>) -> bool {
+    image.img_url.is_none()
+        || image
+            .img_url
+            .iter()
+            .chain(image.derivative_urls.iter())
+            .any(|key| missing.contains(key))
+}
+
+/// Returns the (inmate id, img_url) of inmates whose key isn't an `image` or `img_derivative`
+/// key. These predate content addressed storage, and their blobs live in the legacy `img` table.
+async fn get_legacy_inmate_img_urls(pool: &PgPool) -> Result<Vec<(i32, String)>, Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT inmate.id, inmate.img_url
+        FROM inmate
+        WHERE inmate.img_url <> ''
+            AND NOT EXISTS (SELECT 1 FROM image WHERE image.img_url = inmate.img_url)
+        ORDER BY inmate.id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| Ok((row.try_get("id")?, row.try_get("img_url")?)))
+        .collect()
+}
+
+/// Uploads a stored image and its derivatives again, and records its key. Puts are conditional,
+/// so objects that survived are left untouched.
+async fn restore_image(
+    image: &StoredImage,
+    policy: ImgStoragePolicy,
+    pool: &PgPool,
+    object_store: &dyn ObjectStore,
+) -> Result<String, Error> {
+    let mut transaction = pool.begin().await?;
+    let row = sqlx::query(
+        r#"
+        SELECT image.img, inmate.id AS inmate_id, inmate.booking_date::text AS booking_date
+        FROM image
+        JOIN inmate_image ON inmate_image.image_id = image.id
+        JOIN inmate ON inmate.id = inmate_image.inmate_id
+        WHERE image.id = $1
+        ORDER BY inmate.id
+        LIMIT 1
+        "#,
+    )
+    .bind(image.id)
+    .fetch_one(&mut *transaction)
+    .await?;
+    let img_blob: Vec<u8> = row
+        .try_get::<Option<Vec<u8>>, _>("img")?
+        .ok_or(Error::InternalError(format!(
+            "Image {} has no stored blob",
+            image.id
+        )))?;
+    let booking_date: String = row.try_get("booking_date")?;
+    let provenance = ImgProvenance {
+        inmate_id: row.try_get("inmate_id")?,
+        booking_date: &booking_date,
+        source_url: None,
+    };
+
+    let img_url = image
+        .img_url
+        .clone()
+        .unwrap_or_else(|| mugshot::content_key(&image.sha256));
+    upload_image(
+        &image.id,
+        &img_blob,
+        &img_url,
+        &provenance,
+        policy,
+        object_store,
+        &mut transaction,
+    )
+    .await?;
+    transaction.commit().await?;
+
+    Ok(img_url)
+}
+
+/// Uploads a legacy inmate's blob from the `img` table under the inmate's img_url.
+async fn restore_legacy_img(
+    inmate_id: i32,
+    img_url: &str,
+    pool: &PgPool,
+    object_store: &dyn ObjectStore,
+) -> Result<(), Error> {
+    let img_blob: Vec<u8> = sqlx::query("SELECT img FROM img WHERE inmate_id = $1 AND img IS NOT NULL")
+        .bind(inmate_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(Error::InternalError(format!(
+            "No img blob stored for inmate {}",
+            inmate_id
+        )))?
+        .try_get("img")?;
+
+    upload_legacy_img(img_url, img_blob, object_store).await
+}
+
+/// Uploads a legacy blob under its img_url, unless an object is already stored there.
+async fn upload_legacy_img(
+    img_url: &str,
+    img_blob: Vec<u8>,
+    object_store: &dyn ObjectStore,
+) -> Result<(), Error> {
+    let img_metadata = validate_mugshot(&img_blob)?;
+
+    let options = PutOptions {
+        content_type: Some(img_metadata.format.content_type().to_string()),
+        if_not_exists: true,
+        ..Default::default()
+    };
+    object_store
+        .put_with_options(img_url, img_blob, &options)
+        .await?;
+
+    Ok(())
+}
+
+/// Points inmates with an empty img_url at their stored image. Returns the number of inmates
+/// updated.
+async fn relink_inmate_img_urls(pool: &PgPool) -> Result<u64, Error> {
+    let res = sqlx::query(
+        r#"
+        UPDATE inmate
+        SET img_url = image.img_url
+        FROM inmate_image
+        JOIN image ON image.id = inmate_image.image_id
+        WHERE inmate.id = inmate_image.inmate_id
+            AND COALESCE(inmate.img_url, '') = ''
+            AND COALESCE(image.img_url, '') <> ''
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(res.rows_affected())
+}
+
+/// Deletes orphaned objects, and returns the keys that were deleted. A failed delete is logged
+/// and skipped.
+async fn delete_orphans(orphans: &[String], object_store: &dyn ObjectStore) -> Vec<String> {
+    let mut deleted = Vec::new();
+    for key in orphans.iter() {
+        match object_store.delete(key).await {
+            Ok(()) => deleted.push(key.clone()),
+            Err(e) => warn!("Failed to delete orphaned object {}: {:#?}", key, e),
+        }
+    }
+
+    deleted
+}
+
+/// Compares the object store against the image keys in the database, then (unless it's a dry run)
+/// uploads missing objects again from their stored blobs, finishes interrupted uploads, and
+/// points inmates with an empty img_url back at their image.
+///
+/// Orphaned objects are only deleted when asked. An upload from a crawl that's still running can
+/// look like an orphan until its transaction commits, so don't delete orphans during a crawl.
+///
+/// # Errors
+/// Fails if the object store can't be listed or the database can't be read. Failures to restore
+/// a single object are recorded in the report instead.
+pub async fn reconcile_images(
+    pool: &PgPool,
+    object_store: &dyn ObjectStore,
+    options: &ReconcileOptions,
+) -> Result<ReconcileReport, Error> {
+    let listed: BTreeSet<String> = object_store
+        .list(&options.prefix)
+        .await?
+        .into_iter()
+        .collect();
+    let policy = options.img_storage_policy;
+    let images: Vec<StoredImage> = get_stored_images(pool)
+        .await?
+        .into_iter()
+        .filter(|image| image.img_url.is_some() || policy.uploads())
+        .collect();
+    let legacy_img_urls = get_legacy_inmate_img_urls(pool).await?;
+
+    let referenced = referenced_keys(&images, &legacy_img_urls, &options.prefix);
+    let (missing, orphans) = diff_keys(&listed, &referenced);
+    let mut report = ReconcileReport {
+        listed_objects: listed.len(),
+        referenced_objects: referenced.len(),
+        missing,
+        orphans,
+        ..Default::default()
+    };
+    info!(
+        "Reconciling {} listed objects against {} referenced objects: {} missing, {} orphaned",
+        report.listed_objects,
+        report.referenced_objects,
+        report.missing.len(),
+        report.orphans.len()
+    );
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    let missing: BTreeSet<&String> = report.missing.iter().collect();
+    for image in images.iter() {
+        if !needs_restore(image, &missing) {
+            continue;
+        }
+
+        match restore_image(image, policy, pool, object_store).await {
+            Ok(img_url) => {
+                debug!("Restored image {}: {}", image.id, img_url);
+                report.restored.push(img_url);
+            }
+            Err(e) => {
+                warn!("Failed to restore image {}: {:#?}", image.id, e);
+                report.unrestorable.push(format!("image {}: {}", image.id, e));
+            }
+        }
+    }
+
+    for (inmate_id, img_url) in legacy_img_urls.iter() {
+        if !missing.contains(img_url) {
+            continue;
+        }
+
+        match restore_legacy_img(*inmate_id, img_url, pool, object_store).await {
+            Ok(()) => report.restored.push(img_url.clone()),
+            Err(e) => {
+                warn!("Failed to restore legacy img for inmate {}: {:#?}", inmate_id, e);
+                report.unrestorable.push(format!("{}: {}", img_url, e));
+            }
+        }
+    }
+
+    report.relinked_inmates = relink_inmate_img_urls(pool).await?;
+
+    if options.delete_orphans {
+        report.deleted_orphans = delete_orphans(&report.orphans, object_store).await;
+    }
+
+    info!(
+        "Reconciled images: {} restored, {} unrestorable, {} inmates relinked, {} orphans deleted",
+        report.restored.len(),
+        report.unrestorable.len(),
+        report.relinked_inmates,
+        report.deleted_orphans.len()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::LocalObjectStore;
+    use crate::test_db::png;
+
+    fn keys(keys: &[&str]) -> BTreeSet<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn stored_image(id: i32, img_url: Option<&str>, derivative_urls: &[&str]) -> StoredImage {
+        StoredImage {
+            id,
+            img_url: img_url.map(str::to_string),
+            sha256: format!("sha{}", id),
+            derivative_urls: derivative_urls.iter().map(|key| key.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_diff_keys() {
+        let listed = keys(&["mugshots/a", "mugshots/a-thumb.jpg", "mugshots/stale"]);
+        let referenced = keys(&["mugshots/a", "mugshots/a-thumb.jpg", "mugshots/b"]);
+
+        let (missing, orphans) = diff_keys(&listed, &referenced);
+        assert_eq!(missing, vec!["mugshots/b".to_string()]);
+        assert_eq!(orphans, vec!["mugshots/stale".to_string()]);
+
+        assert_eq!(diff_keys(&listed, &listed), (vec![], vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_restore_selection() {
+        let root = tempfile::tempdir().unwrap();
+        let store = LocalObjectStore::new(root.path());
+        for key in [
+            "mugshots/a",
+            "mugshots/a-thumb.jpg",
+            "mugshots/b",
+            "mugshots/stale",
+        ] {
+            store.put(key, vec![1]).await.unwrap();
+        }
+        store.put("other/x", vec![1]).await.unwrap();
+
+        let images = vec![
+            // Fully stored
+            stored_image(1, Some("mugshots/a"), &["mugshots/a-thumb.jpg"]),
+            // Its derivative was lost
+            stored_image(2, Some("mugshots/b"), &["mugshots/b-thumb.jpg"]),
+            // Its upload never completed
+            stored_image(3, None, &[]),
+        ];
+        let legacy_img_urls = vec![
+            (10, "mugshots/legacy.jpg".to_string()),
+            (11, "elsewhere/legacy.jpg".to_string()),
+        ];
+
+        let referenced = referenced_keys(&images, &legacy_img_urls, DEFAULT_RECONCILE_PREFIX);
+        assert_eq!(
+            referenced,
+            keys(&[
+                "mugshots/a",
+                "mugshots/a-thumb.jpg",
+                "mugshots/b",
+                "mugshots/b-thumb.jpg",
+                "mugshots/legacy.jpg",
+                "mugshots/sha3",
+            ])
+        );
+
+        let listed = store
+            .list(DEFAULT_RECONCILE_PREFIX)
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        let (missing, orphans) = diff_keys(&listed, &referenced);
+        assert_eq!(
+            missing,
+            vec![
+                "mugshots/b-thumb.jpg",
+                "mugshots/legacy.jpg",
+                "mugshots/sha3"
+            ]
+        );
+        assert_eq!(orphans, vec!["mugshots/stale"]);
+
+        let missing: BTreeSet<&String> = missing.iter().collect();
+        let restored: Vec<i32> = images
+            .iter()
+            .filter(|image| needs_restore(image, &missing))
+            .map(|image| image.id)
+            .collect();
+        assert_eq!(restored, vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_upload_legacy_img() {
+        let root = tempfile::tempdir().unwrap();
+        let store = LocalObjectStore::new(root.path());
+        let img_blob = png(0);
+
+        upload_legacy_img("mugshots/legacy.jpg", img_blob.clone(), &store)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("mugshots/legacy.jpg").await.unwrap(),
+            Some(img_blob.clone())
+        );
+
+        // An object that reappeared in the meantime is left untouched
+        store.put("mugshots/kept.jpg", vec![1, 2, 3]).await.unwrap();
+        upload_legacy_img("mugshots/kept.jpg", img_blob, &store)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("mugshots/kept.jpg").await.unwrap(),
+            Some(vec![1, 2, 3])
+        );
+
+        // A corrupt blob isn't uploaded
+        assert!(
+            upload_legacy_img("mugshots/corrupt.jpg", vec![0; 16], &store)
+                .await
+                .is_err()
+        );
+        assert!(!store.exists("mugshots/corrupt.jpg").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete_orphans() {
+        let root = tempfile::tempdir().unwrap();
+        let store = LocalObjectStore::new(root.path());
+        for key in ["mugshots/a", "mugshots/stale", "mugshots/stale-thumb.jpg"] {
+            store.put(key, vec![1]).await.unwrap();
+        }
+
+        let orphans = vec![
+            "mugshots/stale".to_string(),
+            "mugshots/stale-thumb.jpg".to_string(),
+            // Refused by the store, so it's skipped
+            "../escape".to_string(),
+        ];
+        let deleted = delete_orphans(&orphans, &store).await;
+        assert_eq!(deleted, orphans[..2]);
+        assert_eq!(store.list("").await.unwrap(), vec!["mugshots/a"]);
+    }
+}
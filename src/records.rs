@@ -0,0 +1,408 @@
+use chrono::{DateTime, Utc};
+use log::trace;
+use serde::Serialize;
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::{FromRow, Row};
+use std::collections::HashMap;
+
+use crate::inmate::{Bond, BondInformation, Charge, ChargeInformation, DbInmateProfile, Record};
+use crate::person::PersonLink;
+use crate::{Error, SCOTT_COUNTY_INMATE_TRAVERSAL_ROOT};
+
+/// Selects what [`DbInmateProfile`]'s `FromRow<PgRow>` expects. Callers append the WHERE and
+/// ORDER BY clauses.
+const PROFILE_SELECT: &str = r#"
+    SELECT inmate.id, inmate.first_name, inmate.middle_name, inmate.last_name, inmate.affix,
+        inmate.permanent_id, inmate.sex, to_char(inmate.dob, 'MM/DD/YYYY') AS dob,
+        inmate.arresting_agency,
+        to_char(inmate.booking_date AT TIME ZONE 'America/Chicago', 'MM/DD/YYYY HH24:MI') AS booking_date,
+        inmate.booking_date AS booked_at, inmate.booking_number, inmate.height, inmate.weight,
+        inmate.race, inmate.eye_color, inmate.scil_sysid, inmate.img_url, inmate.person_id,
+        inmate.person_match, inmate.person_confidence,
+        ARRAY(
+            SELECT alias.alias
+            FROM inmate_alias
+            JOIN alias ON alias.id = inmate_alias.alias_id
+            WHERE inmate_alias.inmate_id = inmate.id
+            ORDER BY alias.alias
+        ) AS aliases,
+        latest_image.sha256 AS img_sha256, latest_image.format AS img_format,
+        latest_image.width AS img_width, latest_image.height AS img_height
+    FROM inmate
+    LEFT JOIN LATERAL (
+        SELECT image.sha256, image.format, image.width, image.height
+        FROM inmate_image
+        JOIN image ON image.id = inmate_image.image_id
+        WHERE inmate_image.inmate_id = inmate.id
+        ORDER BY image.id DESC
+        LIMIT 1
+    ) latest_image ON true
+"#;
+
+/// A record loaded from Postgres, with what the database knows beyond the crawl.
+#[derive(Debug)]
+pub struct DbRecord {
+    pub id: i32,
+    pub booked_at: DateTime<Utc>,
+    /// The image's object store key, once it's uploaded
+    pub img_url: Option<String>,
+    /// None until the booking is linked to a person
+    pub person: Option<PersonLink>,
+    pub record: Record,
+}
+
+/// Where a page of records ends. Pass it back to [`page_records`] to get the next page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RecordCursor {
+    pub booked_at: DateTime<Utc>,
+    pub id: i32,
+}
+
+#[derive(Debug)]
+pub struct RecordPage {
+    pub records: Vec<DbRecord>,
+    /// None once there are no more records
+    pub next: Option<RecordCursor>,
+}
+
+/// Loads the record of an inmate by id, even if it's a superseded duplicate. Returns None if
+/// there's no such inmate.
+pub async fn load_record(pool: &PgPool, inmate_id: i32) -> Result<Option<DbRecord>, Error> {
+    let query = format!("{} WHERE inmate.id = $1", PROFILE_SELECT);
+    let rows = sqlx::query(&query).bind(inmate_id).fetch_all(pool).await?;
+    Ok(load_records(pool, rows).await?.pop())
+}
+
+/// Loads the booking with the sys_id, e.g. "?sysid=...". Returns None if there's none.
+pub async fn load_record_by_sys_id(pool: &PgPool, sys_id: &str) -> Result<Option<DbRecord>, Error> {
+    let query = format!(
+        "{} WHERE inmate.scil_sysid = $1 AND inmate.superseded_by IS NULL ORDER BY inmate.booking_date DESC, inmate.id DESC LIMIT 1",
+        PROFILE_SELECT
+    );
+    let rows = sqlx::query(&query).bind(sys_id).fetch_all(pool).await?;
+    Ok(load_records(pool, rows).await?.pop())
+}
+
+/// Loads the booking with the booking number. Returns None if there's none.
+pub async fn load_record_by_booking_number(
+    pool: &PgPool,
+    booking_number: &str,
+) -> Result<Option<DbRecord>, Error> {
+    let query = format!(
+        "{} WHERE inmate.booking_number = $1 AND inmate.superseded_by IS NULL ORDER BY inmate.booking_date DESC, inmate.id DESC LIMIT 1",
+        PROFILE_SELECT
+    );
+    let rows = sqlx::query(&query)
+        .bind(booking_number)
+        .fetch_all(pool)
+        .await?;
+    Ok(load_records(pool, rows).await?.pop())
+}
+
+/// Loads every booking of the person, oldest first.
+pub async fn load_records_by_person(pool: &PgPool, person_id: i32) -> Result<Vec<DbRecord>, Error> {
+    let query = format!(
+        "{} WHERE inmate.person_id = $1 AND inmate.superseded_by IS NULL \
+        ORDER BY inmate.booking_date, inmate.id",
+        PROFILE_SELECT
+    );
+    let rows = sqlx::query(&query).bind(person_id).fetch_all(pool).await?;
+    load_records(pool, rows).await
+}
+
+/// Returns up to `limit` records booked after the cursor, oldest first. Start with `None`.
+/// Duplicates superseded by a later row are skipped.
+///
+/// # Errors
+/// ArgumentError: If the limit isn't positive
+pub async fn page_records(
+    pool: &PgPool,
+    after: Option<RecordCursor>,
+    limit: i64,
+) -> Result<RecordPage, Error> {
+    if limit <= 0 {
+        return Err(Error::ArgumentError);
+    }
+
+    // The cursor keeps pages stable while new bookings are inserted
+    let query = format!(
+        "{} WHERE inmate.superseded_by IS NULL \
+        AND ($1::timestamptz IS NULL OR (inmate.booking_date, inmate.id) > ($1, $2)) \
+        ORDER BY inmate.booking_date, inmate.id LIMIT $3",
+        PROFILE_SELECT
+    );
+    let rows = sqlx::query(&query)
+        .bind(after.map(|cursor| cursor.booked_at))
+        .bind(after.map_or(0, |cursor| cursor.id))
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+    let full_page = rows.len() as i64 == limit;
+    let records = load_records(pool, rows).await?;
+    let next = next_cursor(&records, full_page);
+
+    Ok(RecordPage { records, next })
+}
+
+/// Returns the cursor after the last record of a page, or None if the page wasn't full, in which
+/// case there's nothing after it.
+fn next_cursor(records: &[DbRecord], full_page: bool) -> Option<RecordCursor> {
+    records
+        .last()
+        .filter(|_| full_page)
+        .map(|last| RecordCursor {
+            booked_at: last.booked_at,
+            id: last.id,
+        })
+}
+
+/// Loads the bonds and charges of the profile rows, in one query each, and assembles records in
+/// the rows' order.
+async fn load_records(pool: &PgPool, rows: Vec<PgRow>) -> Result<Vec<DbRecord>, Error> {
+    let ids = rows
+        .iter()
+        .map(|row| row.try_get::<i32, _>("id"))
+        .collect::<Result<Vec<i32>, _>>()?;
+    trace!("Loading records for inmate ids: {:?}", ids);
+
+    let mut bonds: HashMap<i32, Vec<Bond>> = HashMap::new();
+    for row in sqlx::query(
+        r#"
+        SELECT inmate_id, type, amount_pennies, amount_unparseable, amount_not_set
+        FROM bond
+        WHERE inmate_id = ANY($1)
+        ORDER BY id
+        "#,
+    )
+    .bind(&ids)
+    .fetch_all(pool)
+    .await?
+    {
+        bonds
+            .entry(row.try_get("inmate_id")?)
+            .or_default()
+            .push(Bond::from_row(&row)?);
+    }
+
+    let mut charges: HashMap<i32, Vec<Charge>> = HashMap::new();
+    for row in sqlx::query(
+        r#"
+        SELECT inmate_id, description, grade, offense_date
+        FROM charge
+        WHERE inmate_id = ANY($1)
+        ORDER BY id
+        "#,
+    )
+    .bind(&ids)
+    .fetch_all(pool)
+    .await?
+    {
+        charges
+            .entry(row.try_get("inmate_id")?)
+            .or_default()
+            .push(Charge::from_row(&row)?);
+    }
+
+    let mut records = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        let db_profile = DbInmateProfile::from_row(row)?;
+        let id = db_profile.id as i32;
+        let person = match row.try_get::<Option<i32>, _>("person_id")? {
+            Some(person_id) => Some(PersonLink {
+                person_id,
+                matched_by: row.try_get::<String, _>("person_match")?.parse()?,
+                confidence: row.try_get("person_confidence")?,
+            }),
+            None => None,
+        };
+        records.push(DbRecord {
+            id,
+            booked_at: row.try_get("booked_at")?,
+            img_url: db_profile.img_url,
+            person,
+            record: Record {
+                url: format!(
+                    "{}{}",
+                    SCOTT_COUNTY_INMATE_TRAVERSAL_ROOT,
+                    db_profile
+                        .profile
+                        .scil_sys_id
+                        .as_deref()
+                        .unwrap_or_default()
+                ),
+                profile: db_profile.profile,
+                bond: BondInformation {
+                    bonds: bonds.remove(&id).unwrap_or_default(),
+                },
+                charges: ChargeInformation {
+                    charges: charges.remove(&id).unwrap_or_default(),
+                },
+            },
+        });
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inmate::ChargeGrade;
+    use crate::mugshot::validate_mugshot;
+    use crate::serialize::ImgStoragePolicy;
+    use crate::test_db::{png, test_pool, test_record, unique_suffix};
+    use crate::upsert::upsert_record;
+    use chrono::{NaiveDate, TimeZone};
+
+    fn db_record(id: i32, booked_at: DateTime<Utc>) -> DbRecord {
+        DbRecord {
+            id,
+            booked_at,
+            img_url: None,
+            person: None,
+            record: test_record(&id.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_next_cursor() {
+        let booked_at = Utc.with_ymd_and_hms(2024, 10, 1, 13, 15, 0).unwrap();
+        let records = vec![db_record(3, booked_at), db_record(2, booked_at)];
+        assert_eq!(
+            next_cursor(&records, true),
+            Some(RecordCursor { booked_at, id: 2 })
+        );
+        assert_eq!(next_cursor(&records, false), None);
+        assert_eq!(next_cursor(&[], true), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, run with `TEST_DATABASE_URL=... cargo test -- --ignored`"]
+    async fn test_load_record_round_trip() {
+        let pool = test_pool().await;
+        let mut record = test_record(&unique_suffix());
+        record.profile.middle_name = Some("Q".to_string());
+        record.profile.aliases = Some(vec!["JD".to_string(), "BIG JOHN".to_string()]);
+        record.profile.img_blob = Some(png(3));
+        record.profile.img_metadata = Some(validate_mugshot(&png(3)).unwrap());
+        record.bond.bonds[0].amount_not_set = true;
+        record.charges.charges.push(crate::inmate::Charge {
+            description: "OWI".to_string(),
+            grade: ChargeGrade::Felony,
+            offense_date: "09/30/2024".to_string(),
+        });
+        let inmate_id = upsert_record(record.clone(), &pool, &None, ImgStoragePolicy::DbOnly)
+            .await
+            .unwrap()
+            .inmate_id;
+
+        let loaded = load_record(&pool, inmate_id).await.unwrap().unwrap();
+        assert_eq!(loaded.id, inmate_id);
+        assert!(loaded.img_url.is_none());
+        assert!(loaded.person.is_some());
+        // 08:15 in Chicago
+        assert_eq!(
+            loaded.booked_at,
+            Utc.with_ymd_and_hms(2024, 10, 1, 13, 15, 0).unwrap()
+        );
+        let sys_id = record.profile.scil_sys_id.clone().unwrap();
+        assert_eq!(
+            loaded.record.url,
+            format!("{SCOTT_COUNTY_INMATE_TRAVERSAL_ROOT}{sys_id}")
+        );
+
+        // Nothing changes going from the stored booking to the crawl it came from
+        let diff = loaded.record.diff(&record);
+        assert!(diff.is_empty(), "{diff:#?}");
+        assert_eq!(
+            loaded.record.profile.aliases,
+            Some(vec!["BIG JOHN".to_string(), "JD".to_string()])
+        );
+        assert!(loaded.record.bond.bonds[0].amount_not_set);
+        // The record loaders leave the image bytes out, Record::load brings them back
+        assert!(loaded.record.profile.img_blob.is_none());
+        assert!(loaded.record.profile.img_metadata.unwrap().ahash.is_none());
+        let full = Record::load(&pool, inmate_id).await.unwrap().unwrap();
+        assert_eq!(full.profile.img_blob, Some(png(3)));
+        assert_eq!(full.profile.img_metadata, record.profile.img_metadata);
+        assert!(Record::load(&pool, -1).await.unwrap().is_none());
+
+        let by_sys_id = load_record_by_sys_id(&pool, &sys_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_sys_id.id, inmate_id);
+        let booking_number = record.profile.booking_number.unwrap();
+        let by_booking_number = load_record_by_booking_number(&pool, &booking_number)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_booking_number.id, inmate_id);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, run with `TEST_DATABASE_URL=... cargo test -- --ignored`"]
+    async fn test_page_records() {
+        let pool = test_pool().await;
+        let policy = ImgStoragePolicy::DbOnly;
+        // Booked far in the future, later than anything earlier runs stored
+        let booked_local = NaiveDate::from_ymd_opt(2200, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            + chrono::Duration::minutes(Utc::now().timestamp() * 2);
+
+        // Two bookings at the same time, then one a minute later
+        let mut ids = Vec::new();
+        for minutes in [0, 0, 1] {
+            let mut record = test_record(&unique_suffix());
+            record.profile.booking_date_iso8601 = (booked_local
+                + chrono::Duration::minutes(minutes))
+            .format("%m/%d/%Y %H:%M")
+            .to_string();
+            let changes = upsert_record(record, &pool, &None, policy).await.unwrap();
+            ids.push(changes.inmate_id);
+        }
+
+        let booked_at: DateTime<Utc> =
+            sqlx::query_scalar("SELECT booking_date FROM inmate WHERE id = $1")
+                .bind(ids[0])
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let start = RecordCursor {
+            booked_at: booked_at - chrono::Duration::seconds(1),
+            id: 0,
+        };
+        let page = page_records(&pool, Some(start), 2).await.unwrap();
+        let page_ids: Vec<i32> = page.records.iter().map(|record| record.id).collect();
+        assert_eq!(page_ids, ids[..2]);
+        // The bookings at the same time are told apart by id
+        assert_eq!(page.records[1].booked_at, booked_at);
+        let next = page.next.unwrap();
+        assert_eq!(
+            next,
+            RecordCursor {
+                booked_at,
+                id: ids[1]
+            }
+        );
+        let tie = RecordCursor {
+            booked_at,
+            id: ids[0],
+        };
+        let page = page_records(&pool, Some(tie), 1).await.unwrap();
+        assert_eq!(page.records[0].id, ids[1]);
+
+        let page = page_records(&pool, Some(next), 2).await.unwrap();
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(page.records[0].id, ids[2]);
+        assert!(page.next.is_none());
+
+        assert!(matches!(
+            page_records(&pool, None, 0).await,
+            Err(Error::ArgumentError)
+        ));
+    }
+}
@@ -1,125 +1,322 @@
 use crate::Error;
-use aws_config::meta::region::RegionProviderChain;
+use aws_config::environment::EnvironmentVariableCredentialsProvider;
+use aws_config::profile::ProfileFileCredentialsProvider;
+use log::error;
 use aws_sdk_s3::operation::{
     copy_object::{CopyObjectError, CopyObjectOutput},
     create_bucket::{CreateBucketError, CreateBucketOutput},
     get_object::{GetObjectError, GetObjectOutput},
-    list_objects_v2::ListObjectsV2Output,
     put_object::{PutObjectError, PutObjectOutput},
 };
+use aws_sdk_s3::primitives::DateTimeFormat;
 use aws_sdk_s3::types::{
     BucketLocationConstraint, CreateBucketConfiguration, Delete, ObjectIdentifier,
 };
-use aws_sdk_s3::{config::Region, error::SdkError, primitives::ByteStream, Client};
+use aws_sdk_s3::{
+    config::{Credentials, Region},
+    error::SdkError,
+    primitives::ByteStream,
+    Client,
+};
+use serde::Serialize;
 use std::env;
 use std::path::Path;
 use std::str;
 
 const DEFAULT_BUCKET_NAME: &str = "scjailio-dev";
+const DEFAULT_REGION: &str = "us-east-2";
+
+/// Where the S3 client gets its credentials from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum S3CredentialsSource {
+    /// The default AWS provider chain: env vars, shared profile files, then instance metadata
+    Default,
+    /// Only the `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY` env vars
+    Environment,
+    /// A named profile from the shared AWS config files
+    Profile(String),
+    /// Fixed keys, e.g. for a local MinIO
+    Static {
+        access_key_id: String,
+        secret_access_key: String,
+    },
+}
+
+/// Everything needed to reach the bucket, so S3-compatible providers (MinIO, LocalStack, ...) can
+/// be used in place of AWS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    /// Custom endpoint, e.g. "http://localhost:9000". None uses the AWS endpoint for the region.
+    pub endpoint_url: Option<String>,
+    /// Address buckets as `<endpoint>/<bucket>` instead of `<bucket>.<endpoint>`, which most
+    /// S3-compatible servers require
+    pub force_path_style: bool,
+    pub credentials: S3CredentialsSource,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            bucket: DEFAULT_BUCKET_NAME.to_string(),
+            region: DEFAULT_REGION.to_string(),
+            endpoint_url: None,
+            force_path_style: false,
+            credentials: S3CredentialsSource::Default,
+        }
+    }
+}
+
+impl S3Config {
+    /// Reads the S3 config from env vars:
+    /// - `AWS_BUCKET_NAME` (default "scjailio-dev")
+    /// - `AWS_REGION` (default "us-east-2")
+    /// - `S3_ENDPOINT_URL`
+    /// - `S3_FORCE_PATH_STYLE` ("true"/"1" to enable)
+    /// - `S3_CREDENTIALS_SOURCE`: "default", "environment", "profile" (named by `AWS_PROFILE`) or
+    ///   "static" (`S3_ACCESS_KEY_ID` and `S3_SECRET_ACCESS_KEY`)
+    ///
+    /// # Errors
+    /// ArgumentError: If the credentials source is unknown, or is missing its settings
+    pub fn from_env() -> Result<S3Config, Error> {
+        S3Config::from_lookup(|name| env::var(name).ok())
+    }
+
+    /// Same as [`S3Config::from_env`], reading each variable through `lookup`.
+    pub fn from_lookup<F>(lookup: F) -> Result<S3Config, Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let lookup = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+        let defaults = S3Config::default();
+
+        let credentials = match lookup("S3_CREDENTIALS_SOURCE")
+            .unwrap_or("default".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "default" => S3CredentialsSource::Default,
+            "environment" | "env" => S3CredentialsSource::Environment,
+            "profile" => S3CredentialsSource::Profile(lookup("AWS_PROFILE").ok_or_else(|| {
+                error!("S3_CREDENTIALS_SOURCE=profile requires AWS_PROFILE");
+                Error::ArgumentError
+            })?),
+            "static" => match (lookup("S3_ACCESS_KEY_ID"), lookup("S3_SECRET_ACCESS_KEY")) {
+                (Some(access_key_id), Some(secret_access_key)) => S3CredentialsSource::Static {
+                    access_key_id,
+                    secret_access_key,
+                },
+                _ => {
+                    error!("S3_CREDENTIALS_SOURCE=static requires S3_ACCESS_KEY_ID and S3_SECRET_ACCESS_KEY");
+                    return Err(Error::ArgumentError);
+                }
+            },
+            other => {
+                error!("Unknown S3_CREDENTIALS_SOURCE: {}. Expected one of: default, environment, profile, static", other);
+                return Err(Error::ArgumentError);
+            }
+        };
+
+        Ok(S3Config {
+            bucket: lookup("AWS_BUCKET_NAME").unwrap_or(defaults.bucket),
+            region: lookup("AWS_REGION").unwrap_or(defaults.region),
+            endpoint_url: lookup("S3_ENDPOINT_URL"),
+            force_path_style: lookup("S3_FORCE_PATH_STYLE")
+                .is_some_and(|value| matches!(value.to_lowercase().as_str(), "true" | "1")),
+            credentials,
+        })
+    }
+
+    pub async fn build_client(&self) -> Client {
+        let mut loader = aws_config::from_env().region(Region::new(self.region.clone()));
+        loader = match &self.credentials {
+            S3CredentialsSource::Default => loader,
+            S3CredentialsSource::Environment => {
+                loader.credentials_provider(EnvironmentVariableCredentialsProvider::new())
+            }
+            S3CredentialsSource::Profile(profile) => loader.credentials_provider(
+                ProfileFileCredentialsProvider::builder()
+                    .profile_name(profile)
+                    .build(),
+            ),
+            S3CredentialsSource::Static {
+                access_key_id,
+                secret_access_key,
+            } => loader.credentials_provider(Credentials::new(
+                access_key_id,
+                secret_access_key,
+                None,
+                None,
+                "scjail-static",
+            )),
+        };
+        let shared_config = loader.load().await;
 
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&shared_config)
+            .force_path_style(self.force_path_style);
+        if let Some(endpoint_url) = &self.endpoint_url {
+            s3_config = s3_config.endpoint_url(endpoint_url);
+        }
+
+        Client::from_conf(s3_config.build())
+    }
+}
+
+/// Returns a client built from [`S3Config::from_env`], and its region.
+///
+/// # Panics
+/// If the S3 env config is invalid
 pub async fn get_default_s3_client() -> (Region, Client) {
-    let region_provider = RegionProviderChain::first_try(Region::new("us-east-2"));
-    let region = region_provider
-        .region()
-        .await
-        .expect("Expect us-east-2 to be a valid region");
+    let config = S3Config::from_env().expect("Expect a valid S3 env config");
+    (Region::new(config.region.clone()), config.build_client().await)
+}
+
+/// An object in a bucket listing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ObjectSummary {
+    pub key: String,
+    pub size: i64,
+    /// RFC 3339 timestamp
+    pub last_modified: Option<String>,
+}
 
-    let shared_config = aws_config::from_env().region(region_provider).load().await;
-    let client = Client::new(&shared_config);
+/// S3 deletes at most this many objects per DeleteObjects request
+const DELETE_BATCH_SIZE: usize = 1000;
 
-    (region, client)
+pub async fn bucket_exists(client: &Client, bucket_name: &str) -> Result<bool, Error> {
+    match client.head_bucket().bucket(bucket_name).send().await {
+        Ok(_) => Ok(true),
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Creates the bucket unless it already exists. Returns true if the bucket was created.
+pub async fn bootstrap_bucket(client: &Client, bucket_name: &str, region: &str) -> Result<bool, Error> {
+    if bucket_exists(client, bucket_name).await? {
+        return Ok(false);
+    }
+    create_bucket(client, bucket_name, region).await?;
+
+    Ok(true)
 }
 
 pub async fn delete_bucket(client: &Client, bucket_name: &str) -> Result<(), Error> {
     client.delete_bucket().bucket(bucket_name).send().await?;
-    println!("Bucket deleted");
     Ok(())
 }
 
-pub async fn delete_objects(client: &Client, bucket_name: &str) -> Result<Vec<String>, Error> {
-    let objects = client.list_objects_v2().bucket(bucket_name).send().await?;
-
-    let mut delete_objects: Vec<ObjectIdentifier> = vec![];
-    for obj in objects.contents() {
-        let obj_id = ObjectIdentifier::builder()
-            .set_key(Some(obj.key().unwrap().to_string()))
-            .build()
-            .map_err(Error::from)?;
-        delete_objects.push(obj_id);
-    }
-
-    let return_keys = delete_objects.iter().map(|o| o.key.clone()).collect();
+/// Deletes every object under the prefix. Returns the deleted keys.
+///
+/// # Errors
+/// S3Error: If any object couldn't be deleted
+pub async fn delete_objects(client: &Client, bucket_name: &str, prefix: &str) -> Result<Vec<String>, Error> {
+    let keys: Vec<String> = list_objects(client, bucket_name, prefix)
+        .await?
+        .into_iter()
+        .map(|object| object.key)
+        .collect();
 
-    if !delete_objects.is_empty() {
-        client
+    for batch in keys.chunks(DELETE_BATCH_SIZE) {
+        let delete_objects = batch
+            .iter()
+            .map(|key| ObjectIdentifier::builder().key(key).build())
+            .collect::<Result<Vec<ObjectIdentifier>, _>>()?;
+        let output = client
             .delete_objects()
             .bucket(bucket_name)
             .delete(
                 Delete::builder()
                     .set_objects(Some(delete_objects))
+                    .quiet(true)
                     .build()
                     .map_err(Error::from)?,
             )
             .send()
             .await?;
-    }
-
-    let objects: ListObjectsV2Output = client.list_objects_v2().bucket(bucket_name).send().await?;
-
-    eprintln!("{objects:?}");
 
-    match objects.key_count {
-        Some(0) => Ok(return_keys),
-        _ => Err(Error::S3Error(
-            "There were still objects left in the bucket.".to_string(),
-        )),
+        if let Some(e) = output.errors().first() {
+            error!("Failed to delete {} objects. First failure: {:?}", output.errors().len(), e);
+            return Err(Error::S3Error(format!(
+                "Failed to delete {} objects under {}",
+                output.errors().len(),
+                prefix
+            )));
+        }
     }
+
+    Ok(keys)
 }
 
-pub async fn list_objects(client: &Client, bucket: &str) -> Result<(), Error> {
-    let mut response = client
+/// Returns every object under the prefix, in key order.
+pub async fn list_objects(client: &Client, bucket: &str, prefix: &str) -> Result<Vec<ObjectSummary>, Error> {
+    let mut pages = client
         .list_objects_v2()
-        .bucket(bucket.to_owned())
-        .max_keys(10) // In this example, go 10 at a time.
+        .bucket(bucket)
+        .prefix(prefix)
         .into_paginator()
         .send();
 
-    while let Some(result) = response.next().await {
-        match result {
-            Ok(output) => {
-                for object in output.contents() {
-                    println!(" - {}", object.key().unwrap_or("Unknown"));
-                }
-            }
-            Err(err) => {
-                eprintln!("{err:?}")
-            }
+    let mut objects = Vec::new();
+    while let Some(page) = pages.next().await {
+        for object in page?.contents() {
+            let Some(key) = object.key() else {
+                continue;
+            };
+            objects.push(ObjectSummary {
+                key: key.to_string(),
+                size: object.size().unwrap_or_default(),
+                last_modified: object
+                    .last_modified()
+                    .and_then(|last_modified| last_modified.fmt(DateTimeFormat::DateTime).ok()),
+            });
         }
     }
+    objects.sort_by(|a, b| a.key.cmp(&b.key));
 
-    Ok(())
+    Ok(objects)
 }
 
+/// The `x-amz-copy-source` of an object: its bucket and key.
+fn copy_source(bucket_name: &str, key: &str) -> String {
+    format!("{}/{}", bucket_name, key)
+}
+
+/// Copies an object, possibly between buckets. The client's credentials need access to both.
 pub async fn copy_object(
     client: &Client,
-    bucket_name: &str,
-    object_key: &str,
+    source_bucket: &str,
+    source_key: &str,
+    target_bucket: &str,
     target_key: &str,
 ) -> Result<CopyObjectOutput, SdkError<CopyObjectError>> {
-    let mut source_bucket_and_object: String = "".to_owned();
-    source_bucket_and_object.push_str(bucket_name);
-    source_bucket_and_object.push('/');
-    source_bucket_and_object.push_str(object_key);
-
     client
         .copy_object()
-        .copy_source(source_bucket_and_object)
-        .bucket(bucket_name)
+        .copy_source(copy_source(source_bucket, source_key))
+        .bucket(target_bucket)
         .key(target_key)
         .send()
         .await
 }
 
+/// Copies every object under the prefix to the same key in the target bucket. Returns the copied
+/// keys.
+pub async fn copy_objects(
+    client: &Client,
+    source_bucket: &str,
+    target_bucket: &str,
+    prefix: &str,
+) -> Result<Vec<String>, Error> {
+    let mut copied = Vec::new();
+    for object in list_objects(client, source_bucket, prefix).await? {
+        copy_object(client, source_bucket, &object.key, target_bucket, &object.key).await?;
+        copied.push(object.key);
+    }
+
+    Ok(copied)
+}
+
 pub async fn download_object(
     client: &Client,
     bucket_name: &str,
@@ -148,21 +345,26 @@ pub async fn upload_img_to_default_bucket_s3(
         .await
 }
 
+/// Uploads the img to the bucket configured by [`S3Config::from_env`].
+///
+/// # Errors
+/// ArgumentError: If the S3 env config is invalid, so nothing is written to an unintended bucket
+/// S3Error: If the upload fails
 pub async fn upload_img_to_env_bucket_s3(
     client: &Client,
     img_data: Vec<u8>,
     img_key_s3: &str,
-) -> Result<PutObjectOutput, SdkError<PutObjectError>> {
+) -> Result<PutObjectOutput, Error> {
+    let bucket = S3Config::from_env()?.bucket;
     let body = ByteStream::from(img_data);
-    let bucket = env::var("AWS_BUCKET_NAME").unwrap_or(DEFAULT_BUCKET_NAME.to_string());
 
-    client
+    Ok(client
         .put_object()
         .bucket(bucket)
         .key(img_key_s3)
         .body(body)
         .send()
-        .await
+        .await?)
 }
 
 pub async fn upload_object(
@@ -186,14 +388,97 @@ pub async fn create_bucket(
     bucket_name: &str,
     region: &str,
 ) -> Result<CreateBucketOutput, SdkError<CreateBucketError>> {
-    let constraint = BucketLocationConstraint::from(region);
-    let cfg = CreateBucketConfiguration::builder()
-        .location_constraint(constraint)
-        .build();
+    // us-east-1 is the default location, and S3 rejects it as an explicit constraint
+    let cfg = (region != "us-east-1").then(|| {
+        CreateBucketConfiguration::builder()
+            .location_constraint(BucketLocationConstraint::from(region))
+            .build()
+    });
     client
         .create_bucket()
-        .create_bucket_configuration(cfg)
+        .set_create_bucket_configuration(cfg)
         .bucket(bucket_name)
         .send()
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<S3Config, Error> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        S3Config::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_s3_config_defaults() {
+        assert_eq!(config_from(&[]).unwrap(), S3Config::default());
+        assert_eq!(
+            config_from(&[("AWS_BUCKET_NAME", " "), ("S3_ENDPOINT_URL", "")]).unwrap(),
+            S3Config::default()
+        );
+    }
+
+    #[test]
+    fn test_s3_config_minio() {
+        let config = config_from(&[
+            ("AWS_BUCKET_NAME", "scjailio-test"),
+            ("AWS_REGION", "us-east-1"),
+            ("S3_ENDPOINT_URL", "http://localhost:9000"),
+            ("S3_FORCE_PATH_STYLE", "true"),
+            ("S3_CREDENTIALS_SOURCE", "static"),
+            ("S3_ACCESS_KEY_ID", "minioadmin"),
+            ("S3_SECRET_ACCESS_KEY", "minioadmin"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config,
+            S3Config {
+                bucket: "scjailio-test".to_string(),
+                region: "us-east-1".to_string(),
+                endpoint_url: Some("http://localhost:9000".to_string()),
+                force_path_style: true,
+                credentials: S3CredentialsSource::Static {
+                    access_key_id: "minioadmin".to_string(),
+                    secret_access_key: "minioadmin".to_string(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_copy_source() {
+        assert_eq!(
+            copy_source("scjailio-dev", "mugshots/abc-thumb.jpg"),
+            "scjailio-dev/mugshots/abc-thumb.jpg"
+        );
+    }
+
+    #[test]
+    fn test_s3_config_credentials_source() {
+        assert_eq!(
+            config_from(&[("S3_CREDENTIALS_SOURCE", "Environment")])
+                .unwrap()
+                .credentials,
+            S3CredentialsSource::Environment
+        );
+        assert_eq!(
+            config_from(&[("S3_CREDENTIALS_SOURCE", "profile"), ("AWS_PROFILE", "scjail")])
+                .unwrap()
+                .credentials,
+            S3CredentialsSource::Profile("scjail".to_string())
+        );
+        assert!(config_from(&[("S3_CREDENTIALS_SOURCE", "profile")]).is_err());
+        assert!(config_from(&[
+            ("S3_CREDENTIALS_SOURCE", "static"),
+            ("S3_ACCESS_KEY_ID", "key")
+        ])
+        .is_err());
+        assert!(config_from(&[("S3_CREDENTIALS_SOURCE", "magic")]).is_err());
+    }
+}
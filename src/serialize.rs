@@ -1,153 +1,119 @@
 use async_openai::config::{Config, OpenAIConfig};
 use async_openai::Client;
-use aws_sdk_s3::Client as S3Client;
 use itertools::Itertools;
+use std::collections::BTreeMap;
 use log::{debug, info, trace, warn};
 use sqlx::postgres::PgPool;
-use sqlx::Row;
+use sqlx::{Connection, Row};
 
+use crate::alias::alias_key;
 use crate::inmate::{Bond, Charge, InmateProfile, Record};
-use crate::s3_utils;
+use crate::mugshot::{self, Derivative, MugshotMetadata};
+use crate::object_store::{ObjectStore, PutOptions, PutOutcome};
+use crate::upsert::{log_changes, upsert_record_in, RecordChanges};
+use crate::utils::get_crawl_run_id;
 use crate::Error;
 
-pub async fn create_dbs(pool: &PgPool) -> Result<(), Error> {
-    info!("Creating databases if not already existing...");
-    create_inmate(pool).await?;
-    create_alias(pool).await?;
-    create_bond(pool).await?;
-    create_charge(pool).await?;
-    create_img(pool).await?;
-    create_inmate_alias(pool).await?;
+/// Keys are content addressed, so an uploaded object never changes
+const IMG_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
 
-    info!("Databases created successfully!");
-    Ok(())
-}
-
-async fn run_sql_batch(
-    pool: &sqlx::Pool<sqlx::Postgres>,
-    statements: &Vec<&str>,
-) -> Result<(), Error> {
-    for statement in statements {
-        debug!("Running statement: {}", statement);
-        sqlx::query(statement).execute(pool).await.expect(&format!(
-            "Expect run sql batch statements. Failed on statement: {}",
-            statement
-        ));
-    }
-
-    Ok(())
+/// Where an uploaded image came from, stored as object metadata.
+pub(crate) struct ImgProvenance<'a> {
+    pub(crate) inmate_id: i32,
+    pub(crate) booking_date: &'a str,
+    /// None when the image isn't uploaded during a crawl, e.g. when reconciling
+    pub(crate) source_url: Option<&'a str>,
 }
 
-async fn create_inmate_alias(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<(), Error> {
-    sqlx::query!(
-        r#"CREATE TABLE IF NOT EXISTS inmate_alias (
-          inmate_id INTEGER NOT NULL,
-          alias_id INTEGER NOT NULL,
-          FOREIGN KEY (inmate_id) REFERENCES inmate(id),
-          FOREIGN KEY (alias_id) REFERENCES alias(id),
-          PRIMARY KEY (inmate_id, alias_id)
-        );"#
-    )
-    .execute(pool)
-    .await?;
-
-    Ok(())
-}
+impl ImgProvenance<'_> {
+    /// Options for a conditional put, so an existing object is never overwritten.
+    fn put_options(&self, content_type: &str, derivative: Option<&str>) -> PutOptions {
+        let mut metadata = BTreeMap::from([
+            ("inmate-id".to_string(), self.inmate_id.to_string()),
+            ("booking-date".to_string(), self.booking_date.to_string()),
+            ("crawl-run-id".to_string(), get_crawl_run_id().to_string()),
+        ]);
+        if let Some(source_url) = self.source_url {
+            metadata.insert("source-url".to_string(), source_url.to_string());
+        }
+        if let Some(derivative) = derivative {
+            metadata.insert("derivative".to_string(), derivative.to_string());
+        }
 
-async fn create_img(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<(), Error> {
-    let statements = vec![
-        r#"CREATE TABLE IF NOT EXISTS img (
-          id SERIAL PRIMARY KEY,
-          inmate_id INTEGER NOT NULL,
-          img BYTEA,
-          FOREIGN KEY (inmate_id) REFERENCES inmate(id) 
-        );"#,
-        r#"CREATE INDEX IF NOT EXISTS idx_img_inmate_id ON img(inmate_id);"#,
-    ];
-    run_sql_batch(pool, &statements).await
+        PutOptions {
+            content_type: Some(content_type.to_string()),
+            cache_control: Some(IMG_CACHE_CONTROL.to_string()),
+            metadata,
+            if_not_exists: true,
+        }
+    }
 }
 
-async fn create_charge(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<(), Error> {
-    let statements = vec![
-        r#"CREATE TABLE IF NOT EXISTS charge (
-          id SERIAL PRIMARY KEY,
-          inmate_id INTEGER,
-          description TEXT,
-          grade TEXT,
-          offense_date TEXT,
-          FOREIGN KEY (inmate_id) REFERENCES inmate(id)
-        );"#,
-        r#"CREATE INDEX IF NOT EXISTS idx_inmate_id ON charge(inmate_id);"#,
-    ];
-    run_sql_batch(pool, &statements).await
+/// Where mugshot blobs are kept, set by the `IMG_STORAGE_POLICY` env var.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImgStoragePolicy {
+    /// Only in the `image` table, nothing is uploaded
+    DbOnly,
+    /// Only in the object store. A blob stays in the `image` table until its upload succeeds.
+    ObjectStoreOnly,
+    /// In both the `image` table and the object store
+    Both,
 }
 
-async fn create_bond(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<(), Error> {
-    let statements = vec![
-        r#"CREATE TABLE IF NOT EXISTS bond (
-          id SERIAL PRIMARY KEY,
-          inmate_id INTEGER NOT NULL,
-          type TEXT NOT NULL,
-          amount_pennies INTEGER NOT NULL DEFAULT 0,
-          FOREIGN KEY (inmate_id) REFERENCES inmate(id) 
-        );"#,
-        r#"CREATE INDEX IF NOT EXISTS bond_inmate_id_idx ON bond(inmate_id);"#,
-    ];
-
-    run_sql_batch(pool, &statements).await
-}
+impl ImgStoragePolicy {
+    /// Reads the policy from `IMG_STORAGE_POLICY` ("db", "object-store" or "both"). When unset,
+    /// blobs are only kept in the object store if there is one, and in the database otherwise.
+    ///
+    /// # Errors
+    /// ArgumentError: If `IMG_STORAGE_POLICY` names an unknown policy
+    pub fn from_env(has_object_store: bool) -> Result<ImgStoragePolicy, Error> {
+        ImgStoragePolicy::from_value(
+            std::env::var("IMG_STORAGE_POLICY").ok().as_deref(),
+            has_object_store,
+        )
+    }
 
-async fn create_alias(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<(), Error> {
-    sqlx::query!(
-        r#"CREATE TABLE IF NOT EXISTS alias (
-            id SERIAL PRIMARY KEY,
-            alias TEXT UNIQUE NOT NULL CHECK (alias <> '')
-        );"#,
-    )
-    .execute(pool)
-    .await?;
+    fn from_value(
+        value: Option<&str>,
+        has_object_store: bool,
+    ) -> Result<ImgStoragePolicy, Error> {
+        match value.map(|value| value.trim().to_lowercase()).as_deref() {
+            None | Some("") if has_object_store => Ok(ImgStoragePolicy::ObjectStoreOnly),
+            None | Some("") => Ok(ImgStoragePolicy::DbOnly),
+            Some("db") | Some("db-only") => Ok(ImgStoragePolicy::DbOnly),
+            Some("object-store") | Some("object-store-only") => {
+                Ok(ImgStoragePolicy::ObjectStoreOnly)
+            }
+            Some("both") => Ok(ImgStoragePolicy::Both),
+            Some(other) => {
+                warn!("Unknown IMG_STORAGE_POLICY: {}", other);
+                Err(Error::ArgumentError)
+            }
+        }
+    }
 
-    Ok(())
-}
+    pub fn uploads(&self) -> bool {
+        matches!(self, ImgStoragePolicy::ObjectStoreOnly | ImgStoragePolicy::Both)
+    }
 
-pub async fn create_inmate(pool: &PgPool) -> Result<(), Error> {
-    let statements = vec![
-        r#"CREATE EXTENSION IF NOT EXISTS vector;"#,
-        r#"CREATE EXTENSION IF NOT EXISTS pg_trgm;"#,
-        r#"CREATE TABLE IF NOT EXISTS inmate (
-          id SERIAL PRIMARY KEY,
-          first_name TEXT NOT NULL CHECK (first_name <> ''),
-          middle_name TEXT,
-          last_name TEXT NOT NULL CHECK (last_name <> ''),
-          affix TEXT,
-          permanent_id TEXT,
-          sex TEXT,
-          dob date NOT NULL,
-          arresting_agency TEXT,
-          booking_date TIMESTAMP WITH TIME ZONE NOT NULL,
-          booking_number TEXT,
-          height TEXT,
-          weight TEXT,
-          race TEXT,
-          eye_color TEXT,
-          img_url TEXT,
-          scil_sysid TEXT,
-          record_visits INTEGER DEFAULT 0,
-          shared INTEGER DEFAULT 0,
-          embedding vector(1536),
-          UNIQUE (first_name, last_name, dob, booking_date)
-        );"#,
-        r#"CREATE INDEX IF NOT EXISTS idx_inmate_first_name ON inmate(first_name);"#,
-        r#"CREATE INDEX IF NOT EXISTS idx_inmate_middle_name ON inmate(middle_name);"#,
-        r#"CREATE INDEX IF NOT EXISTS idx_inmate_last_name ON inmate(last_name);"#,
-    ];
-    run_sql_batch(pool, &statements).await
+    pub fn keeps_db_blob(&self) -> bool {
+        matches!(self, ImgStoragePolicy::DbOnly | ImgStoragePolicy::Both)
+    }
 }
 
-/// Returns true if the profile has the necessary criteria to upload to S3, false otherwise.
-fn has_s3_upload_criteria(profile: &InmateProfile, aws_s3_client: &Option<S3Client>) -> bool {
-    debug!("Checking S3 upload criteria... Profile has img?: {:#?}. Have s3 client?: {:#?}", profile.img_blob.is_some(), aws_s3_client.is_some());
-    profile.img_blob.is_some() && !profile.img_blob.as_ref().unwrap().is_empty() && aws_s3_client.is_some()
+/// Returns true if the profile has the necessary criteria to upload to the object store, false
+/// otherwise.
+fn has_img_upload_criteria(
+    profile: &InmateProfile,
+    object_store: &Option<Box<dyn ObjectStore>>,
+    policy: ImgStoragePolicy,
+) -> bool {
+    debug!("Checking img upload criteria... Profile has img?: {:#?}. Have object store?: {:#?}. Policy: {:?}", profile.img_blob.is_some(), object_store.is_some(), policy);
+    profile.img_blob.is_some()
+        && !profile.img_blob.as_ref().unwrap().is_empty()
+        && profile.img_metadata.is_some()
+        && object_store.is_some()
+        && policy.uploads()
 }
 
 pub async fn inmate_count(pool: &PgPool) -> Result<i64, Error> {
@@ -159,61 +125,165 @@ pub async fn inmate_count(pool: &PgPool) -> Result<i64, Error> {
         .expect("Expect count to be present on on inmate count query"))
 }
 
-/// Serializes a batch of records into the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializeOptions {
+    /// Records committed per transaction. Each record gets its own savepoint, so a record that
+    /// fails is rolled back alone.
+    pub batch_size: usize,
+    /// Where mugshot blobs are kept. Images are only uploaded if there's an object store too.
+    pub img_storage_policy: ImgStoragePolicy,
+}
+
+impl Default for SerializeOptions {
+    fn default() -> Self {
+        SerializeOptions {
+            batch_size: 1,
+            img_storage_policy: ImgStoragePolicy::ObjectStoreOnly,
+        }
+    }
+}
+
+impl SerializeOptions {
+    /// Overrides the batch size with the `SERIALIZE_BATCH_SIZE` env var, if set.
+    ///
+    /// # Errors
+    /// ArgumentError: If `SERIALIZE_BATCH_SIZE` isn't a positive integer
+    pub fn with_env(mut self) -> Result<SerializeOptions, Error> {
+        if let Ok(batch_size) = std::env::var("SERIALIZE_BATCH_SIZE") {
+            self.batch_size = batch_size
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|batch_size| *batch_size > 0)
+                .ok_or(Error::ArgumentError)?;
+        }
+
+        Ok(self)
+    }
+}
+
+/// Serializes a batch of records into the database, merging re-crawled bookings into their
+/// existing inmate. Records are committed `options.batch_size` at a time. Returns the changes
+/// made by each record that serialized.
 ///
 /// # Errors
 /// Only errors if count query used in final log fails. Otherwise, failures to insert are logged
-/// and the function continues to the next record.
+/// and the function continues to the next record. If a batch fails to commit, its records are
+/// counted as failed.
 pub async fn serialize_records<I, C>(
     records: I,
     pool: &PgPool,
     oai_client: &Option<Client<OpenAIConfig>>,
-    aws_s3_client: &Option<S3Client>,
-) -> Result<(), Error>
+    object_store: &Option<Box<dyn ObjectStore>>,
+    options: &SerializeOptions,
+) -> Result<Vec<RecordChanges>, Error>
 where
     I: IntoIterator<Item = crate::inmate::Record>,
+    I::IntoIter: Send,
     C: Config,
 {
-    info!("Serializing records...");
-    let (mut inserted_count, mut failed_count) = (0, 0);
-    for (idx, mut record) in records.into_iter().enumerate() {
-        trace!("Serializing record: {:#?}", record);
-
-        if record.profile.embedding.is_none() && oai_client.is_some() {
-            if let Err(e) = record
-                .gather_openai_embedding(oai_client.as_ref().unwrap())
-                .await
-            {
-                warn!(
-                    "Failed to gather OpenAI embedding: {:#?}. Continuing serialize.",
-                    e
-                );
+    info!("Serializing records in batches of {}...", options.batch_size);
+    let (mut inserted_count, mut merged_count, mut failed_count) = (0, 0, 0);
+    let mut processed_count = 0;
+    let mut all_changes = Vec::new();
+    let mut records = records.into_iter();
+    loop {
+        let mut batch: Vec<Record> = records.by_ref().take(options.batch_size.max(1)).collect();
+        if batch.is_empty() {
+            break;
+        }
+        // Before the batch's transaction, so its locks aren't held while OpenAI responds
+        if let Some(oai_client) = oai_client {
+            for record in batch.iter_mut() {
+                gather_embedding(record, oai_client).await;
             }
         }
-
-        match serialize_record(record, pool, aws_s3_client).await {
-            Ok(_) => {
-                inserted_count += 1;
+        let batch_len = batch.len();
+        match serialize_batch(batch, pool, object_store, options.img_storage_policy).await {
+            Ok((changes, failed)) => {
+                for changes in changes {
+                    log_changes(&changes);
+                    if changes.created {
+                        inserted_count += 1;
+                    } else if !changes.is_unchanged() {
+                        merged_count += 1;
+                    }
+                    all_changes.push(changes);
+                }
+                failed_count += failed;
             }
             Err(e) => {
-                warn!("Failed to serialize record. Error: {:#?}", e);
-                failed_count += 1;
+                warn!(
+                    "Failed to commit a batch of {} records. Error: {:#?}",
+                    batch_len, e
+                );
+                failed_count += batch_len;
             }
         }
 
-        if idx % 25 == 0 {
-            info!("Processed {} records", idx + 1);
+        // Log about every 25 records, whatever the batch size
+        let previous_count = processed_count;
+        processed_count += batch_len;
+        if previous_count == 0 || previous_count / 25 != processed_count / 25 {
+            info!("Processed {} records", processed_count);
         }
     }
 
     info!(
-        "Inserted {} records, failed to insert {} records. Total records: {}. OpenAI querying enabled? {}",
+        "Inserted {} records, merged changes into {} re-crawled records, failed to serialize {} records. Total records: {}. OpenAI querying enabled? {}",
         inserted_count,
+        merged_count,
         failed_count,
         inmate_count(pool).await?,
         oai_client.is_some()
     );
-    Ok(())
+    Ok(all_changes)
+}
+
+/// Gathers the record's embedding if it's missing. Failures are logged, the record can be stored
+/// without one.
+pub async fn gather_embedding<C: Config>(record: &mut Record, oai_client: &Client<C>) {
+    if record.profile.embedding.is_some() {
+        return;
+    }
+    if let Err(e) = record.gather_openai_embedding(oai_client).await {
+        warn!(
+            "Failed to gather OpenAI embedding: {:#?}. Continuing serialize.",
+            e
+        );
+    }
+}
+
+/// Upserts the records in one transaction, each in its own savepoint. Returns the changes of the
+/// records that serialized and how many failed. Nothing in the transaction waits on the network:
+/// embeddings are gathered beforehand, and images are uploaded from the outbox after it commits.
+async fn serialize_batch(
+    records: Vec<Record>,
+    pool: &PgPool,
+    object_store: &Option<Box<dyn ObjectStore>>,
+    policy: ImgStoragePolicy,
+) -> Result<(Vec<RecordChanges>, usize), Error> {
+    let mut transaction = pool.begin().await?;
+    let (mut all_changes, mut failed_count) = (Vec::with_capacity(records.len()), 0);
+    for record in records {
+        trace!("Serializing record: {:#?}", record);
+
+        let mut savepoint = transaction.begin().await?;
+        match upsert_record_in(record, &mut savepoint, object_store, policy).await {
+            Ok(changes) => {
+                savepoint.commit().await?;
+                all_changes.push(changes);
+            }
+            Err(e) => {
+                savepoint.rollback().await?;
+                warn!("Failed to serialize record. Error: {:#?}", e);
+                failed_count += 1;
+            }
+        }
+    }
+    transaction.commit().await?;
+
+    Ok((all_changes, failed_count))
 }
 
 /// Updates null img records with the img blob from the latest parse.
@@ -221,13 +291,17 @@ where
 /// available.
 ///
 /// # Errors
-/// Returns an error if the S3 client is not found.
-pub async fn update_null_img_records<I>(records: I, pool: &PgPool, aws_s3_client: &Option<S3Client>)
--> Result<(), Error>
+/// Returns an error if the policy uploads images and the object store is not found.
+pub async fn update_null_img_records<I>(
+    records: I,
+    pool: &PgPool,
+    object_store: &Option<Box<dyn ObjectStore>>,
+    policy: ImgStoragePolicy,
+) -> Result<(), Error>
 where I: IntoIterator<Item = (i32, crate::inmate::Record)>
 {
-    if aws_s3_client.is_none() {
-        return Err(Error::InternalError("No S3 client found. Cannot update null img records.".to_string()));
+    if object_store.is_none() && policy.uploads() {
+        return Err(Error::InternalError("No object store found. Cannot update null img records.".to_string()));
     }
 
     info!("Updating null img records...");
@@ -235,7 +309,7 @@ where I: IntoIterator<Item = (i32, crate::inmate::Record)>
     for (idx, record) in records.into_iter() {
         trace!("Updating record: {:#?}", record);
 
-        match update_null_img_record(&idx, &record, pool, aws_s3_client).await {
+        match update_null_img_record(&idx, &record, pool, object_store, policy).await {
             Ok(_) => {
                 updated_count += 1;
             }
@@ -258,7 +332,8 @@ pub async fn update_null_img_record(
     inmate_id: &i32,
     record: &Record,
     pool: &PgPool,
-    aws_s3_client: &Option<S3Client>,
+    object_store: &Option<Box<dyn ObjectStore>>,
+    policy: ImgStoragePolicy,
 ) -> Result<(), Error> {
 
     if record.profile.img_blob.is_none() {
@@ -269,30 +344,47 @@ pub async fn update_null_img_record(
             parsing failures, or internal logic failures".to_string()));
     }
 
-    let meets_upload_criteria = has_s3_upload_criteria(&record.profile, &aws_s3_client);
-    if !meets_upload_criteria {
-        return Err(Error::InternalError(format!("Record or env does not meet S3 upload criteria.")));
+    // Without uploads, the img is done once it's stored in the database
+    let meets_upload_criteria = has_img_upload_criteria(&record.profile, object_store, policy);
+    if !meets_upload_criteria && (policy.uploads() || record.profile.img_metadata.is_none()) {
+        return Err(Error::InternalError("Record or env does not meet img upload criteria.".to_string()));
     }
 
-    let s3_img_url = record.profile.get_hash_on_core_attributes();
-    s3_utils::upload_img_to_env_bucket_s3(
-        aws_s3_client.as_ref().unwrap(),
-        record.profile.img_blob.clone().unwrap(),
-        &s3_img_url,
-    ).await?;
-
-    debug!("Image uploaded to S3 successfully: {:#?}", s3_img_url);
-    sqlx::query!(
-        r#"
-        UPDATE inmate
-        SET img_url = $1
-        WHERE id = $2
-        "#,
-        s3_img_url,
-        inmate_id
-    ).execute(pool).await?;
+    let mut transaction = pool.begin().await?;
+    let (image_id, existing_img_url) = serialize_image(
+        inmate_id,
+        record.profile.img_blob.as_ref().unwrap(),
+        record.profile.img_metadata.as_ref().unwrap(),
+        &mut transaction,
+    )
+    .await?;
+    match existing_img_url {
+        Some(existing_img_url) => {
+            debug!("Image already in object store, reusing it: {:#?}", existing_img_url);
+            sqlx::query(
+                r#"
+                UPDATE inmate
+                SET img_url = $1
+                WHERE id = $2
+                "#,
+            )
+            .bind(existing_img_url)
+            .bind(inmate_id)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        None if meets_upload_criteria => {
+            let img_url = record.profile.get_img_key().ok_or(Error::InternalError(
+                "Expect profile meeting img upload criteria to have an img key".to_string(),
+            ))?;
+            enqueue_img_upload(&image_id, inmate_id, &img_url, Some(&record.url), &mut transaction)
+                .await?;
+        }
+        None => debug!("Image stored in the database only, policy: {:?}", policy),
+    }
+    transaction.commit().await?;
 
-    info!("Null img record updated: {}. Inmate id {} should have s3 img now", record.url, inmate_id);
+    info!("Null img record updated: {}. Inmate id {} has a stored or queued img now", record.url, inmate_id);
     debug!("Null img record updated: {:#?}.", record);
     Ok(())
 }
@@ -300,23 +392,31 @@ pub async fn update_null_img_record(
 pub async fn serialize_record(
     record: Record,
     pool: &PgPool,
-    aws_s3_client: &Option<S3Client>,
+    object_store: &Option<Box<dyn ObjectStore>>,
+    policy: ImgStoragePolicy,
 ) -> Result<i32, Error> {
     trace!("Serializing record: {:#?}", record);
     let mut transaction = pool.begin().await?;
-    let inmate_info = record.profile.get_core_attributes();
-    let inmate_id = serialize_profile(record.profile, &mut transaction, aws_s3_client).await?;
-
-    for bond in record.bond.bonds {
-        serialize_bond(bond, &inmate_id, &mut transaction).await?;
-    }
-
-    for charge in record.charges.charges {
-        serialize_charge(charge, &inmate_id, &mut transaction).await?;
-    }
+    let inmate_id = insert_record(record, &mut transaction, object_store, policy).await?;
 
     // Commit transaction, otherwise implicity rollback on out of scope
     transaction.commit().await?;
+    Ok(inmate_id)
+}
+
+/// Inserts a new booking with its bonds and charges inside the caller's transaction.
+pub(crate) async fn insert_record(
+    record: Record,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    object_store: &Option<Box<dyn ObjectStore>>,
+    policy: ImgStoragePolicy,
+) -> Result<i32, Error> {
+    let inmate_info = record.profile.get_core_attributes();
+    let inmate_id =
+        serialize_profile(record.profile, &record.url, transaction, object_store, policy).await?;
+
+    serialize_bonds(&record.bond.bonds, &inmate_id, transaction).await?;
+    serialize_charges(&record.charges.charges, &inmate_id, transaction).await?;
 
     debug!(
         "Successfully serialized {} yielding inmate_id: {}.",
@@ -325,102 +425,393 @@ pub async fn serialize_record(
     Ok(inmate_id)
 }
 
-async fn serialize_bond(
-    bond: Bond,
+/// Inserts the inmate's bonds in one round trip, keeping their order.
+pub(crate) async fn serialize_bonds(
+    bonds: &[Bond],
     inmate_id: &i32,
     transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
 ) -> Result<(), Error> {
-    // Could do bulk insert here: https://github.com/launchbadge/sqlx/blob/main/FAQ.md#how-can-i-bind-an-array-to-a-values-clause-how-can-i-do-bulk-inserts
-    // But, there is a low amount of bonds per inmate; therefores, its probably overengineering
+    if bonds.is_empty() {
+        return Ok(());
+    }
+
+    let types: Vec<&str> = bonds.iter().map(|bond| bond.bond_type.as_str()).collect();
+    let amounts = bonds
+        .iter()
+        .map(|bond| i64::try_from(bond.bond_amount))
+        .collect::<Result<Vec<i64>, _>>()?;
+    let unparseable: Vec<bool> = bonds.iter().map(|bond| bond.amount_unparseable).collect();
+    let not_set: Vec<bool> = bonds.iter().map(|bond| bond.amount_not_set).collect();
     sqlx::query!(
         r#"
         INSERT INTO bond
-            (inmate_id, type, amount_pennies)
-        VALUES
-            ($1, $2, $3)
+            (inmate_id, type, amount_pennies, amount_unparseable, amount_not_set)
+        SELECT $1, type, amount_pennies, amount_unparseable, amount_not_set
+        FROM UNNEST($2::text[], $3::bigint[], $4::boolean[], $5::boolean[])
+            WITH ORDINALITY AS bonds(type, amount_pennies, amount_unparseable, amount_not_set, idx)
+        ORDER BY idx
         "#,
         inmate_id,
-        bond.bond_type,
-        bond.bond_amount as i32 // TODO: update schema to use i64? bonds are in pennies, so a few billion is possible (I think?) It would be historic...
+        &types as &[&str],
+        &amounts,
+        &unparseable,
+        &not_set
     )
     .execute(&mut **transaction)
     .await?;
 
-    trace!("Bond serialized: {:#?}", bond);
+    trace!("Bonds serialized: {:#?}", bonds);
     Ok(())
 }
 
-async fn serialize_charge(
-    charge: Charge,
+/// Inserts the inmate's charges in one round trip, keeping their order.
+pub(crate) async fn serialize_charges(
+    charges: &[Charge],
     inmate_id: &i32,
     transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
 ) -> Result<(), Error> {
-    // Could do bulk insert here: https://github.com/launchbadge/sqlx/blob/main/FAQ.md#how-can-i-bind-an-array-to-a-values-clause-how-can-i-do-bulk-inserts
-    // But, there is a low amount of bonds per inmate; therefores, its probably overengineering
+    if charges.is_empty() {
+        return Ok(());
+    }
+
+    let descriptions: Vec<&str> = charges
+        .iter()
+        .map(|charge| charge.description.as_str())
+        .collect();
+    let grades: Vec<String> = charges
+        .iter()
+        .map(|charge| charge.grade.to_string())
+        .collect();
+    let offense_dates: Vec<&str> = charges
+        .iter()
+        .map(|charge| charge.offense_date.as_str())
+        .collect();
     sqlx::query!(
         r#"
         INSERT INTO charge
             (inmate_id, description, grade, offense_date)
-        VALUES
-            ($1, $2, $3, $4)
+        SELECT $1, description, grade, offense_date
+        FROM UNNEST($2::text[], $3::text[], $4::text[])
+            WITH ORDINALITY AS charges(description, grade, offense_date, idx)
+        ORDER BY idx
         "#,
         inmate_id,
-        charge.description,
-        charge.grade.to_string(),
-        charge.offense_date
+        &descriptions as &[&str],
+        &grades,
+        &offense_dates as &[&str]
     )
     .execute(&mut **transaction)
     .await?;
 
-    trace!("Charge serialized: {:#?}", charge);
+    trace!("Charges serialized: {:#?}", charges);
     Ok(())
 }
 
-async fn serialize_alias(
-    alias: String,
+/// Stores the profile's image once, keyed by the SHA-256 of its content, and links it to the
+/// inmate. Returns the image id, and the image's object store key if an earlier booking already
+/// uploaded the same image.
+pub(crate) async fn serialize_image(
+    inmate_id: &i32,
+    img_blob: &[u8],
+    img_metadata: &MugshotMetadata,
     transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-) -> Result<i32, Error> {
+) -> Result<(i32, Option<String>), Error> {
     // two possible routes to the query -
+    // -- insert image, returning id
+    // -- conflict on sha256 (same image from another booking), return existing id & img_url
+    let row = sqlx::query(
+        r#"
+        INSERT INTO image
+            (sha256, img, format, width, height)
+        VALUES
+            ($1, $2, $3, $4, $5)
+        ON CONFLICT (sha256) DO UPDATE
+            SET sha256 = EXCLUDED.sha256
+        RETURNING id, img_url
+        "#,
+    )
+    .bind(&img_metadata.sha256)
+    .bind(img_blob)
+    .bind(img_metadata.format.to_string())
+    .bind(img_metadata.width as i32)
+    .bind(img_metadata.height as i32)
+    .fetch_one(&mut **transaction)
+    .await?;
+    let image_id: i32 = row.try_get("id")?;
+    let img_url: Option<String> = row.try_get("img_url")?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO inmate_image
+            (inmate_id, image_id)
+        VALUES
+            ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(image_id)
+    .execute(&mut **transaction)
+    .await?;
+    debug!("Image serialized. Image ID: {}", image_id);
+
+    Ok((image_id, img_url.filter(|img_url| !img_url.is_empty())))
+}
+
+/// Queues the upload of an image to the object store, to be done by [`crate::upload_queue`] once
+/// the transaction commits. An image is queued at most once; queueing an unfinished upload again
+/// gives it a fresh set of attempts, so a re-crawl revives one that ran out of them.
+async fn enqueue_img_upload(
+    image_id: &i32,
+    inmate_id: &i32,
+    img_url: &str,
+    source_url: Option<&str>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO img_upload_task
+            (image_id, inmate_id, img_url, source_url)
+        VALUES
+            ($1, $2, $3, $4)
+        ON CONFLICT (image_id) DO UPDATE
+        SET attempts = 0, last_error = NULL, next_attempt_at = now()
+        WHERE img_upload_task.completed_at IS NULL
+        "#,
+    )
+    .bind(image_id)
+    .bind(inmate_id)
+    .bind(img_url)
+    .bind(source_url)
+    .execute(&mut **transaction)
+    .await?;
+    debug!("Image upload queued. Image ID: {}", image_id);
+
+    Ok(())
+}
+
+/// Uploads an image and its derivatives to the object store, then records the image's key. The
+/// image's blob is dropped from the database unless the [`ImgStoragePolicy`] keeps it. Objects
+/// already under a key are left untouched and treated as uploaded.
+///
+/// # Errors
+/// Returns an error if the image isn't a valid mugshot or the original image fails to upload.
+/// Derivative failures are only logged.
+pub(crate) async fn upload_image(
+    image_id: &i32,
+    img_blob: &[u8],
+    img_url: &str,
+    provenance: &ImgProvenance<'_>,
+    policy: ImgStoragePolicy,
+    object_store: &dyn ObjectStore,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), Error> {
+    let img_metadata = mugshot::validate_mugshot(img_blob)?;
+    let options = provenance.put_options(img_metadata.format.content_type(), None);
+    match object_store.put_with_options(img_url, img_blob.to_vec(), &options).await? {
+        PutOutcome::Stored => trace!("Image uploaded to object store successfully: {}", img_url),
+        PutOutcome::AlreadyExists => debug!("Image already in object store: {}", img_url),
+    }
+
+    let derivatives = upload_img_derivatives(img_blob, img_url, provenance, object_store).await;
+    serialize_img_derivatives(image_id, img_url, &derivatives, transaction).await?;
+
+    // The object store has the image now, so only keep the blob if the policy asks for it
+    let keep_db_blob = policy.keeps_db_blob();
+    sqlx::query(
+        r#"
+        UPDATE image
+        SET img_url = $1,
+            img = CASE WHEN $3 THEN img ELSE NULL END
+        WHERE id = $2
+        "#,
+    )
+    .bind(img_url)
+    .bind(image_id)
+    .bind(keep_db_blob)
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Generates the configured derivatives of an image and uploads them next to the original
+/// image's key. Returns the derivatives that were uploaded successfully; failures are logged and
+/// skipped, as the original image is still usable without them.
+async fn upload_img_derivatives(
+    img_blob: &[u8],
+    img_url: &str,
+    provenance: &ImgProvenance<'_>,
+    object_store: &dyn ObjectStore,
+) -> Vec<Derivative> {
+    let derivatives =
+        match mugshot::generate_derivatives(img_blob, &mugshot::derivative_specs_from_env()) {
+            Ok(derivatives) => derivatives,
+            Err(e) => {
+                warn!("Failed to generate image derivatives for {}: {:#?}", img_url, e);
+                return Vec::new();
+            }
+        };
+
+    let mut uploaded = Vec::new();
+    for derivative in derivatives {
+        let key = derivative.spec.key(img_url);
+        let options = provenance.put_options(
+            derivative.spec.format.content_type(),
+            Some(&derivative.spec.name),
+        );
+        match object_store
+            .put_with_options(&key, derivative.bytes.clone(), &options)
+            .await
+        {
+            Ok(_) => {
+                trace!("Image derivative uploaded to object store successfully: {}", key);
+                uploaded.push(derivative);
+            }
+            Err(e) => warn!("Failed to upload image derivative {}: {:#?}", key, e),
+        }
+    }
+
+    uploaded
+}
+
+async fn serialize_img_derivatives(
+    image_id: &i32,
+    img_url: &str,
+    derivatives: &[Derivative],
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), Error> {
+    for derivative in derivatives {
+        sqlx::query(
+            r#"
+            INSERT INTO img_derivative
+                (image_id, name, img_url, format, width, height)
+            VALUES
+                ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (image_id, name, format) DO UPDATE
+                SET img_url = EXCLUDED.img_url,
+                    width = EXCLUDED.width,
+                    height = EXCLUDED.height
+            "#,
+        )
+        .bind(image_id)
+        .bind(&derivative.spec.name)
+        .bind(derivative.spec.key(img_url))
+        .bind(derivative.spec.format.to_string())
+        .bind(derivative.width as i32)
+        .bind(derivative.height as i32)
+        .execute(&mut **transaction)
+        .await?;
+    }
+    debug!("Image derivatives serialized: {}", derivatives.len());
+
+    Ok(())
+}
+
+/// Links the aliases to the inmate, storing the ones that are new. Empty and repeated aliases are
+/// skipped. An alias that fails to store is logged and skipped rather than failing the record.
+pub(crate) async fn link_aliases(
+    aliases: &[String],
+    inmate_id: &i32,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), Error> {
+    let aliases: Vec<&str> = aliases
+        .iter()
+        .map(String::as_str)
+        .filter(|alias| !alias.is_empty())
+        .unique()
+        .collect();
+    if aliases.is_empty() {
+        return Ok(());
+    }
+
+    // All at once, then one at a time to find the alias that failed
+    let mut savepoint = transaction.begin().await?;
+    match insert_aliases(&aliases, inmate_id, &mut savepoint).await {
+        Ok(()) => {
+            savepoint.commit().await?;
+            return Ok(());
+        }
+        Err(e) => {
+            savepoint.rollback().await?;
+            debug!(
+                "Failed to link aliases together, linking them one at a time: {:#?}",
+                e
+            );
+        }
+    }
+    for alias in aliases {
+        let mut savepoint = transaction.begin().await?;
+        match insert_aliases(&[alias], inmate_id, &mut savepoint).await {
+            Ok(()) => savepoint.commit().await?,
+            Err(e) => {
+                savepoint.rollback().await?;
+                warn!(
+                    "Failed to serialize alias {:?}: {:#?}. Continuing serialize.",
+                    alias, e
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores the aliases that are new and links them all to the inmate, in two round trips.
+async fn insert_aliases(
+    aliases: &[&str],
+    inmate_id: &i32,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), Error> {
+    // two possible routes for each alias -
     // -- insert alias, returning id
     // -- conflict on alias insert (duplicate), return existing alias id
-    let res = sqlx::query!(
+    let keys: Vec<String> = aliases.iter().map(|alias| alias_key(alias)).collect();
+    let ids: Vec<i32> = sqlx::query_scalar!(
         r#"
         INSERT INTO alias
-            (alias)
-        VALUES
-            ($1)
+            (alias, alias_key)
+        SELECT * FROM UNNEST($1::text[], $2::text[])
         ON CONFLICT (alias) DO UPDATE
             SET alias = EXCLUDED.alias
         RETURNING id
         "#,
-        alias
+        aliases as &[&str],
+        &keys
     )
-    .fetch_one(&mut **transaction)
+    .fetch_all(&mut **transaction)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO inmate_alias
+            (inmate_id, alias_id)
+        SELECT $1, * FROM UNNEST($2::integer[])
+        ON CONFLICT DO NOTHING
+        "#,
+        inmate_id,
+        &ids
+    )
+    .execute(&mut **transaction)
     .await?;
 
-    Ok(res.id)
+    Ok(())
 }
 
 async fn serialize_profile(
     profile: InmateProfile,
+    source_url: &str,
     transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    aws_s3_client: &Option<S3Client>,
+    object_store: &Option<Box<dyn ObjectStore>>,
+    policy: ImgStoragePolicy,
 ) -> Result<i32, Error> {
     //TODO: Can this have compile time checks with pgvectgor extension? It doesn't seem possible
     //currently.
 
-    // Pre-allocate the s3 url for the image
-    let has_s3_upload_criteria = has_s3_upload_criteria(&profile, &aws_s3_client);
-    let s3_img_url = if has_s3_upload_criteria {
-        profile.get_hash_on_core_attributes()
-    } else {
-        "".to_string()
-    };
-
-    // NOTE: We insert the inmate here assuming S3 upload success for one primary reason:
-    //     1) This insert will fail if the inmate is already in the database. In this case, we
-    //        don't want to overwrite potentially existing s3 img data (as the s3 keys will be the
-    //        same). This could cause unintended errors, and would be a waste of resources.
+    let has_img_upload_criteria = has_img_upload_criteria(&profile, object_store, policy);
+
+    // NOTE: The inmate's img_url stays empty until its img is in the object store. The upload
+    // worker sets it once the queued upload succeeds.
     // Note: Carefully manage timezones on insertion
     let row = sqlx::query(
         r#"
@@ -448,13 +839,13 @@ async fn serialize_profile(
     .bind(profile.sex)
     .bind(profile.dob)
     .bind(profile.arrest_agency)
-    .bind(profile.booking_date_iso8601)
+    .bind(&profile.booking_date_iso8601)
     .bind(profile.booking_number)
     .bind(profile.height)
     .bind(profile.weight)
     .bind(profile.race)
     .bind(profile.eye_color)
-    .bind(s3_img_url.clone())
+    .bind("")
     .bind(profile.scil_sys_id)
     .bind(profile.embedding)
     .fetch_one(&mut **transaction)
@@ -468,79 +859,97 @@ async fn serialize_profile(
         inmate_id
     );
 
-    // TODO: Now that we're confident we have a unique inmate, write img to s3
-    if has_s3_upload_criteria {
-        match s3_utils::upload_img_to_env_bucket_s3(
-            aws_s3_client.as_ref().unwrap(),
-            profile.img_blob.clone().unwrap(),
-            &s3_img_url,
-        )
-        .await
-        {
-            Ok(put_obj) => {
-                trace!("Image uploaded to S3 successfully: {:#?}", put_obj);
-            }
-            Err(e) => {
-                warn!("Failed to upload image to S3: {:#?}", e);
-
-                // we assumed s3 upload success, update the img_url to be empty
-                sqlx::query!(
-                    r#"
-                        UPDATE inmate
-                        SET img_url = ''
-                        WHERE id = $1
-                    "#,
-                    inmate_id
-                )
-                .execute(&mut **transaction)
+    // Now that we're confident we have a unique inmate, store the img once by content and queue
+    // its upload, unless an earlier booking with the same img already uploaded it
+    if let (Some(img_blob), Some(img_metadata)) = (&profile.img_blob, &profile.img_metadata) {
+        let (image_id, existing_img_url) =
+            serialize_image(&inmate_id, img_blob, img_metadata, transaction).await?;
+
+        if let Some(existing_img_url) = existing_img_url {
+            debug!("Image already in object store, reusing it: {:#?}", existing_img_url);
+            sqlx::query(
+                r#"
+                UPDATE inmate
+                SET img_url = $1
+                WHERE id = $2
+                "#,
+            )
+            .bind(existing_img_url)
+            .bind(inmate_id)
+            .execute(&mut **transaction)
+            .await?;
+        } else if has_img_upload_criteria {
+            let img_url = mugshot::content_key(&img_metadata.sha256);
+            enqueue_img_upload(&image_id, &inmate_id, &img_url, Some(source_url), transaction)
                 .await?;
-            }
         }
     }
 
-    // TODO: error handle failures on profile serialization that can be ignored? Letting
-    // core profile data pass and ignoring the rest?
-    for alias in profile.aliases.into_iter().flatten().unique() {
-        if alias.is_empty() {
-            continue;
-        }
+    let aliases = profile.aliases.unwrap_or_default();
+    link_aliases(&aliases, &inmate_id, transaction).await?;
+    debug!("Aliases serialized");
 
-        let alias_id = match serialize_alias(alias, transaction).await {
-            Ok(id) => id,
-            Err(e) => {
-                warn!("Failed to serialize alias: {:#?}. Continuing serialize.", e);
-                continue;
-            }
-        };
+    Ok(inmate_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_img_storage_policy() {
+        assert_eq!(
+            ImgStoragePolicy::from_value(None, true).unwrap(),
+            ImgStoragePolicy::ObjectStoreOnly
+        );
+        assert_eq!(
+            ImgStoragePolicy::from_value(None, false).unwrap(),
+            ImgStoragePolicy::DbOnly
+        );
+        assert_eq!(
+            ImgStoragePolicy::from_value(Some(" Both "), false).unwrap(),
+            ImgStoragePolicy::Both
+        );
+        assert_eq!(
+            ImgStoragePolicy::from_value(Some("db-only"), true).unwrap(),
+            ImgStoragePolicy::DbOnly
+        );
+        assert_eq!(
+            ImgStoragePolicy::from_value(Some("object-store"), true).unwrap(),
+            ImgStoragePolicy::ObjectStoreOnly
+        );
+        assert!(ImgStoragePolicy::from_value(Some("s3"), true).is_err());
+
+        assert!(!ImgStoragePolicy::DbOnly.uploads());
+        assert!(!ImgStoragePolicy::ObjectStoreOnly.keeps_db_blob());
+        assert!(ImgStoragePolicy::Both.uploads() && ImgStoragePolicy::Both.keeps_db_blob());
+    }
 
-        sqlx::query!(
+    #[tokio::test]
+    #[ignore = "needs Postgres, run with `TEST_DATABASE_URL=... cargo test -- --ignored`"]
+    async fn test_serialize_record_skips_bad_alias() {
+        let pool = crate::test_db::test_pool().await;
+        let suffix = crate::test_db::unique_suffix();
+        let mut record = crate::test_db::test_record(&suffix);
+        // Postgres can't store a NUL in text
+        let alias = format!("JOHNNY DOE{suffix}");
+        record.profile.aliases = Some(vec!["BAD\0ALIAS".to_string(), alias.clone()]);
+        let inmate_id = serialize_record(record, &pool, &None, ImgStoragePolicy::DbOnly)
+            .await
+            .unwrap();
+
+        let aliases: Vec<String> = sqlx::query_scalar(
             r#"
-            INSERT INTO inmate_alias
-            (inmate_id, alias_id)
-            VALUES
-            ($1, $2)
+            SELECT alias.alias
+            FROM inmate_alias
+            JOIN alias ON alias.id = inmate_alias.alias_id
+            WHERE inmate_alias.inmate_id = $1
             "#,
-            inmate_id,
-            alias_id
         )
-        .execute(&mut **transaction)
-        .await?;
+        .bind(inmate_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(aliases, vec![alias]);
     }
-    debug!("Aliases serialized");
-
-    sqlx::query!(
-        r#"
-        INSERT INTO img
-            (inmate_id, img)
-        VALUES
-            ($1, $2)
-        "#,
-        inmate_id,
-        profile.img_blob
-    )
-    .execute(&mut **transaction)
-    .await?;
-    debug!("Image serialized");
-
-    Ok(inmate_id)
 }
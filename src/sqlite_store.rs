@@ -0,0 +1,720 @@
+use async_trait::async_trait;
+use itertools::Itertools;
+use log::{debug, info, trace, warn};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow};
+use sqlx::{FromRow, Row};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use crate::alias::alias_key;
+use crate::diff::profile_from_columns;
+use crate::history::{BondVersion, ChargeVersion};
+use crate::inmate::{Bond, BondInformation, Charge, ChargeInformation, DbInmateProfile, Record};
+use crate::merge::{plan_merge, StoredBooking};
+use crate::store::RecordStore;
+use crate::upsert::{IdentityMatch, RecordChanges};
+use crate::{Error, SCOTT_COUNTY_INMATE_TRAVERSAL_ROOT};
+
+const SCHEMA: &str = include_str!("../queries/sqlite/schema.sql");
+
+type SqliteTransaction<'c> = sqlx::Transaction<'c, sqlx::Sqlite>;
+
+/// Records in a single SQLite file, images included. Bookings are merged the same way as in
+/// Postgres, but there's no booking history, people or object store.
+#[derive(Debug, Clone)]
+pub struct SqliteRecordStore {
+    pool: SqlitePool,
+}
+
+impl SqliteRecordStore {
+    /// Opens the database, creating the file if it doesn't exist. `sqlite::memory:` gives a
+    /// database that lives as long as the store.
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
+        // One connection: SQLite has a single writer anyway, and each connection to an in-memory
+        // database would get a database of its own
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await?;
+        Ok(SqliteRecordStore { pool })
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    async fn insert_record(
+        &self,
+        record: Record,
+        transaction: &mut SqliteTransaction<'_>,
+    ) -> Result<i32, Error> {
+        let profile = record.profile;
+        let inmate_id: i32 = sqlx::query_scalar(
+            r#"
+            INSERT INTO inmate
+            (
+                first_name, middle_name, last_name, affix, permanent_id, sex, dob,
+                arresting_agency, booking_date, booking_number, height, weight, race, eye_color,
+                scil_sysid, embedding
+            )
+            VALUES
+            (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
+            RETURNING id
+            "#,
+        )
+        .bind(&profile.first_name)
+        .bind(&profile.middle_name)
+        .bind(&profile.last_name)
+        .bind(&profile.affix)
+        .bind(&profile.perm_id)
+        .bind(&profile.sex)
+        .bind(&profile.dob)
+        .bind(&profile.arrest_agency)
+        .bind(&profile.booking_date_iso8601)
+        .bind(&profile.booking_number)
+        .bind(&profile.height)
+        .bind(&profile.weight)
+        .bind(&profile.race)
+        .bind(&profile.eye_color)
+        .bind(&profile.scil_sys_id)
+        .bind(profile.embedding.as_deref().map(embedding_to_blob))
+        .fetch_one(&mut **transaction)
+        .await?;
+
+        if let Some(img_blob) = profile.img_blob.as_ref().filter(|blob| !blob.is_empty()) {
+            sqlx::query("INSERT INTO img (inmate_id, img) VALUES (?1, ?2)")
+                .bind(inmate_id)
+                .bind(img_blob)
+                .execute(&mut **transaction)
+                .await?;
+        }
+        link_aliases(&profile.aliases.unwrap_or_default(), inmate_id, transaction).await?;
+        insert_bonds(&record.bond.bonds, inmate_id, transaction).await?;
+        insert_charges(&record.charges.charges, inmate_id, transaction).await?;
+
+        debug!("Inserted inmate {} into SQLite", inmate_id);
+        Ok(inmate_id)
+    }
+
+    /// Loads the records of the profile rows, in the rows' order.
+    async fn load_records(&self, rows: Vec<SqliteRow>) -> Result<Vec<Record>, Error> {
+        let mut records = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            let db_profile = DbInmateProfile::from_row(row)?;
+            let id = db_profile.id;
+            let mut profile = db_profile.profile;
+
+            let aliases: Vec<String> = sqlx::query_scalar(
+                r#"
+                SELECT alias.alias
+                FROM inmate_alias
+                JOIN alias ON alias.id = inmate_alias.alias_id
+                WHERE inmate_alias.inmate_id = ?1
+                ORDER BY alias.alias
+                "#,
+            )
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await?;
+            profile.aliases = (!aliases.is_empty()).then_some(aliases);
+            profile.embedding = row
+                .try_get::<Option<Vec<u8>>, _>("embedding")?
+                .map(|blob| embedding_from_blob(&blob));
+
+            let bonds: Vec<Bond> = sqlx::query_as(
+                "SELECT type, amount_pennies, amount_unparseable, amount_not_set FROM bond WHERE inmate_id = ?1 ORDER BY id",
+            )
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await?;
+            let charges: Vec<Charge> = sqlx::query_as(
+                "SELECT description, grade, offense_date FROM charge WHERE inmate_id = ?1 ORDER BY id",
+            )
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await?;
+
+            records.push(Record {
+                url: format!(
+                    "{}{}",
+                    SCOTT_COUNTY_INMATE_TRAVERSAL_ROOT,
+                    profile.scil_sys_id.as_deref().unwrap_or_default()
+                ),
+                profile,
+                bond: BondInformation { bonds },
+                charges: ChargeInformation { charges },
+            });
+        }
+        Ok(records)
+    }
+}
+
+#[async_trait]
+impl RecordStore for SqliteRecordStore {
+    async fn migrate(&self) -> Result<(), Error> {
+        sqlx::raw_sql(SCHEMA).execute(&self.pool).await?;
+        // CREATE TABLE IF NOT EXISTS leaves files created before a column without it
+        add_missing_column(
+            &self.pool,
+            "bond",
+            "amount_not_set",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn upsert_record(&self, record: Record) -> Result<RecordChanges, Error> {
+        trace!("Upserting record into SQLite: {:#?}", record);
+        let mut transaction = self.pool.begin().await?;
+
+        let sys_id = record
+            .profile
+            .scil_sys_id
+            .as_deref()
+            .filter(|id| !id.is_empty());
+        let booking_number = record
+            .profile
+            .booking_number
+            .as_deref()
+            .filter(|n| !n.is_empty());
+        let existing = sqlx::query(
+            r#"
+            SELECT id, first_name, middle_name, last_name, affix, permanent_id, sex, dob,
+                arresting_agency, booking_date, booking_number, height, weight, race, eye_color,
+                scil_sysid,
+                COALESCE(scil_sysid = ?5, 0) AS sys_id_matched,
+                COALESCE(booking_number = ?6, 0) AS booking_number_matched
+            FROM inmate
+            WHERE scil_sysid = ?5
+                OR booking_number = ?6
+                OR (
+                    first_name = ?1 AND last_name = ?2 AND dob = ?3 AND booking_date = ?4
+                    AND (NULLIF(scil_sysid, '') IS NULL OR ?5 IS NULL OR scil_sysid = ?5)
+                    AND (NULLIF(booking_number, '') IS NULL OR ?6 IS NULL OR booking_number = ?6)
+                )
+            ORDER BY sys_id_matched DESC, booking_number_matched DESC, id DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(&record.profile.first_name)
+        .bind(&record.profile.last_name)
+        .bind(&record.profile.dob)
+        .bind(&record.profile.booking_date_iso8601)
+        .bind(sys_id)
+        .bind(booking_number)
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        let Some(existing) = existing else {
+            let inmate_id = self.insert_record(record, &mut transaction).await?;
+            transaction.commit().await?;
+            return Ok(RecordChanges {
+                inmate_id,
+                created: true,
+                ..Default::default()
+            });
+        };
+
+        let matched_by = if existing.try_get::<i64, _>("sys_id_matched")? != 0 {
+            IdentityMatch::SysId
+        } else if existing.try_get::<i64, _>("booking_number_matched")? != 0 {
+            IdentityMatch::BookingNumber
+        } else {
+            IdentityMatch::CoreAttributes
+        };
+        let stored = load_stored_booking(&existing, matched_by, &mut transaction).await?;
+        let inmate_id = stored.inmate_id;
+        let plan = plan_merge(&stored, &record);
+        let mut changes = plan.changes.clone();
+
+        // There's no superseded_by here, so the duplicate gives up the booking number instead
+        if let Some(booking_number) = plan.takes_booking_number.as_deref() {
+            changes.superseded = sqlx::query_scalar(
+                r#"
+                UPDATE inmate
+                SET booking_number = NULL
+                WHERE booking_number = ?1 AND id <> ?2
+                RETURNING id
+                "#,
+            )
+            .bind(booking_number)
+            .bind(inmate_id)
+            .fetch_optional(&mut *transaction)
+            .await?;
+            if let Some(superseded) = changes.superseded {
+                warn!(
+                    "Inmate {} took over booking number {} from inmate {}, clearing it there",
+                    inmate_id, booking_number, superseded
+                );
+            }
+        }
+
+        for change in plan.changes.fields.iter() {
+            // field is one of the column names above, never input
+            sqlx::query(&format!(
+                "UPDATE inmate SET {} = ?1 WHERE id = ?2",
+                change.field
+            ))
+            .bind(&change.new)
+            .bind(inmate_id)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        link_aliases(&plan.changes.aliases_added, inmate_id, &mut transaction).await?;
+        for (id, _) in plan.charges_to_delete.iter() {
+            sqlx::query("DELETE FROM charge WHERE id = ?1")
+                .bind(id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        insert_charges(&plan.charges_to_insert, inmate_id, &mut transaction).await?;
+        for (id, _) in plan.bonds_to_delete.iter() {
+            sqlx::query("DELETE FROM bond WHERE id = ?1")
+                .bind(id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        insert_bonds(&plan.bonds_to_insert, inmate_id, &mut transaction).await?;
+
+        transaction.commit().await?;
+        if !changes.is_unchanged() {
+            info!(
+                "Merged re-crawled record into inmate {}: {:?}",
+                inmate_id, changes
+            );
+        }
+        Ok(changes)
+    }
+
+    async fn blacklist_and_updatelist(
+        &self,
+        n: i64,
+    ) -> Result<(HashSet<String>, HashMap<String, i32>), Error> {
+        let mut blacklist = HashSet::new();
+        let mut updatelist = HashMap::new();
+        let rows = sqlx::query(
+            r#"
+            SELECT id, scil_sysid, EXISTS (SELECT 1 FROM img WHERE img.inmate_id = inmate.id) AS has_img
+            FROM inmate
+            ORDER BY id DESC
+            LIMIT ?1
+            "#,
+        )
+        .bind(n)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for row in rows {
+            let id: i32 = row.try_get("id")?;
+            match row.try_get::<Option<String>, _>("scil_sysid")? {
+                Some(sys_id) if row.try_get::<i64, _>("has_img")? == 0 => {
+                    updatelist.insert(sys_id, id);
+                }
+                Some(sys_id) => {
+                    blacklist.insert(sys_id);
+                }
+                None => warn!("Found a record with no sys_id. Inmate id: {}", id),
+            }
+        }
+
+        Ok((blacklist, updatelist))
+    }
+
+    async fn update_null_img_records(&self, records: Vec<(i32, Record)>) -> Result<(), Error> {
+        let mut updated_count = 0;
+        for (inmate_id, record) in records {
+            let Some(img_blob) = record.profile.img_blob.filter(|blob| !blob.is_empty()) else {
+                warn!(
+                    "Latest parse of inmate {} still has no img. Skipping update.",
+                    inmate_id
+                );
+                continue;
+            };
+            let res = sqlx::query("INSERT OR IGNORE INTO img (inmate_id, img) VALUES (?1, ?2)")
+                .bind(inmate_id)
+                .bind(img_blob)
+                .execute(&self.pool)
+                .await?;
+            updated_count += res.rows_affected();
+        }
+        info!("Updated {} null img records", updated_count);
+        Ok(())
+    }
+
+    async fn load_record(&self, inmate_id: i32) -> Result<Option<Record>, Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT inmate.*, NULL AS aliases, img.img
+            FROM inmate
+            LEFT JOIN img ON img.inmate_id = inmate.id
+            WHERE inmate.id = ?1
+            "#,
+        )
+        .bind(inmate_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(self.load_records(rows).await?.pop())
+    }
+
+    async fn load_record_by_sys_id(&self, sys_id: &str) -> Result<Option<Record>, Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT inmate.*, NULL AS aliases, img.img
+            FROM inmate
+            LEFT JOIN img ON img.inmate_id = inmate.id
+            WHERE inmate.scil_sysid = ?1
+            "#,
+        )
+        .bind(sys_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(self.load_records(rows).await?.pop())
+    }
+}
+
+/// Links the aliases to the inmate, storing the ones that are new.
+async fn link_aliases(
+    aliases: &[String],
+    inmate_id: i32,
+    transaction: &mut SqliteTransaction<'_>,
+) -> Result<(), Error> {
+    let aliases: Vec<&String> = aliases
+        .iter()
+        .filter(|alias| !alias.is_empty())
+        .unique()
+        .collect();
+    for alias in aliases {
+        let alias_id: i32 = sqlx::query_scalar(
+            r#"
+            INSERT INTO alias (alias, alias_key)
+            VALUES (?1, ?2)
+            ON CONFLICT (alias) DO UPDATE SET alias = excluded.alias
+            RETURNING id
+            "#,
+        )
+        .bind(alias)
+        .bind(alias_key(alias))
+        .fetch_one(&mut **transaction)
+        .await?;
+
+        sqlx::query("INSERT OR IGNORE INTO inmate_alias (inmate_id, alias_id) VALUES (?1, ?2)")
+            .bind(inmate_id)
+            .bind(alias_id)
+            .execute(&mut **transaction)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Adds a column to a table unless it already has it.
+async fn add_missing_column(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), Error> {
+    let exists: bool =
+        sqlx::query_scalar("SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2")
+            .bind(table)
+            .bind(column)
+            .fetch_one(pool)
+            .await?;
+    if !exists {
+        debug!("Adding column {}.{}", table, column);
+        sqlx::raw_sql(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn insert_bonds(
+    bonds: &[Bond],
+    inmate_id: i32,
+    transaction: &mut SqliteTransaction<'_>,
+) -> Result<(), Error> {
+    for bond in bonds {
+        sqlx::query(
+            "INSERT INTO bond (inmate_id, type, amount_pennies, amount_unparseable, amount_not_set) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(inmate_id)
+        .bind(&bond.bond_type)
+        .bind(i64::try_from(bond.bond_amount)?)
+        .bind(bond.amount_unparseable)
+        .bind(bond.amount_not_set)
+        .execute(&mut **transaction)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn insert_charges(
+    charges: &[Charge],
+    inmate_id: i32,
+    transaction: &mut SqliteTransaction<'_>,
+) -> Result<(), Error> {
+    for charge in charges {
+        sqlx::query(
+            "INSERT INTO charge (inmate_id, description, grade, offense_date) VALUES (?1, ?2, ?3, ?4)",
+        )
+        .bind(inmate_id)
+        .bind(&charge.description)
+        .bind(charge.grade.to_string())
+        .bind(&charge.offense_date)
+        .execute(&mut **transaction)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Loads what [`plan_merge`] needs of the booking in `existing`, the inmate row a record matched.
+async fn load_stored_booking(
+    existing: &SqliteRow,
+    matched_by: IdentityMatch,
+    transaction: &mut SqliteTransaction<'_>,
+) -> Result<StoredBooking<i64>, Error> {
+    let inmate_id: i32 = existing.try_get("id")?;
+    let mut profile = profile_from_columns(|column| Ok(existing.try_get(column)?))?;
+    let aliases: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT alias.alias
+        FROM inmate_alias
+        JOIN alias ON alias.id = inmate_alias.alias_id
+        WHERE inmate_alias.inmate_id = ?1
+        "#,
+    )
+    .bind(inmate_id)
+    .fetch_all(&mut **transaction)
+    .await?;
+    profile.aliases = Some(aliases);
+
+    let rows = sqlx::query(
+        "SELECT id, description, grade, offense_date FROM charge WHERE inmate_id = ?1 ORDER BY id",
+    )
+    .bind(inmate_id)
+    .fetch_all(&mut **transaction)
+    .await?;
+    let mut charges = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        let charge = ChargeVersion {
+            description: row.try_get("description")?,
+            grade: row.try_get("grade")?,
+            offense_date: row.try_get("offense_date")?,
+        };
+        charges.push((row.try_get("id")?, charge));
+    }
+
+    let rows = sqlx::query(
+        "SELECT id, type, amount_pennies, amount_unparseable, amount_not_set FROM bond WHERE inmate_id = ?1 ORDER BY id",
+    )
+    .bind(inmate_id)
+    .fetch_all(&mut **transaction)
+    .await?;
+    let mut bonds = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        bonds.push((row.try_get("id")?, BondVersion::from(&Bond::from_row(row)?)));
+    }
+
+    Ok(StoredBooking {
+        inmate_id,
+        matched_by,
+        profile,
+        charges,
+        bonds,
+    })
+}
+
+/// Stores the embedding as little-endian f32s.
+fn embedding_to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+fn embedding_from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inmate::{ChargeGrade, InmateProfile};
+    use crate::utils::Money;
+
+    fn record(first_name: &str) -> Record {
+        Record {
+            url: String::new(),
+            profile: InmateProfile {
+                first_name: first_name.to_string(),
+                last_name: "DOE".to_string(),
+                dob: "01/02/1990".to_string(),
+                booking_date_iso8601: "10/01/2024 08:15".to_string(),
+                booking_number: Some("2024-001".to_string()),
+                scil_sys_id: Some("?sysid=abc".to_string()),
+                aliases: Some(vec!["JOHNNY DOE".to_string()]),
+                embedding: Some(vec![0.5, -1.25]),
+                ..Default::default()
+            },
+            bond: BondInformation {
+                bonds: vec![Bond {
+                    bond_type: "Cash Only".to_string(),
+                    bond_amount: Money::from_cents(50_000),
+                    amount_unparseable: false,
+                    amount_not_set: false,
+                }],
+            },
+            charges: ChargeInformation {
+                charges: vec![Charge {
+                    description: "THEFT".to_string(),
+                    grade: ChargeGrade::Misdemeanor,
+                    offense_date: "10/01/2024".to_string(),
+                }],
+            },
+        }
+    }
+
+    async fn store() -> SqliteRecordStore {
+        let store = SqliteRecordStore::connect("sqlite::memory:").await.unwrap();
+        store.migrate().await.unwrap();
+        store.migrate().await.unwrap();
+        store
+    }
+
+    #[test]
+    fn test_embedding_blob_round_trip() {
+        let embedding = vec![0.0, 1.5, -2.25, f32::MAX];
+        assert_eq!(
+            embedding_from_blob(&embedding_to_blob(&embedding)),
+            embedding
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upsert_and_load() {
+        let store = store().await;
+        let changes = store.upsert_record(record("JOHN")).await.unwrap();
+        assert!(changes.created);
+
+        let loaded = store.load_record(changes.inmate_id).await.unwrap().unwrap();
+        assert!(loaded.diff(&record("JOHN")).is_empty());
+        assert_eq!(loaded.profile.embedding, Some(vec![0.5, -1.25]));
+        assert!(store
+            .load_record(changes.inmate_id + 1)
+            .await
+            .unwrap()
+            .is_none());
+
+        let unchanged = store.upsert_record(record("JOHN")).await.unwrap();
+        assert_eq!(unchanged.inmate_id, changes.inmate_id);
+        assert!(unchanged.is_unchanged());
+    }
+
+    #[tokio::test]
+    async fn test_upsert_merges_corrected_name() {
+        let store = store().await;
+        let inmate_id = store.upsert_record(record("JON")).await.unwrap().inmate_id;
+
+        let mut corrected = record("JOHN");
+        corrected.charges.charges[0].grade = ChargeGrade::Felony;
+        let changes = store.upsert_record(corrected).await.unwrap();
+        assert_eq!(changes.inmate_id, inmate_id);
+        assert_eq!(changes.matched_by, Some(IdentityMatch::SysId));
+        assert_eq!(changes.fields.len(), 1);
+        assert_eq!(changes.aliases_added, vec!["JON DOE"]);
+        assert_eq!(changes.charges_removed.len(), 1);
+        assert_eq!(changes.charges_added.len(), 1);
+
+        let loaded = store
+            .load_record_by_sys_id("?sysid=abc")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.profile.first_name, "JOHN");
+        assert_eq!(
+            loaded.profile.aliases,
+            Some(vec!["JOHNNY DOE".to_string(), "JON DOE".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upsert_never_merges_other_ids() {
+        let store = store().await;
+        store.upsert_record(record("JOHN")).await.unwrap();
+
+        // Same name, dob and booking date, but stored under other ids
+        let mut other = record("JOHN");
+        other.profile.scil_sys_id = Some("?sysid=def".to_string());
+        other.profile.booking_number = Some("2024-002".to_string());
+        assert!(store.upsert_record(other).await.is_err());
+        let loaded = store
+            .load_record_by_sys_id("?sysid=abc")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.profile.booking_number.as_deref(), Some("2024-001"));
+    }
+
+    #[tokio::test]
+    async fn test_upsert_takes_over_booking_number() {
+        let store = store().await;
+        let inmate_id = store.upsert_record(record("JOHN")).await.unwrap().inmate_id;
+        let mut duplicate = record("JON");
+        duplicate.profile.scil_sys_id = Some("?sysid=def".to_string());
+        duplicate.profile.booking_number = Some("2024-002".to_string());
+        let duplicate_id = store.upsert_record(duplicate).await.unwrap().inmate_id;
+
+        // The booking matched by sys_id takes the duplicate's booking number over
+        let mut renumbered = record("JOHN");
+        renumbered.profile.booking_number = Some("2024-002".to_string());
+        let changes = store.upsert_record(renumbered).await.unwrap();
+        assert_eq!(changes.inmate_id, inmate_id);
+        assert_eq!(changes.superseded, Some(duplicate_id));
+
+        let loaded = store.load_record(inmate_id).await.unwrap().unwrap();
+        assert_eq!(loaded.profile.booking_number.as_deref(), Some("2024-002"));
+        let duplicate = store.load_record(duplicate_id).await.unwrap().unwrap();
+        assert_eq!(duplicate.profile.booking_number, None);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_adds_missing_bond_column() {
+        let store = SqliteRecordStore::connect("sqlite::memory:").await.unwrap();
+        sqlx::raw_sql(
+            "CREATE TABLE bond (id INTEGER PRIMARY KEY AUTOINCREMENT, inmate_id INTEGER NOT NULL, \
+            type TEXT, amount_pennies INTEGER NOT NULL, amount_unparseable INTEGER NOT NULL DEFAULT 0)",
+        )
+        .execute(store.pool())
+        .await
+        .unwrap();
+        store.migrate().await.unwrap();
+
+        let mut not_set = record("JOHN");
+        not_set.bond.bonds[0].bond_amount = Money::ZERO;
+        not_set.bond.bonds[0].amount_not_set = true;
+        let inmate_id = store.upsert_record(not_set).await.unwrap().inmate_id;
+        let loaded = store.load_record(inmate_id).await.unwrap().unwrap();
+        assert!(loaded.bond.bonds[0].amount_not_set);
+        assert_eq!(loaded.bond.get_total_bond_description(), "no amount set");
+    }
+
+    #[tokio::test]
+    async fn test_blacklist_and_updatelist() {
+        let store = store().await;
+        let inmate_id = store.upsert_record(record("JOHN")).await.unwrap().inmate_id;
+        let (blacklist, updatelist) = store.blacklist_and_updatelist(10).await.unwrap();
+        assert!(blacklist.is_empty());
+        assert_eq!(updatelist.get("?sysid=abc"), Some(&inmate_id));
+    }
+}
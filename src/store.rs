@@ -0,0 +1,703 @@
+use async_openai::config::OpenAIConfig;
+use async_openai::Client;
+use async_trait::async_trait;
+use itertools::Itertools;
+use log::{info, warn};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::history::{BondVersion, ChargeVersion};
+use crate::inmate::{BondInformation, ChargeInformation, InmateProfile, Record};
+use crate::merge::{plan_merge, StoredBooking};
+use crate::object_store::ObjectStore;
+use crate::serialize::{gather_embedding, serialize_records, SerializeOptions};
+use crate::sqlite_store::SqliteRecordStore;
+use crate::upload_queue::{drain_img_upload_queue, DrainOptions};
+use crate::upsert::{IdentityMatch, RecordChanges};
+use crate::Error;
+
+/// Where crawled records are stored. [`PgRecordStore`] is the production backend;
+/// [`SqliteRecordStore`] keeps everything in a single file for hobbyist and test setups.
+#[async_trait]
+pub trait RecordStore: Send + Sync + std::fmt::Debug {
+    /// Brings the schema up to date.
+    async fn migrate(&self) -> Result<(), Error>;
+
+    /// Inserts the record, or merges it into its existing booking. Bookings are matched by
+    /// sys_id, then booking number, then name, dob and booking date.
+    async fn upsert_record(&self, record: Record) -> Result<RecordChanges, Error>;
+
+    /// Upserts the records, logging and skipping the ones that fail. Returns the changes made by
+    /// each record that was stored.
+    async fn upsert_records(&self, records: Vec<Record>) -> Result<Vec<RecordChanges>, Error> {
+        let mut all_changes = Vec::with_capacity(records.len());
+        for record in records {
+            match self.upsert_record(record).await {
+                Ok(changes) => all_changes.push(changes),
+                Err(e) => warn!("Failed to store record. Error: {:#?}", e),
+            }
+        }
+        Ok(all_changes)
+    }
+
+    /// Returns (sys_ids of the last `n` bookings that are complete, sys_ids of the ones still
+    /// missing an image with their inmate ids), see [`crate::utils::get_blacklist_and_updatelist`].
+    async fn blacklist_and_updatelist(
+        &self,
+        n: i64,
+    ) -> Result<(HashSet<String>, HashMap<String, i32>), Error>;
+
+    /// Stores the images of bookings that were stored without one.
+    async fn update_null_img_records(&self, records: Vec<(i32, Record)>) -> Result<(), Error>;
+
+    /// Finishes work queued while storing records, e.g. image uploads.
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Loads the record of an inmate by id. Returns None if there's no such inmate.
+    async fn load_record(&self, inmate_id: i32) -> Result<Option<Record>, Error>;
+
+    /// Loads the booking with the sys_id, e.g. "?sysid=...". Returns None if there's none.
+    async fn load_record_by_sys_id(&self, sys_id: &str) -> Result<Option<Record>, Error>;
+}
+
+/// Connects to the store for the URL: SQLite for `sqlite:` URLs, e.g. `sqlite://crawler.db`,
+/// and Postgres otherwise. Only Postgres uses the object store.
+pub async fn record_store_from_url(
+    url: &str,
+    object_store: Option<Box<dyn ObjectStore>>,
+    options: SerializeOptions,
+) -> Result<Box<dyn RecordStore>, Error> {
+    if url.starts_with("sqlite:") {
+        info!("Using the SQLite record store");
+        return Ok(Box::new(SqliteRecordStore::connect(url).await?));
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect(url)
+        .await
+        .map_err(|e| {
+            Error::InternalError(format!("Failed to connect to database: {}. e: {}", url, e))
+        })?;
+    Ok(Box::new(PgRecordStore::new(pool, object_store, options)))
+}
+
+/// Gathers missing embeddings, if there's an OpenAI client, then upserts the records.
+pub async fn store_records(
+    store: &dyn RecordStore,
+    mut records: Vec<Record>,
+    oai_client: &Option<Client<OpenAIConfig>>,
+) -> Result<Vec<RecordChanges>, Error> {
+    if let Some(oai_client) = oai_client {
+        for record in records.iter_mut() {
+            gather_embedding(record, oai_client).await;
+        }
+    }
+    store.upsert_records(records).await
+}
+
+/// Records in Postgres, with images in the object store.
+#[derive(Debug)]
+pub struct PgRecordStore {
+    pool: PgPool,
+    object_store: Option<Box<dyn ObjectStore>>,
+    options: SerializeOptions,
+}
+
+impl PgRecordStore {
+    pub fn new(
+        pool: PgPool,
+        object_store: Option<Box<dyn ObjectStore>>,
+        options: SerializeOptions,
+    ) -> Self {
+        PgRecordStore {
+            pool,
+            object_store,
+            options,
+        }
+    }
+
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+}
+
+#[async_trait]
+impl RecordStore for PgRecordStore {
+    async fn migrate(&self) -> Result<(), Error> {
+        let applied = crate::migrations::migrate(&self.pool).await?;
+        if !applied.is_empty() {
+            info!("Applied migrations {:?}", applied);
+        }
+        Ok(())
+    }
+
+    async fn upsert_record(&self, record: Record) -> Result<RecordChanges, Error> {
+        crate::upsert::upsert_record(
+            record,
+            &self.pool,
+            &self.object_store,
+            self.options.img_storage_policy,
+        )
+        .await
+    }
+
+    /// Serializes the records in batches, then links new bookings to people.
+    async fn upsert_records(&self, records: Vec<Record>) -> Result<Vec<RecordChanges>, Error> {
+        // Embeddings are gathered by the caller, see store_records
+        let changes = serialize_records::<_, OpenAIConfig>(
+            records,
+            &self.pool,
+            &None,
+            &self.object_store,
+            &self.options,
+        )
+        .await?;
+        if let Err(e) = crate::person::link_unresolved_bookings(&self.pool).await {
+            warn!("Failed to link bookings to people: {:?}", e);
+        }
+        Ok(changes)
+    }
+
+    async fn blacklist_and_updatelist(
+        &self,
+        n: i64,
+    ) -> Result<(HashSet<String>, HashMap<String, i32>), Error> {
+        crate::utils::get_blacklist_and_updatelist(
+            n,
+            DrainOptions::from_env()?.max_attempts,
+            self.options.img_storage_policy,
+            &self.pool,
+        )
+        .await
+    }
+
+    async fn update_null_img_records(&self, records: Vec<(i32, Record)>) -> Result<(), Error> {
+        crate::serialize::update_null_img_records(
+            records,
+            &self.pool,
+            &self.object_store,
+            self.options.img_storage_policy,
+        )
+        .await
+    }
+
+    /// Drains what's due in the img upload queue, so a lone crawler still uploads. Anything left
+    /// over is retried by img_upload_worker or the next crawl.
+    async fn flush(&self) -> Result<(), Error> {
+        if let Some(object_store) = self.object_store.as_deref() {
+            info!("Draining img upload queue...");
+            let options = DrainOptions {
+                img_storage_policy: self.options.img_storage_policy,
+                ..DrainOptions::from_env()?
+            };
+            drain_img_upload_queue(&self.pool, object_store, &options).await?;
+        }
+        Ok(())
+    }
+
+    async fn load_record(&self, inmate_id: i32) -> Result<Option<Record>, Error> {
+        Ok(crate::records::load_record(&self.pool, inmate_id)
+            .await?
+            .map(|db_record| db_record.record))
+    }
+
+    async fn load_record_by_sys_id(&self, sys_id: &str) -> Result<Option<Record>, Error> {
+        Ok(crate::records::load_record_by_sys_id(&self.pool, sys_id)
+            .await?
+            .map(|db_record| db_record.record))
+    }
+}
+
+/// Records in memory, for tests and throwaway runs. Bookings are unique by sys_id, by booking
+/// number, and by name, dob and booking date, as in the database, and are merged the same way.
+#[derive(Debug, Default)]
+pub struct InMemoryRecordStore {
+    bookings: Mutex<Bookings>,
+}
+
+/// Every stored booking by inmate id, and the ones set aside for the booking that took over their
+/// booking number, like `inmate.superseded_by`.
+#[derive(Debug, Default)]
+struct Bookings {
+    records: BTreeMap<i32, Record>,
+    superseded_by: BTreeMap<i32, i32>,
+}
+
+impl Bookings {
+    /// The bookings that aren't superseded, in id order.
+    fn active(&self) -> impl DoubleEndedIterator<Item = (&i32, &Record)> {
+        self.records
+            .iter()
+            .filter(|(id, _)| !self.superseded_by.contains_key(id))
+    }
+}
+
+impl InMemoryRecordStore {
+    pub fn new() -> InMemoryRecordStore {
+        InMemoryRecordStore::default()
+    }
+
+    /// Returns every stored booking with its inmate id, in id order.
+    pub fn records(&self) -> Vec<(i32, Record)> {
+        self.bookings()
+            .records
+            .iter()
+            .map(|(id, record)| (*id, record.clone()))
+            .collect()
+    }
+
+    fn bookings(&self) -> std::sync::MutexGuard<'_, Bookings> {
+        self.bookings
+            .lock()
+            .expect("Expect in-memory record store lock to not be poisoned")
+    }
+}
+
+#[async_trait]
+impl RecordStore for InMemoryRecordStore {
+    async fn migrate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn upsert_record(&self, record: Record) -> Result<RecordChanges, Error> {
+        let mut bookings = self.bookings();
+        let Some((inmate_id, matched_by)) = find_booking(&bookings, &record.profile) else {
+            check_unique(&bookings, None, &record.profile)?;
+            let inmate_id = bookings.records.keys().next_back().map_or(1, |id| id + 1);
+            bookings.records.insert(inmate_id, record);
+            return Ok(RecordChanges {
+                inmate_id,
+                created: true,
+                ..Default::default()
+            });
+        };
+
+        let (merged, mut changes, takes_booking_number) =
+            merge_booking(&bookings.records[&inmate_id], record, inmate_id, matched_by);
+        // Like Postgres, the booking that held the booking number is superseded by this one
+        if let Some(booking_number) = takes_booking_number {
+            changes.superseded = bookings
+                .active()
+                .find(|(id, record)| {
+                    **id != inmate_id
+                        && record.profile.booking_number.as_ref() == Some(&booking_number)
+                })
+                .map(|(id, _)| *id);
+            if let Some(superseded) = changes.superseded {
+                warn!(
+                    "Inmate {} took over booking number {} from inmate {}, marking it superseded",
+                    inmate_id, booking_number, superseded
+                );
+                bookings.superseded_by.insert(superseded, inmate_id);
+            }
+        }
+        check_unique(&bookings, Some(inmate_id), &merged.profile)?;
+        bookings.records.insert(inmate_id, merged);
+        Ok(changes)
+    }
+
+    async fn blacklist_and_updatelist(
+        &self,
+        n: i64,
+    ) -> Result<(HashSet<String>, HashMap<String, i32>), Error> {
+        let mut blacklist = HashSet::new();
+        let mut updatelist = HashMap::new();
+        let n = usize::try_from(n).map_err(|_| Error::ArgumentError)?;
+        for (id, record) in self.bookings().records.iter().rev().take(n) {
+            match (&record.profile.scil_sys_id, &record.profile.img_blob) {
+                (Some(sys_id), None) => {
+                    updatelist.insert(sys_id.clone(), *id);
+                }
+                (Some(sys_id), Some(_)) => {
+                    blacklist.insert(sys_id.clone());
+                }
+                (None, _) => warn!("Found a record with no sys_id. Inmate id: {}", id),
+            }
+        }
+        Ok((blacklist, updatelist))
+    }
+
+    async fn update_null_img_records(&self, records: Vec<(i32, Record)>) -> Result<(), Error> {
+        let mut bookings = self.bookings();
+        for (inmate_id, record) in records {
+            let Some(img_blob) = record.profile.img_blob.filter(|blob| !blob.is_empty()) else {
+                warn!(
+                    "Latest parse of inmate {} still has no img. Skipping update.",
+                    inmate_id
+                );
+                continue;
+            };
+            match bookings.records.get_mut(&inmate_id) {
+                Some(stored) if stored.profile.img_blob.is_none() => {
+                    stored.profile.img_blob = Some(img_blob);
+                }
+                Some(_) => (),
+                None => warn!("No inmate {} to update the img of", inmate_id),
+            }
+        }
+        Ok(())
+    }
+
+    async fn load_record(&self, inmate_id: i32) -> Result<Option<Record>, Error> {
+        Ok(self.bookings().records.get(&inmate_id).cloned())
+    }
+
+    async fn load_record_by_sys_id(&self, sys_id: &str) -> Result<Option<Record>, Error> {
+        Ok(self
+            .bookings()
+            .active()
+            .map(|(_, record)| record)
+            .find(|record| record.profile.scil_sys_id.as_deref() == Some(sys_id))
+            .cloned())
+    }
+}
+
+/// The keys a booking is identified by, strongest first: sys_id, booking number, then name, dob
+/// and booking date. Empty ids don't identify anything.
+fn identity_keys(profile: &InmateProfile) -> [Option<String>; 3] {
+    let non_empty = |id: &Option<String>| id.clone().filter(|id| !id.is_empty());
+    [
+        non_empty(&profile.scil_sys_id),
+        non_empty(&profile.booking_number),
+        Some(
+            [
+                &profile.first_name,
+                &profile.last_name,
+                &profile.dob,
+                &profile.booking_date_iso8601,
+            ]
+            .iter()
+            .join("\u{1f}"),
+        ),
+    ]
+}
+
+/// Finds the active booking a record belongs to, and what it matched by. Each identity key is
+/// only compared with the same key of other bookings, and name, dob and booking date never match
+/// a booking stored under another sys_id or booking number.
+fn find_booking(bookings: &Bookings, profile: &InmateProfile) -> Option<(i32, IdentityMatch)> {
+    let matches = [
+        IdentityMatch::SysId,
+        IdentityMatch::BookingNumber,
+        IdentityMatch::CoreAttributes,
+    ];
+    let keys = identity_keys(profile);
+    (0..keys.len()).find_map(|idx| {
+        bookings
+            .active()
+            .rev()
+            .find(|(_, record)| matches_key(&keys, &identity_keys(&record.profile), idx))
+            .map(|(id, _)| (*id, matches[idx]))
+    })
+}
+
+/// Returns true if a record with `keys` matches a booking with `other_keys` by the key at `idx`.
+/// Matching by name, dob and booking date also needs the ids to agree where both have them.
+fn matches_key(keys: &[Option<String>; 3], other_keys: &[Option<String>; 3], idx: usize) -> bool {
+    let agree = |idx: usize| {
+        keys[idx].is_none() || other_keys[idx].is_none() || keys[idx] == other_keys[idx]
+    };
+    keys[idx].is_some() && keys[idx] == other_keys[idx] && (idx < 2 || (agree(0) && agree(1)))
+}
+
+/// Errors if another active booking than `inmate_id` already has one of the profile's identity
+/// keys, like the database's unique indexes.
+fn check_unique(
+    bookings: &Bookings,
+    inmate_id: Option<i32>,
+    profile: &InmateProfile,
+) -> Result<(), Error> {
+    let keys = identity_keys(profile);
+    for (id, record) in bookings.active() {
+        if Some(*id) == inmate_id {
+            continue;
+        }
+        let other_keys = identity_keys(&record.profile);
+        if let Some(idx) =
+            (0..keys.len()).find(|&idx| keys[idx].is_some() && keys[idx] == other_keys[idx])
+        {
+            let key_name = ["sys_id", "booking number", "name, dob and booking date"][idx];
+            return Err(Error::InternalError(format!(
+                "Inmate {} already has the same {}",
+                id, key_name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Merges a re-crawled record into its stored booking as planned by [`plan_merge`], like the
+/// database backends. Charge and bond rows are keyed by their index. Also returns the booking
+/// number the booking takes over, if any.
+fn merge_booking(
+    existing: &Record,
+    record: Record,
+    inmate_id: i32,
+    matched_by: IdentityMatch,
+) -> (Record, RecordChanges, Option<String>) {
+    let stored = StoredBooking {
+        inmate_id,
+        matched_by,
+        profile: existing.profile.clone(),
+        charges: existing
+            .charges
+            .charges
+            .iter()
+            .map(ChargeVersion::from)
+            .enumerate()
+            .collect(),
+        bonds: existing
+            .bond
+            .bonds
+            .iter()
+            .map(BondVersion::from)
+            .enumerate()
+            .collect(),
+    };
+    let plan = plan_merge(&stored, &record);
+
+    let mut aliases = existing.profile.aliases.clone().unwrap_or_default();
+    aliases.extend(plan.changes.aliases_added.iter().cloned());
+    let charges = ChargeInformation {
+        charges: keep_and_add(
+            &existing.charges.charges,
+            &plan.charges_to_delete,
+            plan.charges_to_insert,
+        ),
+    };
+    let bond = BondInformation {
+        bonds: keep_and_add(
+            &existing.bond.bonds,
+            &plan.bonds_to_delete,
+            plan.bonds_to_insert,
+        ),
+    };
+
+    let mut profile = record.profile;
+    // Ids the crawl lacks are kept, as planned
+    let planned = |field| {
+        plan.changes
+            .fields
+            .iter()
+            .any(|change| change.field == field)
+    };
+    if !planned("scil_sysid") {
+        profile
+            .scil_sys_id
+            .clone_from(&existing.profile.scil_sys_id);
+    }
+    if !planned("booking_number") {
+        profile
+            .booking_number
+            .clone_from(&existing.profile.booking_number);
+    }
+    profile.aliases = (!aliases.is_empty()).then_some(aliases);
+    profile.img_blob = existing.profile.img_blob.clone();
+    profile.embedding = existing.profile.embedding.clone();
+    (
+        Record {
+            url: record.url,
+            profile,
+            bond,
+            charges,
+        },
+        plan.changes,
+        plan.takes_booking_number,
+    )
+}
+
+/// Returns the existing rows that weren't deleted, followed by the inserted rows, in the order
+/// the database would return them.
+fn keep_and_add<T: Clone, V>(existing: &[T], deleted: &[(usize, V)], inserted: Vec<T>) -> Vec<T> {
+    existing
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| !deleted.iter().any(|(deleted, _)| deleted == idx))
+        .map(|(_, row)| row.clone())
+        .chain(inserted)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inmate::{Bond, Charge, ChargeGrade};
+    use crate::utils::Money;
+
+    fn record(sys_id: &str, booking_number: &str, first_name: &str) -> Record {
+        Record {
+            url: String::new(),
+            profile: InmateProfile {
+                first_name: first_name.to_string(),
+                last_name: "DOE".to_string(),
+                dob: "01/02/1990".to_string(),
+                booking_date_iso8601: "10/01/2024 08:15".to_string(),
+                booking_number: Some(booking_number.to_string()),
+                scil_sys_id: Some(sys_id.to_string()),
+                ..Default::default()
+            },
+            bond: BondInformation {
+                bonds: vec![Bond {
+                    bond_type: "Cash Only".to_string(),
+                    bond_amount: Money::from_cents(50_000),
+                    amount_unparseable: false,
+                    amount_not_set: false,
+                }],
+            },
+            charges: ChargeInformation {
+                charges: vec![Charge {
+                    description: "THEFT".to_string(),
+                    grade: ChargeGrade::Misdemeanor,
+                    offense_date: "10/01/2024".to_string(),
+                }],
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_merges_corrected_name() {
+        let store = InMemoryRecordStore::new();
+        let created = store
+            .upsert_record(record("?sysid=1", "24-1", "JON"))
+            .await
+            .unwrap();
+        assert!(created.created);
+
+        let mut corrected = record("?sysid=1", "24-1", "JOHN");
+        corrected.charges.charges[0].grade = ChargeGrade::Felony;
+        let changes = store.upsert_record(corrected).await.unwrap();
+        assert_eq!(changes.inmate_id, created.inmate_id);
+        assert_eq!(changes.matched_by, Some(IdentityMatch::SysId));
+        assert_eq!(changes.fields.len(), 1);
+        assert_eq!(changes.aliases_added, vec!["JON DOE"]);
+        assert_eq!(changes.charges_removed.len(), 1);
+        assert_eq!(changes.charges_added.len(), 1);
+        assert!(changes.bonds_added.is_empty());
+
+        let stored = store
+            .load_record_by_sys_id("?sysid=1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.profile.first_name, "JOHN");
+        assert_eq!(stored.charges.charges.len(), 1);
+        assert_eq!(store.records().len(), 1);
+
+        let unchanged = store
+            .upsert_record(store.load_record(created.inmate_id).await.unwrap().unwrap())
+            .await
+            .unwrap();
+        assert!(unchanged.is_unchanged());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_supersedes_booking_number_holder() {
+        let store = InMemoryRecordStore::new();
+        let holder = store
+            .upsert_record(record("?sysid=1", "24-1", "JOHN"))
+            .await
+            .unwrap();
+        let other = store
+            .upsert_record(record("?sysid=2", "24-2", "JANE"))
+            .await
+            .unwrap();
+        assert!(other.created);
+
+        // sys_id 2 is matched and takes over the booking number of sys_id 1, like in Postgres
+        let changes = store
+            .upsert_record(record("?sysid=2", "24-1", "JANE"))
+            .await
+            .unwrap();
+        assert_eq!(changes.inmate_id, other.inmate_id);
+        assert_eq!(changes.superseded, Some(holder.inmate_id));
+        let stored = store.load_record(other.inmate_id).await.unwrap().unwrap();
+        assert_eq!(stored.profile.booking_number.as_deref(), Some("24-1"));
+
+        // The superseded booking is kept, but no longer found by its ids
+        assert!(store.load_record(holder.inmate_id).await.unwrap().is_some());
+        assert!(store
+            .load_record_by_sys_id("?sysid=1")
+            .await
+            .unwrap()
+            .is_none());
+        let changes = store
+            .upsert_record(record("?sysid=3", "24-1", "JANE"))
+            .await
+            .unwrap();
+        assert_eq!(changes.inmate_id, other.inmate_id);
+        assert_eq!(changes.matched_by, Some(IdentityMatch::BookingNumber));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_matches_keys_slot_by_slot() {
+        let store = InMemoryRecordStore::new();
+        let stored = store
+            .upsert_record(record("?sysid=1", "24-1", "JOHN"))
+            .await
+            .unwrap()
+            .inmate_id;
+
+        // A sys_id never matches a booking number
+        let changes = store
+            .upsert_record(record("24-1", "24-2", "JANE"))
+            .await
+            .unwrap();
+        assert!(changes.created);
+
+        // Without ids, name, dob and booking date match, and the stored ids are kept
+        let mut no_ids = record("", "", "JOHN");
+        no_ids.profile.scil_sys_id = None;
+        let changes = store.upsert_record(no_ids).await.unwrap();
+        assert_eq!(changes.inmate_id, stored);
+        assert_eq!(changes.matched_by, Some(IdentityMatch::CoreAttributes));
+        assert!(changes.is_unchanged());
+        let loaded = store.load_record(stored).await.unwrap().unwrap();
+        assert_eq!(loaded.profile.scil_sys_id.as_deref(), Some("?sysid=1"));
+        assert_eq!(loaded.profile.booking_number.as_deref(), Some("24-1"));
+
+        // But never a booking stored under other ids
+        let res = store
+            .upsert_record(record("?sysid=3", "24-3", "JOHN"))
+            .await;
+        assert!(matches!(res, Err(Error::InternalError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_blacklist_and_updatelist() {
+        let store = InMemoryRecordStore::new();
+        let without_img = store
+            .upsert_record(record("?sysid=1", "24-1", "JOHN"))
+            .await
+            .unwrap()
+            .inmate_id;
+        let mut with_img = record("?sysid=2", "24-2", "JANE");
+        with_img.profile.img_blob = Some(vec![1, 2, 3]);
+        store.upsert_record(with_img).await.unwrap();
+
+        let (blacklist, updatelist) = store.blacklist_and_updatelist(45).await.unwrap();
+        assert_eq!(blacklist, HashSet::from(["?sysid=2".to_string()]));
+        assert_eq!(
+            updatelist,
+            HashMap::from([("?sysid=1".to_string(), without_img)])
+        );
+
+        // Only the last n bookings are listed
+        let (blacklist, updatelist) = store.blacklist_and_updatelist(1).await.unwrap();
+        assert_eq!(blacklist.len(), 1);
+        assert!(updatelist.is_empty());
+
+        let mut recrawled = record("?sysid=1", "24-1", "JOHN");
+        recrawled.profile.img_blob = Some(vec![4, 5, 6]);
+        store
+            .update_null_img_records(vec![(without_img, recrawled)])
+            .await
+            .unwrap();
+        let (blacklist, updatelist) = store.blacklist_and_updatelist(45).await.unwrap();
+        assert_eq!(blacklist.len(), 2);
+        assert!(updatelist.is_empty());
+    }
+}
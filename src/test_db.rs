@@ -0,0 +1,95 @@
+//! Fixtures shared by tests, and helpers for tests that need Postgres. Those tests are ignored by
+//! default; run them against a throwaway database with
+//! `TEST_DATABASE_URL=postgres://... cargo test -- --ignored`.
+use chrono::Utc;
+use image::{ImageBuffer, ImageFormat, Rgb};
+use sqlx::postgres::PgPool;
+use std::io::Cursor;
+
+use crate::inmate::{
+    Bond, BondInformation, Charge, ChargeGrade, ChargeInformation, InmateProfile, Record,
+};
+use crate::utils::Money;
+
+/// Connects to the database at `TEST_DATABASE_URL` and brings its schema up to date.
+pub(crate) async fn test_pool() -> PgPool {
+    let url = std::env::var("TEST_DATABASE_URL")
+        .expect("Expect TEST_DATABASE_URL to point at a throwaway database");
+    let pool = PgPool::connect(&url)
+        .await
+        .expect("Expect the test database to be reachable");
+    crate::migrations::migrate(&pool)
+        .await
+        .expect("Expect the test database to migrate");
+    pool
+}
+
+/// Returns a suffix unique to this test, so bookings stored by earlier runs don't match.
+pub(crate) fn unique_suffix() -> String {
+    Utc::now()
+        .timestamp_nanos_opt()
+        .expect("Expect the current time to fit in nanoseconds")
+        .to_string()
+}
+
+/// A booking with a charge and a bond, identified by `suffix` in its sys_id, booking number and
+/// last name.
+pub(crate) fn test_record(suffix: &str) -> Record {
+    Record {
+        url: format!("https://example.com/inmates.php?sysid=test-{suffix}"),
+        profile: InmateProfile {
+            first_name: "JOHN".to_string(),
+            last_name: format!("DOE{suffix}"),
+            dob: "01/02/1990".to_string(),
+            booking_date_iso8601: "10/01/2024 08:15".to_string(),
+            booking_number: Some(format!("T-{suffix}")),
+            scil_sys_id: Some(format!("?sysid=test-{suffix}")),
+            weight: Some("170".to_string()),
+            ..Default::default()
+        },
+        bond: BondInformation {
+            bonds: vec![Bond {
+                bond_type: "Cash Only".to_string(),
+                bond_amount: Money::from_cents(50_000),
+                amount_unparseable: false,
+                amount_not_set: false,
+            }],
+        },
+        charges: ChargeInformation {
+            charges: vec![Charge {
+                description: "THEFT".to_string(),
+                grade: ChargeGrade::Misdemeanor,
+                offense_date: "10/01/2024".to_string(),
+            }],
+        },
+    }
+}
+
+/// A small PNG, different for each shade.
+pub(crate) fn png(shade: u8) -> Vec<u8> {
+    write_image(
+        ImageBuffer::from_fn(8, 8, |x, _| Rgb([shade, x as u8 * 30, 0])),
+        ImageFormat::Png,
+    )
+}
+
+/// An image of the given size and format, half white so it doesn't look like a placeholder.
+pub(crate) fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+    write_image(
+        ImageBuffer::from_fn(width, height, |x, y| {
+            if x < width / 2 {
+                Rgb([255u8, 255, 255])
+            } else {
+                Rgb([(y % 255) as u8, 0, 0])
+            }
+        }),
+        format,
+    )
+}
+
+fn write_image(img: ImageBuffer<Rgb<u8>, Vec<u8>>, format: ImageFormat) -> Vec<u8> {
+    let mut bytes = Cursor::new(Vec::new());
+    img.write_to(&mut bytes, format)
+        .expect("Expect a test image to encode");
+    bytes.into_inner()
+}
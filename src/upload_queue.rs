@@ -0,0 +1,283 @@
+use log::{debug, info, warn};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::time::Duration;
+
+use crate::object_store::ObjectStore;
+use crate::serialize::{upload_image, ImgProvenance, ImgStoragePolicy};
+use crate::Error;
+
+pub const DEFAULT_UPLOAD_MAX_ATTEMPTS: i32 = 8;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct DrainOptions {
+    /// Most tasks to attempt in one drain
+    pub batch_size: usize,
+    /// Tasks that failed this many times are left for an operator to look at
+    pub max_attempts: i32,
+    /// Whether an uploaded image's blob stays in the database
+    pub img_storage_policy: ImgStoragePolicy,
+}
+
+impl Default for DrainOptions {
+    fn default() -> Self {
+        DrainOptions {
+            batch_size: 100,
+            max_attempts: DEFAULT_UPLOAD_MAX_ATTEMPTS,
+            img_storage_policy: ImgStoragePolicy::ObjectStoreOnly,
+        }
+    }
+}
+
+impl DrainOptions {
+    /// Reads the max attempts from the `UPLOAD_MAX_ATTEMPTS` env var, if set, and the policy from
+    /// `IMG_STORAGE_POLICY`.
+    ///
+    /// # Errors
+    /// ArgumentError: If `UPLOAD_MAX_ATTEMPTS` isn't a positive integer, or the policy is unknown
+    pub fn from_env() -> Result<DrainOptions, Error> {
+        let mut options = DrainOptions {
+            img_storage_policy: ImgStoragePolicy::from_env(true)?,
+            ..Default::default()
+        };
+        if let Ok(max_attempts) = std::env::var("UPLOAD_MAX_ATTEMPTS") {
+            options.max_attempts = max_attempts
+                .trim()
+                .parse::<i32>()
+                .ok()
+                .filter(|max_attempts| *max_attempts > 0)
+                .ok_or(Error::ArgumentError)?;
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DrainReport {
+    pub uploaded: usize,
+    pub failed: usize,
+    /// Tasks that used up their last attempt in this drain
+    pub exhausted: usize,
+}
+
+/// Returns how long to wait before retrying a task that has failed `attempts` times: doubling
+/// from 30 seconds, up to 6 hours.
+pub fn retry_delay(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    RETRY_BASE_DELAY
+        .saturating_mul(2u32.pow(exponent))
+        .min(RETRY_MAX_DELAY)
+}
+
+/// Uploads a claimed task's image, then points the image and every inmate linked to it at the
+/// uploaded key.
+async fn upload_task_image(
+    image_id: i32,
+    inmate_id: i32,
+    img_url: &str,
+    source_url: Option<&str>,
+    policy: ImgStoragePolicy,
+    object_store: &dyn ObjectStore,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT image.img, inmate.booking_date::text AS booking_date
+        FROM image, inmate
+        WHERE image.id = $1 AND inmate.id = $2
+        "#,
+    )
+    .bind(image_id)
+    .bind(inmate_id)
+    .fetch_one(&mut **transaction)
+    .await?;
+    let img_blob: Vec<u8> = row
+        .try_get::<Option<Vec<u8>>, _>("img")?
+        .ok_or(Error::InternalError(format!(
+            "Image {} has no stored blob to upload",
+            image_id
+        )))?;
+    let booking_date: String = row.try_get("booking_date")?;
+    let provenance = ImgProvenance {
+        inmate_id,
+        booking_date: &booking_date,
+        source_url,
+    };
+
+    upload_image(
+        &image_id,
+        &img_blob,
+        img_url,
+        &provenance,
+        policy,
+        object_store,
+        transaction,
+    )
+    .await?;
+
+    sqlx::query(
+        r#"
+        UPDATE inmate
+        SET img_url = $1
+        FROM inmate_image
+        WHERE inmate_image.image_id = $2
+            AND inmate.id = inmate_image.inmate_id
+            AND COALESCE(inmate.img_url, '') = ''
+        "#,
+    )
+    .bind(img_url)
+    .bind(image_id)
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Claims the next due task and attempts it. Returns None when no task is due, otherwise whether
+/// the upload succeeded and whether the task is out of attempts.
+async fn drain_one(
+    pool: &PgPool,
+    object_store: &dyn ObjectStore,
+    options: &DrainOptions,
+) -> Result<Option<(bool, bool)>, Error> {
+    let max_attempts = options.max_attempts;
+    let mut transaction = pool.begin().await?;
+
+    // SKIP LOCKED lets several workers drain the queue without claiming the same task
+    let task = sqlx::query(
+        r#"
+        SELECT id, image_id, inmate_id, img_url, source_url, attempts
+        FROM img_upload_task
+        WHERE completed_at IS NULL
+            AND attempts < $1
+            AND next_attempt_at <= now()
+        ORDER BY next_attempt_at, id
+        LIMIT 1
+        FOR UPDATE SKIP LOCKED
+        "#,
+    )
+    .bind(max_attempts)
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some(task) = task else {
+        return Ok(None);
+    };
+    let task_id: i32 = task.try_get("id")?;
+    let image_id: i32 = task.try_get("image_id")?;
+    let img_url: String = task.try_get("img_url")?;
+    let source_url: Option<String> = task.try_get("source_url")?;
+    let attempts: i32 = task.try_get::<i32, _>("attempts")? + 1;
+
+    // The upload's writes go in a savepoint, so a failure can be rolled back while the attempt
+    // is still recorded
+    let mut upload_transaction = sqlx::Connection::begin(&mut *transaction).await?;
+    let res = upload_task_image(
+        image_id,
+        task.try_get("inmate_id")?,
+        &img_url,
+        source_url.as_deref(),
+        options.img_storage_policy,
+        object_store,
+        &mut upload_transaction,
+    )
+    .await;
+
+    let outcome = match res {
+        Ok(()) => {
+            upload_transaction.commit().await?;
+            sqlx::query(
+                r#"
+                UPDATE img_upload_task
+                SET attempts = $1, completed_at = now(), last_error = NULL
+                WHERE id = $2
+                "#,
+            )
+            .bind(attempts)
+            .bind(task_id)
+            .execute(&mut *transaction)
+            .await?;
+            debug!("Uploaded queued image {}: {}", image_id, img_url);
+            (true, false)
+        }
+        Err(e) => {
+            upload_transaction.rollback().await?;
+            let delay = retry_delay(attempts);
+            warn!(
+                "Queued upload of image {} failed (attempt {}/{}), retrying in {:?}: {:#?}",
+                image_id, attempts, max_attempts, delay, e
+            );
+            sqlx::query(
+                r#"
+                UPDATE img_upload_task
+                SET attempts = $1,
+                    last_error = $2,
+                    next_attempt_at = now() + make_interval(secs => $3)
+                WHERE id = $4
+                "#,
+            )
+            .bind(attempts)
+            .bind(e.to_string())
+            .bind(delay.as_secs_f64())
+            .bind(task_id)
+            .execute(&mut *transaction)
+            .await?;
+            (false, attempts >= max_attempts)
+        }
+    };
+    transaction.commit().await?;
+
+    Ok(Some(outcome))
+}
+
+/// Attempts up to `batch_size` due upload tasks, one at a time. Failed tasks are retried with
+/// exponential backoff, until they run out of attempts.
+///
+/// # Errors
+/// Returns an error if the queue can't be read or updated. Upload failures are recorded on their
+/// task instead.
+pub async fn drain_img_upload_queue(
+    pool: &PgPool,
+    object_store: &dyn ObjectStore,
+    options: &DrainOptions,
+) -> Result<DrainReport, Error> {
+    let mut report = DrainReport::default();
+    for _ in 0..options.batch_size {
+        match drain_one(pool, object_store, options).await? {
+            Some((true, _)) => report.uploaded += 1,
+            Some((false, exhausted)) => {
+                report.failed += 1;
+                if exhausted {
+                    report.exhausted += 1;
+                }
+            }
+            None => break,
+        }
+    }
+
+    if report != DrainReport::default() {
+        info!(
+            "Drained img upload queue: {} uploaded, {} failed, {} out of attempts",
+            report.uploaded, report.failed, report.exhausted
+        );
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::from_secs(30));
+        assert_eq!(retry_delay(2), Duration::from_secs(60));
+        assert_eq!(retry_delay(5), Duration::from_secs(480));
+        assert_eq!(retry_delay(12), RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(i32::MAX), RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(0), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,600 @@
+use log::{debug, info, trace, warn};
+use serde::Serialize;
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::Row;
+
+use crate::diff::{profile_from_columns, FieldChange};
+use crate::history::{
+    record_bond_changes, record_charge_changes, record_profile_version, BondVersion, ChargeVersion,
+};
+use crate::inmate::Record;
+use crate::merge::{plan_merge, StoredBooking};
+use crate::object_store::ObjectStore;
+use crate::person::{resolve_person, PersonLink};
+use crate::serialize::{
+    insert_record, link_aliases, serialize_bonds, serialize_charges, ImgStoragePolicy,
+};
+use crate::utils::Money;
+use crate::Error;
+
+/// How a re-crawled record was matched to its stored booking, strongest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum IdentityMatch {
+    SysId,
+    BookingNumber,
+    /// Name, dob and booking date, for records missing both ids
+    CoreAttributes,
+}
+
+/// What serializing a record did to the database. A re-crawled booking is merged into its
+/// existing inmate id, see [`IdentityMatch`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RecordChanges {
+    pub inmate_id: i32,
+    /// True if the booking was new and inserted, in which case nothing else is listed
+    pub created: bool,
+    /// None when the booking was created
+    pub matched_by: Option<IdentityMatch>,
+    /// Set if the booking was linked to a person again, which happens when it's created or the
+    /// names or ids it's matched by change
+    pub person: Option<PersonLink>,
+    /// Set if another booking had the booking number this one changed to. Postgres marks that
+    /// booking superseded by this one, and SQLite clears its booking number.
+    pub superseded: Option<i32>,
+    pub fields: Vec<FieldChange>,
+    pub aliases_added: Vec<String>,
+    pub charges_added: Vec<String>,
+    pub charges_removed: Vec<String>,
+    pub bonds_added: Vec<String>,
+    pub bonds_removed: Vec<String>,
+}
+
+impl RecordChanges {
+    /// Returns true if an existing booking was re-crawled without changes.
+    pub fn is_unchanged(&self) -> bool {
+        !self.created
+            && self.fields.is_empty()
+            && self.aliases_added.is_empty()
+            && self.charges_added.is_empty()
+            && self.charges_removed.is_empty()
+            && self.bonds_added.is_empty()
+            && self.bonds_removed.is_empty()
+    }
+}
+
+/// Inserts the record, or merges it into the existing booking as planned by [`plan_merge`]:
+/// changed profile fields are updated, new aliases linked, and charges and bonds replaced where
+/// they differ. Every change is appended to the booking's history. Everything happens in one
+/// transaction.
+///
+/// The booking is found by `scil_sysid`, then `booking_number`, then name, dob and booking date,
+/// which never match a booking stored under another sys_id or booking number. Matching on an id lets the jail correct a name, dob or booking date; a corrected name keeps the
+/// old spelling as an alias. A booking matched by sys_id whose booking number belonged to another
+/// booking supersedes it. The image and embedding of an existing booking are left alone; images
+/// are filled in by [`crate::serialize::update_null_img_record`].
+pub async fn upsert_record(
+    record: Record,
+    pool: &PgPool,
+    object_store: &Option<Box<dyn ObjectStore>>,
+    policy: ImgStoragePolicy,
+) -> Result<RecordChanges, Error> {
+    let mut transaction = pool.begin().await?;
+    let changes = upsert_record_in(record, &mut transaction, object_store, policy).await?;
+    transaction.commit().await?;
+
+    log_changes(&changes);
+    Ok(changes)
+}
+
+/// [`upsert_record`] within the caller's transaction, which may be a savepoint. Nothing is
+/// logged until the caller commits, see [`log_changes`].
+pub(crate) async fn upsert_record_in(
+    record: Record,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    object_store: &Option<Box<dyn ObjectStore>>,
+    policy: ImgStoragePolicy,
+) -> Result<RecordChanges, Error> {
+    trace!("Upserting record: {:#?}", record);
+
+    let sys_id = record
+        .profile
+        .scil_sys_id
+        .as_deref()
+        .filter(|id| !id.is_empty());
+    let booking_number = record
+        .profile
+        .booking_number
+        .as_deref()
+        .filter(|n| !n.is_empty());
+    let existing = sqlx::query(
+        r#"
+        SELECT id, first_name, middle_name, last_name, affix, permanent_id, sex,
+            to_char(dob, 'MM/DD/YYYY') AS dob, arresting_agency,
+            to_char(booking_date AT TIME ZONE 'America/Chicago', 'MM/DD/YYYY HH24:MI') AS booking_date,
+            booking_number, height, weight, race, eye_color, scil_sysid,
+            COALESCE(scil_sysid = $5, false) AS sys_id_matched,
+            COALESCE(booking_number = $6, false) AS booking_number_matched
+        FROM inmate
+        WHERE superseded_by IS NULL
+            AND (
+                scil_sysid = $5
+                OR booking_number = $6
+                OR (
+                    first_name = $1
+                    AND last_name = $2
+                    AND dob = $3::date
+                    AND booking_date = $4::TIMESTAMP WITHOUT TIME ZONE AT TIME ZONE 'America/Chicago'
+                    AND (NULLIF(scil_sysid, '') IS NULL OR $5::text IS NULL OR scil_sysid = $5)
+                    AND (NULLIF(booking_number, '') IS NULL OR $6::text IS NULL
+                        OR booking_number = $6)
+                )
+            )
+        ORDER BY sys_id_matched DESC, booking_number_matched DESC, id DESC
+        LIMIT 1
+        FOR UPDATE
+        "#,
+    )
+    .bind(&record.profile.first_name)
+    .bind(&record.profile.last_name)
+    .bind(&record.profile.dob)
+    .bind(&record.profile.booking_date_iso8601)
+    .bind(sys_id)
+    .bind(booking_number)
+    .fetch_optional(&mut **transaction)
+    .await?;
+
+    let Some(existing) = existing else {
+        let charges: Vec<ChargeVersion> = record
+            .charges
+            .charges
+            .iter()
+            .map(ChargeVersion::from)
+            .collect();
+        let bonds: Vec<BondVersion> = record.bond.bonds.iter().map(BondVersion::from).collect();
+        let inmate_id = insert_record(record, transaction, object_store, policy).await?;
+
+        record_profile_version(inmate_id, transaction).await?;
+        record_charge_changes(inmate_id, &charges, false, transaction).await?;
+        record_bond_changes(inmate_id, &bonds, false, transaction).await?;
+        let person = resolve_person(inmate_id, transaction).await?;
+        return Ok(RecordChanges {
+            inmate_id,
+            created: true,
+            person: Some(person),
+            ..Default::default()
+        });
+    };
+
+    let matched_by = if existing.try_get("sys_id_matched")? {
+        IdentityMatch::SysId
+    } else if existing.try_get("booking_number_matched")? {
+        IdentityMatch::BookingNumber
+    } else {
+        IdentityMatch::CoreAttributes
+    };
+    let stored = load_stored_booking(&existing, matched_by, transaction).await?;
+    let inmate_id = stored.inmate_id;
+    let plan = plan_merge(&stored, &record);
+    let mut changes = plan.changes.clone();
+
+    if let Some(booking_number) = plan.takes_booking_number.as_deref() {
+        changes.superseded =
+            supersede_booking_number_holder(inmate_id, booking_number, transaction).await?;
+    }
+
+    for change in plan.changes.fields.iter() {
+        // field is one of the column names above, never input
+        let value = match change.field {
+            "dob" => "$1::date",
+            "booking_date" => "$1::TIMESTAMP WITHOUT TIME ZONE AT TIME ZONE 'America/Chicago'",
+            _ => "$1",
+        };
+        sqlx::query(&format!(
+            "UPDATE inmate SET {} = {} WHERE id = $2",
+            change.field, value
+        ))
+        .bind(&change.new)
+        .bind(inmate_id)
+        .execute(&mut **transaction)
+        .await?;
+    }
+    if let Some(old_name) = plan.renamed_from.as_ref() {
+        info!(
+            "Inmate {} was renamed, keeping {} as an alias",
+            inmate_id, old_name
+        );
+    }
+    if !plan.changes.fields.is_empty() {
+        record_profile_version(inmate_id, transaction).await?;
+    }
+
+    link_aliases(&plan.changes.aliases_added, &inmate_id, transaction).await?;
+
+    let charge_ids: Vec<i32> = plan.charges_to_delete.iter().map(|(id, _)| *id).collect();
+    sqlx::query("DELETE FROM charge WHERE id = ANY($1)")
+        .bind(&charge_ids)
+        .execute(&mut **transaction)
+        .await?;
+    record_charge_changes(inmate_id, &plan.charges_removed(), true, transaction).await?;
+    serialize_charges(&plan.charges_to_insert, &inmate_id, transaction).await?;
+    record_charge_changes(inmate_id, &plan.charges_added(), false, transaction).await?;
+
+    let bond_ids: Vec<i32> = plan.bonds_to_delete.iter().map(|(id, _)| *id).collect();
+    sqlx::query("DELETE FROM bond WHERE id = ANY($1)")
+        .bind(&bond_ids)
+        .execute(&mut **transaction)
+        .await?;
+    record_bond_changes(inmate_id, &plan.bonds_removed(), true, transaction).await?;
+    serialize_bonds(&plan.bonds_to_insert, &inmate_id, transaction).await?;
+    record_bond_changes(inmate_id, &plan.bonds_added(), false, transaction).await?;
+
+    if plan.relinks_person() {
+        changes.person = Some(resolve_person(inmate_id, transaction).await?);
+    }
+
+    Ok(changes)
+}
+
+/// Logs what a committed upsert did.
+pub(crate) fn log_changes(changes: &RecordChanges) {
+    if changes.created {
+        debug!("Inserted inmate {}", changes.inmate_id);
+    } else if changes.is_unchanged() {
+        debug!("Re-crawled inmate {} is unchanged", changes.inmate_id);
+    } else {
+        info!(
+            "Merged re-crawled record into inmate {}: {:?}",
+            changes.inmate_id, changes
+        );
+    }
+}
+
+/// Marks the other active booking with the booking number as superseded by `inmate_id`, the way
+/// migration 013 resolved duplicates. A booking matched by sys_id can take over the booking number
+/// of a duplicate stored under another sys_id, which would otherwise violate
+/// `idx_inmate_booking_number_unique` on every crawl. Returns the superseded booking's id.
+async fn supersede_booking_number_holder(
+    inmate_id: i32,
+    booking_number: &str,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<Option<i32>, Error> {
+    let superseded: Option<i32> = sqlx::query_scalar(
+        r#"
+        UPDATE inmate
+        SET superseded_by = $1
+        WHERE booking_number = $2 AND superseded_by IS NULL AND id <> $1
+        RETURNING id
+        "#,
+    )
+    .bind(inmate_id)
+    .bind(booking_number)
+    .fetch_optional(&mut **transaction)
+    .await?;
+    if let Some(superseded) = superseded {
+        warn!(
+            "Inmate {} took over booking number {} from inmate {}, marking it superseded",
+            inmate_id, booking_number, superseded
+        );
+    }
+    Ok(superseded)
+}
+
+/// Loads what [`plan_merge`] needs of the booking in `existing`, the inmate row a record matched.
+async fn load_stored_booking(
+    existing: &PgRow,
+    matched_by: IdentityMatch,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<StoredBooking<i32>, Error> {
+    let inmate_id: i32 = existing.try_get("id")?;
+    let mut profile = profile_from_columns(|column| Ok(existing.try_get(column)?))?;
+    let aliases: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT alias.alias
+        FROM inmate_alias
+        JOIN alias ON alias.id = inmate_alias.alias_id
+        WHERE inmate_alias.inmate_id = $1
+        "#,
+    )
+    .bind(inmate_id)
+    .fetch_all(&mut **transaction)
+    .await?;
+    profile.aliases = Some(aliases);
+
+    let rows = sqlx::query(
+        r#"
+        SELECT id, description, grade, offense_date
+        FROM charge
+        WHERE inmate_id = $1
+        ORDER BY id
+        "#,
+    )
+    .bind(inmate_id)
+    .fetch_all(&mut **transaction)
+    .await?;
+    let mut charges = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        let charge = ChargeVersion {
+            description: row.try_get("description")?,
+            grade: row.try_get("grade")?,
+            offense_date: row.try_get("offense_date")?,
+        };
+        charges.push((row.try_get("id")?, charge));
+    }
+
+    let rows = sqlx::query(
+        r#"
+        SELECT id, type, amount_pennies, amount_unparseable, amount_not_set
+        FROM bond
+        WHERE inmate_id = $1
+        ORDER BY id
+        "#,
+    )
+    .bind(inmate_id)
+    .fetch_all(&mut **transaction)
+    .await?;
+    let mut bonds = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        let bond = BondVersion {
+            bond_type: row.try_get("type")?,
+            bond_amount: Money::try_from(row.try_get::<i64, _>("amount_pennies")?)?,
+            amount_unparseable: row.try_get("amount_unparseable")?,
+            amount_not_set: row.try_get("amount_not_set")?,
+        };
+        bonds.push((row.try_get("id")?, bond));
+    }
+
+    Ok(StoredBooking {
+        inmate_id,
+        matched_by,
+        profile,
+        charges,
+        bonds,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inmate::ChargeGrade;
+    use crate::mugshot::validate_mugshot;
+    use crate::test_db::{png, test_pool, test_record, unique_suffix};
+
+    #[test]
+    fn test_record_changes_is_unchanged() {
+        let mut changes = RecordChanges::default();
+        assert!(changes.is_unchanged());
+        let bond = BondVersion {
+            bond_type: "Cash Only".to_string(),
+            bond_amount: Money::from_cents(50000),
+            amount_unparseable: false,
+            amount_not_set: false,
+        };
+        changes.bonds_removed.push(bond.to_string());
+        assert!(!changes.is_unchanged());
+        assert_eq!(changes.bonds_removed[0], "Cash Only $500.00");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, run with `TEST_DATABASE_URL=... cargo test -- --ignored`"]
+    async fn test_upsert_record_merges_recrawl() {
+        let pool = test_pool().await;
+        let mut record = test_record(&unique_suffix());
+        record.profile.img_blob = Some(png(1));
+        record.profile.img_metadata = Some(validate_mugshot(&png(1)).unwrap());
+        // inmate.embedding is a vector(1536)
+        let embedding: Vec<f32> = (0..1536).map(|idx| idx as f32 / 1536.0).collect();
+        record.profile.embedding = Some(embedding.clone());
+        let policy = ImgStoragePolicy::DbOnly;
+        let created = upsert_record(record.clone(), &pool, &None, policy)
+            .await
+            .unwrap();
+        assert!(created.created);
+
+        let mut recrawled = record.clone();
+        recrawled.profile.weight = Some("180".to_string());
+        recrawled.charges.charges[0].grade = ChargeGrade::Felony;
+        recrawled.bond.bonds[0].bond_amount = Money::from_cents(100_000);
+        recrawled.profile.img_blob = Some(png(2));
+        recrawled.profile.img_metadata = Some(validate_mugshot(&png(2)).unwrap());
+        recrawled.profile.embedding = Some(vec![1.0; 1536]);
+        let changes = upsert_record(recrawled.clone(), &pool, &None, policy)
+            .await
+            .unwrap();
+        assert_eq!(changes.inmate_id, created.inmate_id);
+        assert_eq!(changes.matched_by, Some(IdentityMatch::SysId));
+        assert_eq!(
+            changes.fields,
+            vec![FieldChange {
+                field: "weight",
+                old: Some("170".to_string()),
+                new: Some("180".to_string()),
+            }]
+        );
+        assert_eq!(
+            changes.charges_removed,
+            vec!["THEFT (Misdemeanor, 10/01/2024)"]
+        );
+        assert_eq!(changes.charges_added, vec!["THEFT (Felony, 10/01/2024)"]);
+        assert_eq!(
+            changes.bonds_removed,
+            vec![BondVersion::from(&record.bond.bonds[0]).to_string()]
+        );
+        assert_eq!(
+            changes.bonds_added,
+            vec![BondVersion::from(&recrawled.bond.bonds[0]).to_string()]
+        );
+        assert!(changes.aliases_added.is_empty());
+        assert!(changes.person.is_none());
+
+        let row = sqlx::query("SELECT weight, embedding FROM inmate WHERE id = $1")
+            .bind(created.inmate_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>("weight"), "180");
+        // The image and embedding of the first crawl are kept
+        assert_eq!(
+            row.get::<pgvector::Vector, _>("embedding").to_vec(),
+            embedding
+        );
+        let sha256s: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT image.sha256
+            FROM inmate_image
+            JOIN image ON image.id = inmate_image.image_id
+            WHERE inmate_image.inmate_id = $1
+            "#,
+        )
+        .bind(created.inmate_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(sha256s, vec![validate_mugshot(&png(1)).unwrap().sha256]);
+
+        let grades: Vec<String> =
+            sqlx::query_scalar("SELECT grade FROM charge WHERE inmate_id = $1")
+                .bind(created.inmate_id)
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(grades, vec!["Felony"]);
+        let amounts: Vec<i64> =
+            sqlx::query_scalar("SELECT amount_pennies FROM bond WHERE inmate_id = $1")
+                .bind(created.inmate_id)
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(amounts, vec![100_000]);
+
+        let unchanged = upsert_record(recrawled, &pool, &None, policy)
+            .await
+            .unwrap();
+        assert!(unchanged.is_unchanged());
+    }
+
+    /// The booking's stored sys_id and booking number
+    async fn identity_keys(pool: &PgPool, inmate_id: i32) -> (Option<String>, Option<String>) {
+        let row = sqlx::query("SELECT scil_sysid, booking_number FROM inmate WHERE id = $1")
+            .bind(inmate_id)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        (row.get("scil_sysid"), row.get("booking_number"))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, run with `TEST_DATABASE_URL=... cargo test -- --ignored`"]
+    async fn test_upsert_record_identity_resolution() {
+        let pool = test_pool().await;
+        let policy = ImgStoragePolicy::DbOnly;
+        let record = test_record(&unique_suffix());
+        let inmate_id = upsert_record(record.clone(), &pool, &None, policy)
+            .await
+            .unwrap()
+            .inmate_id;
+
+        // The sys_id wins over a corrected name, dob and booking date
+        let mut corrected = record.clone();
+        corrected.profile.first_name = "JON".to_string();
+        corrected.profile.dob = "01/03/1990".to_string();
+        corrected.profile.booking_date_iso8601 = "10/01/2024 08:20".to_string();
+        let changes = upsert_record(corrected.clone(), &pool, &None, policy)
+            .await
+            .unwrap();
+        assert_eq!(changes.inmate_id, inmate_id);
+        assert_eq!(changes.matched_by, Some(IdentityMatch::SysId));
+        assert_eq!(changes.fields.len(), 3);
+        assert_eq!(changes.aliases_added, vec![record.profile.get_full_name()]);
+        assert!(changes.person.is_some());
+
+        // Without a sys_id, the booking number matches
+        let mut no_sys_id = corrected.clone();
+        no_sys_id.profile.scil_sys_id = None;
+        let changes = upsert_record(no_sys_id.clone(), &pool, &None, policy)
+            .await
+            .unwrap();
+        assert_eq!(changes.inmate_id, inmate_id);
+        assert_eq!(changes.matched_by, Some(IdentityMatch::BookingNumber));
+        // A missing id never clears the stored one
+        assert!(changes.is_unchanged());
+        let stored_ids = (
+            record.profile.scil_sys_id.clone(),
+            record.profile.booking_number.clone(),
+        );
+        assert_eq!(identity_keys(&pool, inmate_id).await, stored_ids);
+
+        // Without either id, name, dob and booking date match
+        let mut no_ids = no_sys_id.clone();
+        no_ids.profile.booking_number = None;
+        let changes = upsert_record(no_ids, &pool, &None, policy).await.unwrap();
+        assert_eq!(changes.inmate_id, inmate_id);
+        assert_eq!(changes.matched_by, Some(IdentityMatch::CoreAttributes));
+        assert!(changes.is_unchanged());
+        assert_eq!(identity_keys(&pool, inmate_id).await, stored_ids);
+
+        // Name, dob and booking date never match a booking stored under other ids
+        let mut other_ids = corrected.clone();
+        other_ids.profile.scil_sys_id = Some(format!("?sysid=other-{}", unique_suffix()));
+        other_ids.profile.booking_number = Some(format!("O-{}", unique_suffix()));
+        assert!(upsert_record(other_ids, &pool, &None, policy)
+            .await
+            .is_err());
+        assert_eq!(identity_keys(&pool, inmate_id).await, stored_ids);
+
+        // Someone else booked at the same time is a new booking
+        let mut other = test_record(&unique_suffix());
+        other.profile.dob = corrected.profile.dob.clone();
+        other.profile.booking_date_iso8601 = corrected.profile.booking_date_iso8601.clone();
+        let changes = upsert_record(other, &pool, &None, policy).await.unwrap();
+        assert!(changes.created);
+        assert_ne!(changes.inmate_id, inmate_id);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, run with `TEST_DATABASE_URL=... cargo test -- --ignored`"]
+    async fn test_upsert_record_supersedes_booking_number_holder() {
+        let pool = test_pool().await;
+        let policy = ImgStoragePolicy::DbOnly;
+        let duplicate = test_record(&unique_suffix());
+        let duplicate_id = upsert_record(duplicate.clone(), &pool, &None, policy)
+            .await
+            .unwrap()
+            .inmate_id;
+        let record = test_record(&unique_suffix());
+        let inmate_id = upsert_record(record.clone(), &pool, &None, policy)
+            .await
+            .unwrap()
+            .inmate_id;
+
+        // The jail now shows the duplicate's booking number under the other sys_id
+        let mut recrawled = record.clone();
+        recrawled.profile.booking_number = duplicate.profile.booking_number.clone();
+        let changes = upsert_record(recrawled.clone(), &pool, &None, policy)
+            .await
+            .unwrap();
+        assert_eq!(changes.inmate_id, inmate_id);
+        assert_eq!(changes.matched_by, Some(IdentityMatch::SysId));
+        assert_eq!(changes.superseded, Some(duplicate_id));
+
+        let superseded_by: Option<i32> =
+            sqlx::query_scalar("SELECT superseded_by FROM inmate WHERE id = $1")
+                .bind(duplicate_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(superseded_by, Some(inmate_id));
+        let booking_number = duplicate.profile.booking_number.unwrap();
+        let loaded = crate::records::load_record_by_booking_number(&pool, &booking_number)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.id, inmate_id);
+
+        // The next crawl doesn't trip over the booking number again
+        let changes = upsert_record(recrawled, &pool, &None, policy)
+            .await
+            .unwrap();
+        assert!(changes.is_unchanged());
+        assert!(changes.superseded.is_none());
+    }
+}
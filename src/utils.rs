@@ -1,34 +1,174 @@
 use log::{debug, warn};
 use std::collections::{HashMap, HashSet};
-use std::ops::{Div, Rem};
+use serde::Serialize;
+use std::fmt;
+use std::sync::OnceLock;
+use sqlx::Row;
 
+use crate::serialize::ImgStoragePolicy;
 use crate::Error;
 
-/// Returns the cent value of a given dollar string, assuming the string is in the format of "$x.yz", where x is a non-negative integer and yz are two base 10 digits.
-///
-/// ## Warning
+/// Returns the id of this crawl run, used to tag the objects it uploads. Taken from the
+/// `CRAWL_RUN_ID` env var, or the UTC start time of the first call if unset.
+pub fn get_crawl_run_id() -> &'static str {
+    static CRAWL_RUN_ID: OnceLock<String> = OnceLock::new();
+    CRAWL_RUN_ID.get_or_init(|| {
+        std::env::var("CRAWL_RUN_ID")
+            .ok()
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string())
+    })
+}
+
+/// An amount of money in cents. Stored as a BIGINT, so values above `i64::MAX` cents can be parsed
+/// but not serialized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(transparent)]
+pub struct Money(u64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub const fn from_cents(cents: u64) -> Money {
+        Money(cents)
+    }
+
+    pub const fn cents(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    /// Returns the total of the given amounts, or None if it overflows.
+    pub fn checked_sum<I: IntoIterator<Item = Money>>(amounts: I) -> Option<Money> {
+        amounts
+            .into_iter()
+            .try_fold(Money::ZERO, |total, amount| total.checked_add(amount))
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}.{:02}", self.0 / 100, self.0 % 100)
+    }
+}
+
+impl TryFrom<Money> for i64 {
+    type Error = Error;
+
+    fn try_from(money: Money) -> Result<Self, Self::Error> {
+        i64::try_from(money.0).map_err(|_| {
+            Error::InternalError(format!("{} does not fit in a BIGINT of cents", money))
+        })
+    }
+}
+
+impl TryFrom<i64> for Money {
+    type Error = Error;
+
+    fn try_from(cents: i64) -> Result<Self, Self::Error> {
+        u64::try_from(cents)
+            .map(Money)
+            .map_err(|_| Error::InternalError(format!("Negative amount of cents: {}", cents)))
+    }
+}
+
+/// Bond amounts the site shows when no amount is set
+const NO_AMOUNT_MARKERS: &[&str] = &["", "n/a", "na", "none", "unbondable", "no bond"];
+
+/// A bond amount as the site shows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BondAmount {
+    Amount(Money),
+    /// Blank, "N/A", "Unbondable" and the like
+    NotSet,
+}
+
+/// Parses a bond amount, telling amounts the site hasn't set apart from "$0.00".
 ///
-/// This function will break when given negative values, or values without their cents.
-pub fn dollars_to_cents(dollars: &str) -> u64 {
-    if let Ok(cents) = dollars
-        .chars()
-        .filter(|c| c.is_digit(10))
-        .collect::<String>()
-        .parse::<u64>()
+/// # Errors
+/// ParseError: If the amount isn't a no amount marker and doesn't parse, see [`dollars_to_cents`]
+pub fn parse_bond_amount(dollars: &str) -> Result<BondAmount, Error> {
+    let trimmed = dollars.trim();
+    if NO_AMOUNT_MARKERS
+        .iter()
+        .any(|marker| trimmed.eq_ignore_ascii_case(marker))
     {
-        cents
-    } else {
-        warn!("Something went wrong parsing {dollars} for cents value. Returning 0.");
-        0
+        return Ok(BondAmount::NotSet);
     }
+
+    dollars_to_cents(trimmed).map(BondAmount::Amount)
 }
 
-pub fn cents_to_dollars<T>(cents: T) -> String
-where
-    T: Div<Output = T> + Rem<Output = T> + From<u8> + Copy + std::fmt::Display,
-{
-    let dollars = cents / T::from(100);
-    format!("${}.{:02}", dollars, cents % T::from(100))
+/// Parses a dollar string such as "$2,200.75", "$500" or "1000.5" into cents.
+///
+/// # Errors
+/// ParseError: If the amount is blank, negative, malformed, has more than two decimal places or
+/// overflows
+pub fn dollars_to_cents(dollars: &str) -> Result<Money, Error> {
+    let trimmed = dollars.trim();
+    let amount = trimmed.strip_prefix('$').unwrap_or(trimmed);
+    let (whole, fraction) = match amount.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (amount, None),
+    };
+
+    let whole_dollars = parse_dollars(whole).ok_or(Error::ParseError)?;
+    let fraction_cents = match fraction {
+        None => 0,
+        Some(fraction) if is_digits(fraction) && fraction.len() <= 2 => {
+            // "$5.5" is 50 cents
+            fraction.parse::<u64>().map_err(|_| Error::ParseError)? * 10u64.pow(2 - fraction.len() as u32)
+        }
+        Some(_) => return Err(Error::ParseError),
+    };
+
+    whole_dollars
+        .checked_mul(100)
+        .and_then(|cents| cents.checked_add(fraction_cents))
+        .map(Money::from_cents)
+        .ok_or(Error::ParseError)
+}
+
+/// Parses whole dollars, allowing commas only as thousands separators.
+fn parse_dollars(whole: &str) -> Option<u64> {
+    let mut groups = whole.split(',');
+    let first = groups.next()?;
+    if !is_digits(first) {
+        return None;
+    }
+
+    let mut digits = first.to_string();
+    for group in groups {
+        if first.len() > 3 || group.len() != 3 || !is_digits(group) {
+            return None;
+        }
+        digits.push_str(group);
+    }
+    digits.parse::<u64>().ok()
+}
+
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+pub fn cents_to_dollars(cents: Money) -> String {
+    cents.to_string()
+}
+
+/// Returns true if a stored record is still missing its img: it has no img_url, no upload that
+/// will still be retried, and, unless the policy uploads, no blob in the database.
+fn needs_img_update(
+    img_url: Option<&str>,
+    img_upload_pending: bool,
+    has_db_img: bool,
+    img_storage_policy: ImgStoragePolicy,
+) -> bool {
+    img_url.unwrap_or_default().is_empty()
+        && !img_upload_pending
+        && (img_storage_policy.uploads() || !has_db_img)
 }
 
 /// Returns a tuple containing (HashSet of inmate sys_ids that should be ignored, HashMap of inmate
@@ -38,76 +178,216 @@ where
 /// The blacklist reduces unnecessary web requests by ignoring already processed records.
 /// The updatelist is necessary because sometimes our scraper will find records before their images 
 /// are uploaded. This function will help fix those broken records.
+///
+/// An img upload that failed `max_upload_attempts` times won't be retried by the upload worker, so
+/// its record goes on the updatelist, and re-crawling it queues the upload again. Unless the policy
+/// uploads, a record's img is done once its blob is in the database.
 pub async fn get_blacklist_and_updatelist(
     n: i64,
+    max_upload_attempts: i32,
+    img_storage_policy: ImgStoragePolicy,
     pool: &sqlx::Pool<sqlx::Postgres>,
 ) -> Result<(HashSet<String>, HashMap<String, i32>), Error> {
     let mut blacklist = HashSet::new();
     let mut updatelist = HashMap::new();
 
-    let recent_records = sqlx::query!(
+    // Records with a queued img upload already have their img, the upload worker will finish them
+    let recent_records = sqlx::query(
         r#"
-           SELECT id, scil_sysid, img_url
+           SELECT id, scil_sysid, img_url,
+               EXISTS (
+                   SELECT 1
+                   FROM inmate_image
+                   JOIN img_upload_task ON img_upload_task.image_id = inmate_image.image_id
+                   WHERE inmate_image.inmate_id = inmate.id
+                       AND img_upload_task.completed_at IS NULL
+                       AND img_upload_task.attempts < $2
+               ) AS img_upload_pending,
+               EXISTS (
+                   SELECT 1
+                   FROM inmate_image
+                   JOIN image ON image.id = inmate_image.image_id
+                   WHERE inmate_image.inmate_id = inmate.id AND image.img IS NOT NULL
+               ) AS has_db_img
            FROM inmate
            ORDER BY id DESC
            LIMIT $1
         "#,
-        n
     )
+    .bind(n)
+    .bind(max_upload_attempts)
     .fetch_all(pool)
     .await
     .map_err(|e| Error::PostgresError(format!("failed to get last {} sys_ids: {}", n, e)))?;
 
-    debug!("Found {:#?} records to check for image updates", recent_records);
+    debug!("Found {} records to check for image updates", recent_records.len());
     for record in recent_records {
-        match record.scil_sysid {
+        let id: i32 = record.try_get("id")?;
+        let img_url: Option<String> = record.try_get("img_url")?;
+        let img_upload_pending: bool = record.try_get("img_upload_pending")?;
+        let has_db_img: bool = record.try_get("has_db_img")?;
+        match record.try_get::<Option<String>, _>("scil_sysid")? {
             Some(sys_id) => {
-                if record.img_url.is_none() || record.img_url.unwrap().is_empty() {
-                    updatelist.insert(sys_id, record.id);
+                if needs_img_update(
+                    img_url.as_deref(),
+                    img_upload_pending,
+                    has_db_img,
+                    img_storage_policy,
+                ) {
+                    updatelist.insert(sys_id, id);
                 } else {
                     blacklist.insert(sys_id);
                 }
             },
             None => {
-                warn!("Found a record with no sys_id: {:#?}", record);
+                warn!("Found a record with no sys_id. Inmate id: {}", id);
             }
         }
     }
 
-    return Ok((blacklist, updatelist));
+    Ok((blacklist, updatelist))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_dollars_to_cents_positive() {
         let dollars = "$2,200.75";
-        assert_eq!(dollars_to_cents(&dollars), 220075);
+        assert_eq!(dollars_to_cents(dollars).unwrap(), Money::from_cents(220075));
     }
 
     #[test]
     fn test_dollars_to_cents_zero() {
         let dollars = "$0.00";
-        assert_eq!(dollars_to_cents(&dollars), 0);
+        assert_eq!(dollars_to_cents(dollars).unwrap(), Money::ZERO);
+    }
+
+    #[test]
+    fn test_dollars_to_cents_formats() {
+        assert_eq!(dollars_to_cents("$500").unwrap().cents(), 50000);
+        assert_eq!(dollars_to_cents(" $1,000.5 ").unwrap().cents(), 100050);
+        assert_eq!(dollars_to_cents("1234567.89").unwrap().cents(), 123456789);
+    }
+
+    #[test]
+    fn test_parse_bond_amount() {
+        assert_eq!(
+            parse_bond_amount(" $1,000.50 ").unwrap(),
+            BondAmount::Amount(Money::from_cents(100050))
+        );
+        assert_eq!(
+            parse_bond_amount("$0.00").unwrap(),
+            BondAmount::Amount(Money::ZERO)
+        );
+        for dollars in ["N/A", "", "  ", "UNBONDABLE", "No Bond"] {
+            assert_eq!(parse_bond_amount(dollars).unwrap(), BondAmount::NotSet);
+            assert!(dollars_to_cents(dollars).is_err(), "{dollars} isn't an amount");
+        }
+        assert!(parse_bond_amount("TBD").is_err());
+    }
+
+    #[test]
+    fn test_dollars_to_cents_rejects_malformed() {
+        for dollars in [
+            "-$5.00", "$-5.00", "($5.00)", "$5.", "$5.001", "$1,00.00", "$1000,000", "$,100", "$.50",
+            "TBD", "$ 5", "$5.00 USD", "$184467440737095516.16",
+        ] {
+            assert!(dollars_to_cents(dollars).is_err(), "{dollars} should not parse");
+        }
     }
 
     #[test]
     fn test_cents_to_dollars_positive() {
-        let cents = 220075;
+        let cents = Money::from_cents(220075);
         assert_eq!(cents_to_dollars(cents), "$2200.75");
     }
 
     #[test]
     fn test_cents_to_dollars_zero() {
-        let cents = 0;
+        let cents = Money::ZERO;
         assert_eq!(cents_to_dollars(cents), "$0.00");
     }
 
     #[test]
     fn test_cents_to_dollars_large_value() {
-        let cents = 1234567890;
+        let cents = Money::from_cents(1234567890);
         assert_eq!(cents_to_dollars(cents), "$12345678.90");
     }
+
+    #[test]
+    fn test_needs_img_update() {
+        use ImgStoragePolicy::{Both, DbOnly, ObjectStoreOnly};
+
+        for policy in [DbOnly, ObjectStoreOnly, Both] {
+            assert!(!needs_img_update(Some("mugshots/a"), false, false, policy));
+            assert!(!needs_img_update(None, true, true, policy));
+            assert!(needs_img_update(Some(""), false, false, policy));
+        }
+        // Without uploads, a blob in the database is all the img there will be
+        assert!(!needs_img_update(None, false, true, DbOnly));
+        // An upload that ran out of attempts is queued again by re-crawling the record
+        assert!(needs_img_update(None, false, true, ObjectStoreOnly));
+        assert!(needs_img_update(None, false, true, Both));
+    }
+
+    #[test]
+    fn test_money_to_bigint_is_checked() {
+        let max = Money::from_cents(i64::MAX as u64);
+        assert_eq!(i64::try_from(max).unwrap(), i64::MAX);
+        assert!(i64::try_from(Money::from_cents(i64::MAX as u64 + 1)).is_err());
+        assert!(Money::try_from(-1i64).is_err());
+        assert_eq!(Money::try_from(2_147_483_648i64).unwrap().cents(), 2_147_483_648);
+    }
+
+    #[test]
+    fn test_money_checked_sum() {
+        let amounts = [Money::from_cents(100), Money::from_cents(250)];
+        assert_eq!(Money::checked_sum(amounts), Some(Money::from_cents(350)));
+        assert_eq!(Money::checked_sum([Money::from_cents(u64::MAX), Money::from_cents(1)]), None);
+    }
+
+    fn with_thousands_separators(dollars: u64) -> String {
+        let digits = dollars.to_string();
+        let mut grouped = String::new();
+        for (idx, digit) in digits.chars().enumerate() {
+            if idx > 0 && (digits.len() - idx) % 3 == 0 {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        grouped
+    }
+
+    proptest! {
+        #[test]
+        fn prop_dollars_to_cents_round_trips(cents in any::<u64>()) {
+            let money = Money::from_cents(cents);
+            prop_assert_eq!(dollars_to_cents(&cents_to_dollars(money)).unwrap(), money);
+        }
+
+        #[test]
+        fn prop_dollars_to_cents_accepts_separators(dollars in 0..u64::MAX / 100, cents in 0..100u64) {
+            let formatted = format!("${}.{:02}", with_thousands_separators(dollars), cents);
+            prop_assert_eq!(dollars_to_cents(&formatted).unwrap().cents(), dollars * 100 + cents);
+        }
+
+        #[test]
+        fn prop_dollars_to_cents_whole_dollars(dollars in 0..u64::MAX / 100) {
+            prop_assert_eq!(dollars_to_cents(&format!("${}", dollars)).unwrap().cents(), dollars * 100);
+        }
+
+        #[test]
+        fn prop_dollars_to_cents_rejects_negatives(cents in 1..u64::MAX) {
+            let formatted = format!("-{}", cents_to_dollars(Money::from_cents(cents)));
+            prop_assert!(dollars_to_cents(&formatted).is_err());
+        }
+
+        #[test]
+        fn prop_dollars_to_cents_never_panics(dollars in "\\PC*") {
+            let _ = dollars_to_cents(&dollars);
+        }
+    }
 }
@@ -0,0 +1,185 @@
+//! Crawls the sanitized fixture pages from a mock server into an in-memory record store, covering
+//! the blacklist and updatelist flow without a database or the network.
+use image::{ImageBuffer, ImageFormat, Rgb};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use scjail_crawler_service::fetch_records_filtered;
+use scjail_crawler_service::store::{store_records, InMemoryRecordStore, RecordStore};
+
+const LISTING_PATH: &str = "/sheriff/inmates.php";
+const FIXTURE_IMG_ROOT: &str = "//www.scottcountyiowa.us/sheriff/inmatephotos/";
+
+/// The detail page served for each sys_id linked from `listing/day.html`
+const DETAIL_FIXTURES: [(&str, &str); 4] = [
+    ("100003", "no_image"),
+    ("100004", "unbondable"),
+    ("100005", "multiple_bonds"),
+    ("100006", "many_charges"),
+];
+
+/// Reads a fixture page, pointing its mugshot at the mock server.
+fn fixture(relative: &str, server: &MockServer) -> String {
+    let fixture_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(relative);
+    fs::read_to_string(&fixture_path)
+        .unwrap_or_else(|e| panic!("Expect fixture {relative} to be readable: {e}"))
+        .replace(FIXTURE_IMG_ROOT, &format!("{}/photos/", server.uri()))
+}
+
+async fn mock_page(server: &MockServer, sys_id: &str, body: String) {
+    Mock::given(method("GET"))
+        .and(path(LISTING_PATH))
+        .and(query_param("sysid", sys_id))
+        .respond_with(ResponseTemplate::new(200).set_body_string(body))
+        .mount(server)
+        .await;
+}
+
+async fn mock_listing(server: &MockServer) {
+    Mock::given(method("GET"))
+        .and(path(LISTING_PATH))
+        .and(query_param("comdate", "10/06/2024"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_string(fixture("listing/day.html", server)),
+        )
+        .mount(server)
+        .await;
+}
+
+/// A small PNG mugshot, different for each sys_id.
+fn mugshot(sys_id: &str) -> Vec<u8> {
+    let shade = sys_id.bytes().last().unwrap_or_default();
+    let img = ImageBuffer::from_fn(8, 8, |x, _| Rgb([shade, x as u8 * 30, 0]));
+    let mut bytes = Cursor::new(Vec::new());
+    img.write_to(&mut bytes, ImageFormat::Png).unwrap();
+    bytes.into_inner()
+}
+
+async fn mock_photo(server: &MockServer, sys_id: &str) {
+    Mock::given(method("GET"))
+        .and(path(format!("/photos/{sys_id}.jpg")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(mugshot(sys_id)))
+        .mount(server)
+        .await;
+}
+
+/// Serves the day listing, its detail pages and their mugshots.
+async fn mock_site() -> MockServer {
+    let server = MockServer::start().await;
+    mock_listing(&server).await;
+    for (sys_id, name) in DETAIL_FIXTURES {
+        let body = fixture(&format!("detail/{name}.html"), &server);
+        mock_page(&server, sys_id, body).await;
+        mock_photo(&server, sys_id).await;
+    }
+    server
+}
+
+fn listing_url(server: &MockServer) -> String {
+    format!("{}{}?comdate=10%2F06%2F2024", server.uri(), LISTING_PATH)
+}
+
+#[tokio::test]
+async fn test_crawl_to_store() {
+    std::env::set_var("REQ_DELAY_MS", "0");
+    let server = mock_site().await;
+    let client = reqwest::Client::new();
+    let store = InMemoryRecordStore::new();
+
+    let (new_records, update_records) = fetch_records_filtered(
+        &client,
+        &listing_url(&server),
+        &HashSet::new(),
+        &HashMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(new_records.len(), 4);
+    assert!(update_records.is_empty());
+    let changes = store_records(&store, new_records, &None).await.unwrap();
+    assert!(changes.iter().all(|changes| changes.created));
+
+    let stored = store
+        .load_record_by_sys_id("?sysid=100005")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.profile.first_name, "DAVID");
+    assert_eq!(
+        stored.url,
+        format!("{}{}?sysid=100005", server.uri(), LISTING_PATH)
+    );
+    assert_eq!(stored.profile.img_blob, Some(mugshot("100005")));
+
+    // The booking without a mugshot is due for update, the rest are done
+    let (blacklist, updatelist) = store.blacklist_and_updatelist(45).await.unwrap();
+    assert_eq!(blacklist.len(), 3);
+    assert_eq!(updatelist.len(), 1);
+    let (no_img_sys_id, no_img_id) = updatelist.iter().next().unwrap();
+    assert_eq!(no_img_sys_id, "?sysid=100003");
+
+    // The jail has since posted the mugshot
+    let with_img = fixture("detail/no_image.html", &server).replace(
+        "<p>No photo available</p>",
+        &format!(r#"<img src="{}/photos/100003.jpg">"#, server.uri()),
+    );
+    server.reset().await;
+    mock_listing(&server).await;
+    mock_page(&server, "100003", with_img).await;
+    mock_photo(&server, "100003").await;
+
+    let (new_records, update_records) =
+        fetch_records_filtered(&client, &listing_url(&server), &blacklist, &updatelist)
+            .await
+            .unwrap();
+    assert!(new_records.is_empty());
+    assert_eq!(update_records.len(), 1);
+    assert_eq!(update_records[0].0, *no_img_id);
+    store.update_null_img_records(update_records).await.unwrap();
+
+    let stored = store.load_record(*no_img_id).await.unwrap().unwrap();
+    assert_eq!(stored.profile.img_blob, Some(mugshot("100003")));
+    let (blacklist, updatelist) = store.blacklist_and_updatelist(45).await.unwrap();
+    assert_eq!(blacklist.len(), 4);
+    assert!(updatelist.is_empty());
+    assert_eq!(store.records().len(), 4);
+}
+
+#[tokio::test]
+async fn test_recrawl_merges_into_stored_bookings() {
+    std::env::set_var("REQ_DELAY_MS", "0");
+    let server = mock_site().await;
+    let client = reqwest::Client::new();
+    let store = InMemoryRecordStore::new();
+
+    for _ in 0..2 {
+        let (new_records, _) = fetch_records_filtered(
+            &client,
+            &listing_url(&server),
+            &HashSet::new(),
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+        store_records(&store, new_records, &None).await.unwrap();
+    }
+
+    // Crawling the same pages again doesn't duplicate or change anything
+    assert_eq!(store.records().len(), 4);
+    let (new_records, _) = fetch_records_filtered(
+        &client,
+        &listing_url(&server),
+        &HashSet::new(),
+        &HashMap::new(),
+    )
+    .await
+    .unwrap();
+    let changes = store_records(&store, new_records, &None).await.unwrap();
+    assert!(changes.iter().all(|changes| changes.is_unchanged()));
+}
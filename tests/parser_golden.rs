@@ -0,0 +1,150 @@
+//! Golden-file tests for the HTML parsers.
+//!
+//! Each page under `tests/fixtures` is a sanitized copy of a Scott County listing or detail page.
+//! The parsed output is compared against the matching JSON file under `tests/golden`.
+//!
+//! When parser behavior changes on purpose, regenerate the golden files and review the diff:
+//!
+//! ```sh
+//! UPDATE_GOLDEN=1 cargo test --test parser_golden
+//! ```
+use scraper::Html;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use scjail_crawler_service::{
+    inmate::{BondInformation, ChargeInformation, InmateProfile},
+    parse_inmate_sysids_old_to_new, parse_relative_listings_urls_for_last_two_days, Error,
+};
+
+fn fixture_path(relative: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(relative)
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.json"))
+}
+
+fn load_fixture(relative: &str) -> Html {
+    let body = fs::read_to_string(fixture_path(relative))
+        .unwrap_or_else(|e| panic!("Expect fixture {relative} to be readable: {e}"));
+    Html::parse_document(&body)
+}
+
+/// Failed parses are part of the golden output, so they're recorded as their display string.
+fn to_golden<T: Serialize>(res: Result<T, Error>) -> Value {
+    match res {
+        Ok(parsed) => serde_json::to_value(parsed).expect("Expect parsed value to serialize"),
+        Err(e) => json!({ "error": e.to_string() }),
+    }
+}
+
+fn assert_golden(name: &str, actual: Value) {
+    let path = golden_path(name);
+    let actual = format!(
+        "{}\n",
+        serde_json::to_string_pretty(&actual).expect("Expect golden value to serialize")
+    );
+
+    if env::var("UPDATE_GOLDEN").is_ok() {
+        fs::write(&path, &actual).expect("Expect golden file to be writable");
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "Missing golden file {}: {e}. Run `UPDATE_GOLDEN=1 cargo test --test parser_golden`",
+            path.display()
+        )
+    });
+    assert_eq!(
+        expected, actual,
+        "Parsed output for {name} differs from its golden file. If this change is intended, run \
+        `UPDATE_GOLDEN=1 cargo test --test parser_golden` and review the diff."
+    );
+}
+
+fn check_detail(name: &str) {
+    let html = load_fixture(&format!("detail/{name}.html"));
+    let bond = BondInformation::build(&html);
+    let total_bond = bond.as_ref().map(|b| b.get_total_bond_description()).ok();
+
+    let actual = json!({
+        "img_url": to_golden(InmateProfile::get_img_url(&html)),
+        "profile": to_golden(InmateProfile::from_html(&html, &format!("?sysid={name}"))),
+        "bond": to_golden(bond),
+        "total_bond": total_bond,
+        "charges": to_golden(ChargeInformation::build(&html)),
+    });
+    assert_golden(&format!("detail_{name}"), actual);
+}
+
+#[test]
+fn test_listing_landing_page() {
+    let html = load_fixture("listing/landing.html");
+    assert_golden(
+        "listing_landing",
+        to_golden(parse_relative_listings_urls_for_last_two_days(&html)),
+    );
+}
+
+#[test]
+fn test_listing_day_page() {
+    let html = load_fixture("listing/day.html");
+    assert_golden(
+        "listing_day",
+        to_golden(parse_inmate_sysids_old_to_new(&html)),
+    );
+}
+
+#[test]
+fn test_detail_normal() {
+    check_detail("normal");
+}
+
+#[test]
+fn test_detail_no_middle_name() {
+    check_detail("no_middle_name");
+}
+
+#[test]
+fn test_detail_no_image() {
+    check_detail("no_image");
+}
+
+#[test]
+fn test_detail_unbondable() {
+    check_detail("unbondable");
+}
+
+#[test]
+fn test_detail_multiple_bonds() {
+    check_detail("multiple_bonds");
+}
+
+#[test]
+fn test_detail_many_charges() {
+    check_detail("many_charges");
+}
+
+#[test]
+fn test_detail_unparseable_bond() {
+    check_detail("unparseable_bond");
+}
+
+#[test]
+fn test_detail_malformed_tables() {
+    check_detail("malformed_tables");
+}
+
+#[test]
+fn test_detail_no_bond_amount() {
+    check_detail("no_bond_amount");
+}